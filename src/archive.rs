@@ -0,0 +1,994 @@
+use crate::{compress::COMPRESSED_EXTENSIONS, Codec, RotationReason};
+use chrono::prelude::*;
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+pub(crate) const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+/// [`DEFAULT_TIMESTAMP_FORMAT`] with milliseconds, e.g. `2024-01-15_10-20-30-123`.
+pub(crate) const SUBSECOND_TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H-%M-%S-%3f";
+
+/// Moved a file with a plain rename, or by copying it across devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Moved {
+    Renamed,
+    Copied,
+}
+
+#[cfg(unix)]
+async fn same_device(from: &Path, to: &Path) -> Result<bool, Error> {
+    use std::os::unix::fs::MetadataExt;
+    // Canonicalizing resolves a symlinked archive directory to where the
+    // archive will really live
+    let from = tokio::fs::canonicalize(directory(from)).await?;
+    let to = tokio::fs::canonicalize(directory(to)).await?;
+    let from = tokio::fs::metadata(from).await?;
+    let to = tokio::fs::metadata(to).await?;
+    Ok(from.dev() == to.dev())
+}
+
+#[cfg(not(unix))]
+async fn same_device(_from: &Path, _to: &Path) -> Result<bool, Error> {
+    Ok(true)
+}
+
+/// Moves `from` to `to`, copying and deleting when they're on different
+/// devices and renaming otherwise.
+pub(crate) async fn move_file(from: &Path, to: &Path) -> Result<Moved, Error> {
+    if same_device(from, to).await? {
+        match tokio::fs::rename(from, to).await {
+            Ok(()) => return Ok(Moved::Renamed),
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {}
+            Err(e) => return Err(e),
+        }
+    }
+    tokio::fs::copy(from, to).await?;
+    tokio::fs::remove_file(from).await?;
+    Ok(Moved::Copied)
+}
+
+/// Moves a finished archive to `to` so it appears there complete or not at
+/// all. Across devices it's copied to a temporary name next to `to`, synced
+/// and renamed into place.
+pub(crate) async fn publish(from: &Path, to: &Path) -> Result<(), Error> {
+    if same_device(from, to).await? {
+        match tokio::fs::rename(from, to).await {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let temp = crate::compress::temp_path(to);
+    tokio::fs::copy(from, &temp).await?;
+    tokio::fs::File::open(&temp).await?.sync_all().await?;
+    tokio::fs::rename(&temp, to).await?;
+    tokio::fs::remove_file(from).await
+}
+
+/// Deletes the archive `path`, first clearing the flag of
+/// [`immutable_archives`](crate::RotatingFileBuilder::immutable_archives)
+/// if that's what refused.
+pub(crate) async fn remove(path: &Path) -> Result<(), Error> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+            let owned = path.to_path_buf();
+            let cleared = tokio::task::spawn_blocking(move || {
+                crate::filesystem::set_immutable(&owned, false)
+            })
+            .await
+            .map_err(Error::other)?;
+            match cleared {
+                Ok(true) => tokio::fs::remove_file(path).await,
+                _ => Err(e),
+            }
+        }
+        result => result,
+    }
+}
+
+/// What a rotation does when the archive name it would use is taken, e.g.
+/// by an earlier rotation in the same second or a leftover from a crash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Collision {
+    /// Adds a counter before the extension, as in `app-<timestamp>.1.log`,
+    /// which sorts after the archive it collided with.
+    #[default]
+    Disambiguate,
+    /// Fails the rotation with [`ErrorKind::AlreadyExists`].
+    Fail,
+}
+
+/// Whether `archive`, or a compressed form of it, exists in `dir`.
+async fn taken(dir: &Path, archive: &Path, naming: &Naming) -> Result<bool, Error> {
+    let name = archive.file_name().unwrap().to_owned();
+    let extensions = naming
+        .extensions()
+        .flat_map(|ext| [ext.to_string(), ext.to_uppercase()]);
+    for extension in std::iter::once(None).chain(extensions.map(Some)) {
+        let mut candidate = name.clone();
+        if let Some(extension) = extension {
+            candidate.push(".");
+            candidate.push(extension);
+        }
+        if tokio::fs::try_exists(dir.join(candidate)).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Resolves a collision of the new archive `name(0)` with one in any of
+/// `dirs`, per `collision`, trying `name(1)`, `name(2)` and so on.
+pub(crate) async fn claim<F: Fn(u32) -> PathBuf>(
+    name: F,
+    dirs: &[&Path],
+    naming: &Naming,
+    collision: Collision,
+) -> Result<PathBuf, Error> {
+    let mut counter = 0;
+    loop {
+        let candidate = name(counter);
+        let mut free = true;
+        for dir in dirs {
+            free &= !taken(dir, &candidate, naming).await?;
+        }
+        if free {
+            return Ok(candidate);
+        }
+        if collision == Collision::Fail {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("archive {} already exists", candidate.display()),
+            ));
+        }
+        counter += 1;
+    }
+}
+
+/// Splits the counter [`Collision::Disambiguate`] added off `stamp`.
+fn split_counter(stamp: &str) -> Option<(&str, u32)> {
+    let (stamp, counter) = stamp.rsplit_once('.')?;
+    Some((stamp, counter.parse().ok()?))
+}
+
+/// How archive names are derived from the active file's path.
+///
+/// An active file `dir/app.log` is archived as
+/// `dir/app[-<sequence>]-<timestamp>[-<trigger>].log[.gz|.zst]`, laid out
+/// by `template` if set, or, when content addressed, as
+/// `dir/app-<hash>.log[.gz|.zst]` once compressed.
+#[derive(Clone, Debug)]
+pub(crate) struct Naming {
+    pub(crate) timestamp_format: String,
+    pub(crate) sequence_width: Option<usize>,
+    pub(crate) content_addressed: bool,
+    pub(crate) archive_dir: Option<PathBuf>,
+    pub(crate) trigger_in_name: bool,
+    pub(crate) template: Option<Template>,
+    /// The `chrono` format of the subdirectory each archive goes into.
+    pub(crate) date_dirs: Option<String>,
+    /// Replaces [`default_sanitizer`] if set.
+    pub(crate) sanitizer: Option<Sanitizer>,
+    /// Extensions of the [`Codec::Custom`] codecs in use, recognized like
+    /// those of the built-in ones.
+    pub(crate) custom_extensions: Vec<String>,
+}
+
+/// Makes the variable parts of archive names valid in file names; see
+/// [`RotatingFileBuilder::sanitize_names`].
+///
+/// [`RotatingFileBuilder::sanitize_names`]: crate::RotatingFileBuilder::sanitize_names
+#[derive(Clone)]
+pub(crate) struct Sanitizer(pub(crate) Arc<dyn Fn(&str) -> String + Send + Sync>);
+
+impl std::fmt::Debug for Sanitizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Sanitizer")
+    }
+}
+
+/// Replaces each character that can't appear in a file name on this
+/// platform with `_`.
+pub(crate) fn default_sanitizer(name: &str) -> String {
+    let illegal = |c: char| match cfg!(windows) {
+        true => c.is_control() || r#"<>:"/\|?*"#.contains(c),
+        false => c == '/' || c == '\0',
+    };
+    name.chars()
+        .map(|c| if illegal(c) { '_' } else { c })
+        .collect()
+}
+
+/// A piece of a [`Template`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Part {
+    Literal(String),
+    Stem,
+    Ext,
+    Date,
+    Seq,
+    Trigger,
+}
+
+/// An archive name layout set by [`RotatingFileBuilder::naming_template`],
+/// such as `{stem}.{date}.{ext}.{codec}`.
+///
+/// The codec extension must come last, since it's appended once the
+/// archive is compressed, so `parts` only lay out the plain name.
+///
+/// [`RotatingFileBuilder::naming_template`]: crate::RotatingFileBuilder::naming_template
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Template {
+    parts: Vec<Part>,
+    pub(crate) uppercase_codec: bool,
+    /// What's wrong with the template, reported when the file is built.
+    pub(crate) problems: Vec<String>,
+}
+
+impl Template {
+    pub(crate) fn new(template: &str) -> Self {
+        let mut problems = Vec::new();
+        let (layout, uppercase_codec) = match template.strip_suffix(".{codec}") {
+            Some(layout) => (layout, false),
+            None => match template.strip_suffix(".{CODEC}") {
+                Some(layout) => (layout, true),
+                None => {
+                    problems.push(format!(
+                        "naming template {} must end with .{{codec}} or .{{CODEC}}",
+                        template
+                    ));
+                    (template, false)
+                }
+            },
+        };
+        let mut parts = Vec::new();
+        let mut rest = layout;
+        while !rest.is_empty() {
+            let (literal, placeholder) = match rest.find('{') {
+                Some(start) => {
+                    let end = match rest[start..].find('}') {
+                        Some(end) => start + end,
+                        None => {
+                            problems
+                                .push(format!("naming template {} has an unclosed {{", template));
+                            break;
+                        }
+                    };
+                    let placeholder = &rest[start + 1..end];
+                    let literal = &rest[..start];
+                    rest = &rest[end + 1..];
+                    (literal, Some(placeholder))
+                }
+                None => (std::mem::take(&mut rest), None),
+            };
+            if !literal.is_empty() {
+                parts.push(Part::Literal(literal.to_string()));
+            }
+            parts.extend(placeholder.and_then(|placeholder| match placeholder {
+                "stem" => Some(Part::Stem),
+                "ext" => Some(Part::Ext),
+                "date" => Some(Part::Date),
+                "seq" => Some(Part::Seq),
+                "trigger" => Some(Part::Trigger),
+                other => {
+                    problems.push(format!(
+                        "naming template {} has an unknown placeholder {{{}}}",
+                        template, other
+                    ));
+                    None
+                }
+            }));
+        }
+        if !parts.contains(&Part::Date) {
+            problems.push(format!("naming template {} has no {{date}}", template));
+        }
+        Self {
+            parts,
+            uppercase_codec,
+            problems,
+        }
+    }
+
+    /// The literal text between the placeholders.
+    pub(crate) fn literals(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Literal(literal) => Some(literal.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Whether the template includes `part`.
+    pub(crate) fn has(&self, part: &Part) -> bool {
+        self.parts.contains(part)
+    }
+
+    /// Matches `name` against `parts`, returning the `{seq}` and `{date}`
+    /// text found.
+    fn matches<'n>(
+        parts: &[Part],
+        name: &'n str,
+        fields: (&str, &str),
+        seq: Option<&'n str>,
+        date: Option<&'n str>,
+    ) -> Option<(Option<&'n str>, &'n str)> {
+        let (stem, ext) = fields;
+        let (first, parts) = match parts.split_first() {
+            Some(split) => split,
+            None if name.is_empty() => return Some((seq, date?)),
+            None => return None,
+        };
+        let next = |rest: &'n str, seq, date| Self::matches(parts, rest, fields, seq, date);
+        match first {
+            Part::Literal(literal) => next(name.strip_prefix(literal.as_str())?, seq, date),
+            Part::Stem => next(name.strip_prefix(stem)?, seq, date),
+            Part::Ext => next(name.strip_prefix(ext)?, seq, date),
+            Part::Trigger => RotationReason::ALL
+                .iter()
+                .find_map(|r| next(name.strip_prefix(r.token())?, seq, date)),
+            Part::Seq => {
+                let digits = name.bytes().take_while(u8::is_ascii_digit).count();
+                (1..=digits)
+                    .rev()
+                    .find_map(|len| next(&name[len..], Some(&name[..len]), date))
+            }
+            // Tried longest first, as the timestamp may contain the
+            // literal that follows it
+            Part::Date => (1..=name.len())
+                .rev()
+                .filter(|len| name.is_char_boundary(*len))
+                .find_map(|len| next(&name[len..], seq, Some(&name[..len]))),
+        }
+    }
+}
+
+/// Hex digits of the content hash kept in content-addressed names.
+pub(crate) const CONTENT_HASH_LEN: usize = 16;
+
+impl Default for Naming {
+    fn default() -> Self {
+        Self {
+            timestamp_format: DEFAULT_TIMESTAMP_FORMAT.to_string(),
+            sequence_width: None,
+            content_addressed: false,
+            archive_dir: None,
+            trigger_in_name: false,
+            template: None,
+            date_dirs: None,
+            sanitizer: None,
+            custom_extensions: Vec::new(),
+        }
+    }
+}
+
+/// A rotated file found next to the active file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveInfo {
+    pub path: PathBuf,
+    pub sequence: Option<u64>,
+    pub timestamp: Option<NaiveDateTime>,
+    pub compressed: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+fn split_name(base: &Path) -> (String, String) {
+    // Must be file in constructor
+    let stem = base.file_stem().unwrap().to_string_lossy().into_owned();
+    let extension = base.extension().unwrap().to_string_lossy().into_owned();
+    (stem, extension)
+}
+
+/// The empty file that [`RotatingFileBuilder::done_markers`] places next to
+/// a finished archive.
+///
+/// [`RotatingFileBuilder::done_markers`]: crate::RotatingFileBuilder::done_markers
+pub(crate) fn done_marker(archive: &Path) -> PathBuf {
+    let mut name = archive.as_os_str().to_owned();
+    name.push(".done");
+    PathBuf::from(name)
+}
+
+/// The line offset index that [`RotatingFileBuilder::line_index`] writes
+/// next to a compressed archive.
+///
+/// [`RotatingFileBuilder::line_index`]: crate::RotatingFileBuilder::line_index
+pub(crate) fn index_path(archive: &Path) -> PathBuf {
+    let mut name = archive.as_os_str().to_owned();
+    name.push(".idx");
+    PathBuf::from(name)
+}
+
+fn parse_timestamp(s: &str, format: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(s, format).ok().or_else(|| {
+        NaiveDate::parse_from_str(s, format)
+            .ok()
+            .and_then(|d| d.and_hms_opt(0, 0, 0))
+    })
+}
+
+impl Naming {
+    /// The archive of `base` rotated at `now`, with the collision counter
+    /// `counter` unless it's 0.
+    pub(crate) fn archive_path(
+        &self,
+        base: &Path,
+        now: &DateTime<Local>,
+        sequence: u64,
+        reason: RotationReason,
+        counter: u32,
+    ) -> PathBuf {
+        let (stem, extension) = split_name(base);
+        let stamp = self.sanitize(&now.format(&self.timestamp_format).to_string());
+        let sequence = self
+            .sequence_width
+            .map(|width| format!("{:0width$}", sequence, width = width));
+        if let Some(template) = &self.template {
+            let mut filename = String::new();
+            for part in &template.parts {
+                match part {
+                    Part::Literal(literal) => filename.push_str(literal),
+                    Part::Stem => filename.push_str(&stem),
+                    Part::Ext => filename.push_str(&extension),
+                    Part::Seq => filename.push_str(sequence.as_deref().unwrap_or_default()),
+                    Part::Trigger => filename.push_str(reason.token()),
+                    Part::Date => {
+                        filename.push_str(&stamp);
+                        if counter > 0 {
+                            filename.push_str(&format!(".{}", counter));
+                        }
+                    }
+                }
+            }
+            return self.directory_at(base, now).join(filename);
+        }
+        let mut filename = stem;
+        if let Some(sequence) = sequence {
+            filename.push('-');
+            filename.push_str(&sequence);
+        }
+        filename.push('-');
+        filename.push_str(&stamp);
+        if self.trigger_in_name {
+            filename.push('-');
+            filename.push_str(reason.token());
+        }
+        if counter > 0 {
+            filename.push_str(&format!(".{}", counter));
+        }
+        filename.push('.');
+        filename.push_str(&extension);
+        self.directory_at(base, now).join(filename)
+    }
+
+    /// The symlink [`RotatingFileBuilder::latest_link`] keeps to the newest
+    /// archive of `base`.
+    ///
+    /// [`RotatingFileBuilder::latest_link`]: crate::RotatingFileBuilder::latest_link
+    pub(crate) fn latest_link(&self, base: &Path, codec: &Codec) -> PathBuf {
+        let (stem, extension) = split_name(base);
+        let link = self
+            .directory(base)
+            .join(format!("{}.latest.{}", stem, extension));
+        self.output_path(codec, &link)
+    }
+
+    /// Where the archive of `base` rotated at `now` goes.
+    pub(crate) fn directory_at(&self, base: &Path, now: &DateTime<Local>) -> PathBuf {
+        let directory = self.directory(base);
+        match &self.date_dirs {
+            Some(format) => {
+                let dated = now.format(format).to_string();
+                let mut directory = directory.to_path_buf();
+                directory.extend(dated.split('/').map(|part| self.sanitize(part)));
+                directory
+            }
+            None => directory.to_path_buf(),
+        }
+    }
+
+    /// Applies the sanitizer to part of a name.
+    pub(crate) fn sanitize(&self, part: &str) -> String {
+        match &self.sanitizer {
+            Some(sanitizer) => (sanitizer.0)(part),
+            None => default_sanitizer(part),
+        }
+    }
+
+    /// The timestamp format as it appears in names once sanitized.
+    fn stamp_format(&self) -> String {
+        self.sanitize(&self.timestamp_format)
+    }
+
+    /// The directories that may hold archives of `base`: its archive
+    /// directory and, with dated subdirectories, every one below it.
+    async fn directories(&self, base: &Path) -> Result<Vec<PathBuf>, Error> {
+        let depth = self
+            .date_dirs
+            .as_ref()
+            .map_or(0, |format| Path::new(format).components().count());
+        let mut directories = vec![self.directory(base).to_path_buf()];
+        let mut level = directories.clone();
+        for _ in 0..depth {
+            let mut below = Vec::new();
+            for dir in &level {
+                let mut entries = match tokio::fs::read_dir(dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    if entry.file_type().await?.is_dir() {
+                        below.push(entry.path());
+                    }
+                }
+            }
+            directories.extend(below.iter().cloned());
+            level = below;
+        }
+        Ok(directories)
+    }
+
+    /// The extension `codec` appends to archives, cased per the template.
+    pub(crate) fn codec_extension(&self, codec: &Codec) -> Option<String> {
+        let extension = codec.extension()?;
+        Some(match &self.template {
+            Some(template) if template.uppercase_codec => extension.to_uppercase(),
+            _ => extension.to_string(),
+        })
+    }
+
+    /// The extensions of every codec, built in or custom.
+    fn extensions(&self) -> impl Iterator<Item = &str> {
+        COMPRESSED_EXTENSIONS
+            .iter()
+            .copied()
+            .chain(self.custom_extensions.iter().map(String::as_str))
+    }
+
+    /// Strips the extension of any codec, built in or custom, off `name`.
+    fn strip_codec<'a>(&self, name: &'a str) -> Option<&'a str> {
+        strip_codec(name).or_else(|| {
+            let (plain, extension) = name.rsplit_once('.')?;
+            self.custom_extensions
+                .iter()
+                .any(|custom| extension.eq_ignore_ascii_case(custom))
+                .then_some(plain)
+        })
+    }
+
+    /// Where `archive` ends up once compressed with `codec`.
+    pub(crate) fn output_path(&self, codec: &Codec, archive: &Path) -> PathBuf {
+        match self.codec_extension(codec) {
+            Some(extension) => {
+                let mut name = archive.file_name().unwrap().to_owned();
+                name.push(".");
+                name.push(extension);
+                archive.with_file_name(name)
+            }
+            None => archive.to_path_buf(),
+        }
+    }
+
+    /// Where archives of `base` are kept.
+    pub(crate) fn directory<'a>(&'a self, base: &'a Path) -> &'a Path {
+        match &self.archive_dir {
+            Some(dir) => dir,
+            None => directory(base),
+        }
+    }
+
+    /// Names the archive of `base` by a hash of its content.
+    #[cfg(feature = "content-hash")]
+    pub(crate) fn content_path(&self, base: &Path, hash: &str, codec_extension: &str) -> PathBuf {
+        let (stem, extension) = split_name(base);
+        self.directory(base).join(format!(
+            "{}-{}.{}.{}",
+            stem,
+            &hash[..CONTENT_HASH_LEN],
+            extension,
+            codec_extension
+        ))
+    }
+
+    /// Checks whether `path` is an archive of `base`. The returned size and
+    /// mtime are left empty; [`list`] fills them in from the file's metadata.
+    pub(crate) fn parse(&self, base: &Path, path: &Path) -> Option<ArchiveInfo> {
+        let (stem, extension) = split_name(base);
+        let name = path.file_name()?.to_str()?;
+        if let Some(template) = &self.template {
+            let (plain, compressed) = match self.strip_codec(name) {
+                Some(plain) => (plain, true),
+                None => (name, false),
+            };
+            let fields = (stem.as_str(), extension.as_str());
+            let (digits, stamp) = Template::matches(&template.parts, plain, fields, None, None)?;
+            let format = self.stamp_format();
+            let timestamp = parse_timestamp(stamp, &format)
+                .or_else(|| parse_timestamp(split_counter(stamp)?.0, &format));
+            return Some(ArchiveInfo {
+                path: path.to_path_buf(),
+                sequence: match digits {
+                    Some(digits) => Some(digits.parse().ok()?),
+                    None => None,
+                },
+                timestamp: Some(timestamp?),
+                compressed,
+                size: 0,
+                modified: None,
+            });
+        }
+        let rest = name.strip_prefix(&stem)?.strip_prefix('-')?;
+        let plain_suffix = format!(".{}", extension);
+        let (middle, compressed) = match self.strip_codec(rest) {
+            Some(middle) => (middle.strip_suffix(&plain_suffix)?, true),
+            None => (rest.strip_suffix(&plain_suffix)?, false),
+        };
+        if self.content_addressed
+            && middle.len() == CONTENT_HASH_LEN
+            && middle.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Some(ArchiveInfo {
+                path: path.to_path_buf(),
+                sequence: None,
+                timestamp: None,
+                compressed,
+                size: 0,
+                modified: None,
+            });
+        }
+        let (sequence, stamp) = match self.sequence_width {
+            Some(_) => {
+                let (digits, stamp) = middle.split_once('-')?;
+                if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                (Some(digits.parse().ok()?), stamp)
+            }
+            None => (None, middle),
+        };
+        let format = self.stamp_format();
+        let parse_stamp = |stamp: &str| {
+            let stamp = match self.trigger_in_name {
+                true => RotationReason::ALL
+                    .iter()
+                    .find_map(|r| stamp.strip_suffix(r.token())?.strip_suffix('-'))?,
+                false => stamp,
+            };
+            parse_timestamp(stamp, &format)
+        };
+        let timestamp = parse_stamp(stamp).or_else(|| parse_stamp(split_counter(stamp)?.0));
+        let timestamp = Some(timestamp?);
+        Some(ArchiveInfo {
+            path: path.to_path_buf(),
+            sequence,
+            timestamp,
+            compressed,
+            size: 0,
+            modified: None,
+        })
+    }
+}
+
+/// Strips a codec extension, in either case, off `name`.
+pub(crate) fn strip_codec(name: &str) -> Option<&str> {
+    COMPRESSED_EXTENSIONS.iter().find_map(|codec| {
+        let (plain, extension) = name.rsplit_once('.')?;
+        extension.eq_ignore_ascii_case(codec).then_some(plain)
+    })
+}
+
+/// The directory holding `base`, which is `.` for a bare file name.
+pub(crate) fn directory(base: &Path) -> &Path {
+    match base.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+/// Lists the archives belonging to `base`, oldest first.
+pub(crate) async fn list(base: &Path, naming: &Naming) -> Result<Vec<ArchiveInfo>, Error> {
+    let mut archives = Vec::new();
+    for dir in naming.directories(base).await? {
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(mut info) = naming.parse(base, &entry.path()) {
+                let metadata = match entry.metadata().await {
+                    Ok(metadata) => metadata,
+                    // Removed since the directory was read
+                    Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+                if !metadata.is_file() {
+                    continue;
+                }
+                info.size = metadata.len();
+                info.modified = metadata.modified().ok();
+                archives.push(info);
+            }
+        }
+    }
+    archives.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+    Ok(archives)
+}
+
+/// Lists the archives of `file_name` (e.g. `app.log`) found in `dir`,
+/// oldest first, without an open [`RotatingFile`]. Only archives written
+/// with one of `codecs` are listed, [`Codec::None`] standing for plain ones.
+///
+/// Meant for maintenance jobs. Like [`compress_existing`], it recognizes the
+/// default naming, with and without a sequence number.
+///
+/// [`RotatingFile`]: crate::RotatingFile
+/// [`compress_existing`]: crate::compress_existing
+pub async fn list_archives_in<P: AsRef<Path>>(
+    dir: P,
+    file_name: &str,
+    codecs: &[Codec],
+) -> Result<Vec<ArchiveInfo>, Error> {
+    let mut archives = list_standalone(&standalone_base(dir.as_ref(), file_name)?, codecs).await?;
+    archives.retain(|archive| {
+        let extension = archive.path.extension().and_then(|ext| ext.to_str());
+        codecs.iter().any(|codec| match codec.extension() {
+            None => !archive.compressed,
+            Some(codec) => {
+                archive.compressed && extension.is_some_and(|ext| ext.eq_ignore_ascii_case(codec))
+            }
+        })
+    });
+    Ok(archives)
+}
+
+/// The active file `file_name` in `dir` would be, for the functions that
+/// work without one.
+pub(crate) fn standalone_base(dir: &Path, file_name: &str) -> Result<PathBuf, Error> {
+    let base = dir.join(file_name);
+    if base.extension().is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} has no extension", file_name),
+        ));
+    }
+    Ok(base)
+}
+
+/// Lists the archives of `base` under the default naming, with and without
+/// a sequence number, oldest first. Archives of the custom ones among
+/// `codecs` are recognized too.
+pub(crate) async fn list_standalone(
+    base: &Path,
+    codecs: &[Codec],
+) -> Result<Vec<ArchiveInfo>, Error> {
+    let custom_extensions: Vec<_> = codecs
+        .iter()
+        .filter_map(|codec| Some(codec.custom_extension()?.to_string()))
+        .collect();
+    let mut archives: Vec<ArchiveInfo> = Vec::new();
+    for sequence_width in [None, Some(1)] {
+        let naming = Naming {
+            sequence_width,
+            custom_extensions: custom_extensions.clone(),
+            ..Naming::default()
+        };
+        for found in list(base, &naming).await? {
+            if !archives.iter().any(|a| a.path == found.path) {
+                archives.push(found);
+            }
+        }
+    }
+    archives.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+    Ok(archives)
+}
+
+/// Points the latest link of `base` at `archive`, unless it already points
+/// at a newer archive or `archive` is gone.
+#[cfg(unix)]
+pub(crate) async fn point_latest(
+    base: &Path,
+    naming: &Naming,
+    codec: &Codec,
+    archive: &Path,
+) -> Result<(), Error> {
+    let directory = naming.directory(base);
+    let link = naming.latest_link(base, codec);
+    let archives = list(base, naming).await?;
+    let position = |path: &Path| archives.iter().position(|a| a.path == path);
+    let newest = match position(archive) {
+        Some(newest) => newest,
+        None => return Ok(()),
+    };
+    if let Ok(current) = tokio::fs::read_link(&link).await {
+        if position(&directory.join(current)).is_some_and(|current| current > newest) {
+            return Ok(());
+        }
+    }
+    let target = archive.strip_prefix(directory).unwrap_or(archive);
+    let temp = crate::compress::temp_path(&link);
+    match tokio::fs::remove_file(&temp).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    tokio::fs::symlink(target, &temp).await?;
+    tokio::fs::rename(&temp, &link).await
+}
+
+/// Finds leftover `.tmp` files from interrupted compressions of `base`'s
+/// archives.
+pub(crate) async fn stale_temps(base: &Path, naming: &Naming) -> Result<Vec<PathBuf>, Error> {
+    let mut temps = Vec::new();
+    for dir in naming.directories(base).await? {
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "tmp")
+                && naming.parse(base, &path.with_extension("")).is_some()
+            {
+                temps.push(path);
+            }
+        }
+    }
+    temps.sort();
+    Ok(temps)
+}
+
+impl ArchiveInfo {
+    /// Orders by sequence, then by the name's timestamp, falling back to the
+    /// mtime for names without one, then by collision counter.
+    fn sort_key(&self) -> (Option<u64>, Option<NaiveDateTime>, u32, &Path) {
+        let time = self.timestamp.or_else(|| {
+            self.modified
+                .map(|m| DateTime::<Local>::from(m).naive_local())
+        });
+        let counter = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| {
+                let name = strip_codec(name).unwrap_or(name);
+                split_counter(name.rsplit_once('.')?.0)
+            })
+            .map_or(0, |(_, counter)| counter);
+        (self.sequence, time, counter, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_roundtrip() {
+        let naming = Naming {
+            sequence_width: Some(6),
+            ..Naming::default()
+        };
+        let base = Path::new("dir/my.app.log");
+        let now = Local.with_ymd_and_hms(2024, 1, 15, 10, 20, 30).unwrap();
+        let archive = naming.archive_path(base, &now, 123, RotationReason::Bytes, 0);
+        assert_eq!(
+            archive,
+            Path::new("dir/my.app-000123-2024-01-15_10-20-30.log")
+        );
+
+        let info = naming
+            .parse(
+                base,
+                &Naming::default().output_path(&crate::Codec::default(), &archive),
+            )
+            .unwrap();
+        assert_eq!(info.sequence, Some(123));
+        assert_eq!(info.timestamp, Some(now.naive_local()));
+        assert!(info.compressed);
+        assert!(naming.parse(base, Path::new("dir/my.app.log")).is_none());
+        assert!(naming
+            .parse(base, Path::new("dir/my.app-x-y.log"))
+            .is_none());
+    }
+
+    #[test]
+    fn sanitized_roundtrip() {
+        let base = Path::new("dir/app.log");
+        let now = Local.with_ymd_and_hms(2024, 1, 15, 10, 20, 30).unwrap();
+        let check = |naming: Naming, expected: &str| {
+            let archive = naming.archive_path(base, &now, 0, RotationReason::Bytes, 0);
+            assert_eq!(archive, Path::new(expected));
+            let info = naming.parse(base, &archive).unwrap();
+            assert_eq!(info.timestamp, Some(now.naive_local()));
+        };
+        let custom = Naming {
+            timestamp_format: "%Y-%m-%d %H:%M:%S".to_string(),
+            sanitizer: Some(Sanitizer(Arc::new(|part: &str| part.replace(' ', "T")))),
+            ..Naming::default()
+        };
+        check(custom, "dir/app-2024-01-15T10:20:30.log");
+
+        #[cfg(windows)]
+        check(
+            Naming {
+                timestamp_format: "%Y-%m-%d_%H:%M:%S".to_string(),
+                ..Naming::default()
+            },
+            "dir/app-2024-01-15_10_20_30.log",
+        );
+    }
+
+    #[test]
+    fn template_roundtrip() {
+        let naming = Naming {
+            template: Some(Template::new("{stem}.{ext}.{seq}.{date}.{codec}")),
+            sequence_width: Some(2),
+            ..Naming::default()
+        };
+        let base = Path::new("dir/app.log");
+        let now = Local.with_ymd_and_hms(2024, 1, 15, 10, 20, 30).unwrap();
+        let archive = naming.archive_path(base, &now, 7, RotationReason::Bytes, 0);
+        assert_eq!(archive, Path::new("dir/app.log.07.2024-01-15_10-20-30"));
+
+        let info = naming
+            .parse(base, &naming.output_path(&Codec::default(), &archive))
+            .unwrap();
+        assert_eq!(info.sequence, Some(7));
+        assert_eq!(info.timestamp, Some(now.naive_local()));
+        assert!(info.compressed);
+        assert!(naming.parse(base, Path::new("dir/app.log.gz")).is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinked_archive_dir_renames() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = crate::tests::fresh_dir("symlinked_archive_dir_renames").await;
+        let real = dir.join("real");
+        std::fs::create_dir(&real).unwrap();
+        std::os::unix::fs::symlink("real", dir.join("link")).unwrap();
+
+        let from = dir.join("app.log");
+        std::fs::write(&from, b"data").unwrap();
+        let inode = std::fs::metadata(&from).unwrap().ino();
+        let to = dir.join("link").join("app-1.log");
+        assert_eq!(move_file(&from, &to).await.unwrap(), Moved::Renamed);
+        assert_eq!(
+            std::fs::metadata(real.join("app-1.log")).unwrap().ino(),
+            inode
+        );
+    }
+
+    #[tokio::test]
+    async fn subsecond_order_beats_mtime() {
+        let dir = crate::tests::fresh_dir("subsecond_order_beats_mtime").await;
+        let naming = Naming {
+            timestamp_format: SUBSECOND_TIMESTAMP_FORMAT.to_string(),
+            ..Naming::default()
+        };
+        let base = dir.join("app.log");
+        let tick = SystemTime::now();
+        // Written newest first, all within one mtime tick
+        let names = [
+            "app-2024-01-15_10-20-30-900.log.gz",
+            "app-2024-01-15_10-20-30-050.log.gz",
+            "app-2024-01-15_10-20-30-400.log.gz",
+        ];
+        for name in names {
+            let file = std::fs::File::create(dir.join(name)).unwrap();
+            file.set_modified(tick).unwrap();
+        }
+        let listed: Vec<_> = list(&base, &naming)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|a| a.path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(listed, [names[1], names[2], names[0]]);
+    }
+}
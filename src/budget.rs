@@ -0,0 +1,142 @@
+use crate::{
+    archive::{self, ArchiveInfo, Naming},
+    retention::{self, Pins},
+};
+use std::{
+    io::{Error, ErrorKind},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// A disk budget shared by several [`RotatingFile`]s, evicting the oldest
+/// archives among all of them once their combined size exceeds it.
+///
+/// Clone the handle into each file's
+/// [`RotatingFileBuilder::disk_budget`]. The active files count towards the
+/// budget but are never removed, so it can still be exceeded while archives
+/// are waiting to be compressed or by the active files alone. Files stay
+/// registered for as long as the budget lives.
+///
+/// [`RotatingFile`]: crate::RotatingFile
+/// [`RotatingFileBuilder::disk_budget`]: crate::RotatingFileBuilder::disk_budget
+#[derive(Clone)]
+pub struct DiskBudget {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    bytes: u64,
+    files: Mutex<Vec<(PathBuf, Naming, Pins)>>,
+    /// Serializes enforcement, so two files don't evict for the same excess.
+    enforcing: tokio::sync::Mutex<()>,
+}
+
+impl DiskBudget {
+    pub fn new(bytes: u64) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                bytes,
+                files: Mutex::new(Vec::new()),
+                enforcing: Default::default(),
+            }),
+        }
+    }
+
+    /// The budget in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.inner.bytes
+    }
+
+    pub(crate) fn register(&self, path: PathBuf, naming: Naming, pins: Pins) {
+        self.inner.files.lock().unwrap().push((path, naming, pins));
+    }
+
+    /// Deletes the oldest archives of the registered files, by mtime, until
+    /// their total size and that of the active files fits the budget.
+    /// Archives pinned by [`RotatingFile::snapshot_rotate`] count towards
+    /// the budget but are skipped. Returns what was deleted.
+    ///
+    /// Runs after each rotation of a registered file.
+    pub async fn enforce(&self) -> Result<Vec<PathBuf>, Error> {
+        let _enforcing = self.inner.enforcing.lock().await;
+        let files = self.inner.files.lock().unwrap().clone();
+        let mut total = 0u64;
+        let mut archives: Vec<(ArchiveInfo, &Pins)> = Vec::new();
+        for (path, naming, pins) in &files {
+            match tokio::fs::metadata(path).await {
+                Ok(metadata) => total += metadata.len(),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            let listed = archive::list(path, naming).await?;
+            archives.extend(listed.into_iter().map(|a| (a, pins)));
+        }
+        total += archives.iter().map(|(a, _)| a.size).sum::<u64>();
+        archives.sort_by_key(|(a, _)| a.modified.unwrap_or(SystemTime::UNIX_EPOCH));
+
+        let mut removed = Vec::new();
+        for (archive, pins) in archives {
+            if total <= self.inner.bytes {
+                break;
+            }
+            if pins.contains(&archive.path) {
+                continue;
+            }
+            match archive::remove(&archive.path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+            retention::remove_marker(&archive.path).await?;
+            total = total.saturating_sub(archive.size);
+            removed.push(archive.path);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::fresh_dir, Codec, RotatingFile, RotationMode};
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn shared_between_files() {
+        let dir = fresh_dir("disk_budget_shared_between_files").await;
+        let budget = DiskBudget::new(40);
+        let open = |name: &str| {
+            RotatingFile::builder(dir.join(name), RotationMode::Bytes(10))
+                .sequence(1)
+                .codec(Codec::None)
+                .disk_budget(budget.clone())
+                .build()
+        };
+        let mut a = open("a.log").await.unwrap();
+        let mut b = open("b.log").await.unwrap();
+        for _ in 0..4 {
+            a.write_all(&[b'a'; 10]).await.unwrap();
+        }
+        for _ in 0..4 {
+            b.write_all(&[b'b'; 10]).await.unwrap();
+        }
+        // b's archives pushed out all of a's older ones
+        assert!(a.archives().await.unwrap().is_empty());
+        assert_eq!(b.archives().await.unwrap().len(), 3);
+
+        // a's next archive holds bytes written before b's, so it goes first
+        a.write_all(&[b'a'; 10]).await.unwrap();
+        assert!(a.archives().await.unwrap().is_empty());
+        a.write_all(&[b'a'; 10]).await.unwrap();
+        let kept: Vec<_> = b
+            .archives()
+            .await
+            .unwrap()
+            .iter()
+            .map(|a| a.sequence)
+            .collect();
+        assert_eq!(kept, [Some(2), Some(3)]);
+        assert_eq!(a.archives().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,1511 @@
+use crate::{
+    archive::{self, Collision, Naming, Part, Sanitizer, Template},
+    compress::{
+        ArchiveMtime, ArchivePermissions, Codec, CompressionExecutor, MemoryGauge, TokioBlocking,
+        MIN_MEMORY_BUDGET,
+    },
+    filesystem::writable,
+    lines::{LineCounting, LineEnding},
+    non_blocking::{NonBlocking, Overflow},
+    retention::{Pins, RetentionPolicy, RetentionRule},
+    DiskBudget, FlushStrategy, NotifyEndpoint, RotatingFile, RotationMode, RotationReason,
+    RotationStats, RotationStrategy,
+};
+use chrono::{DateTime, Local};
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+/// Decides whether a due rotation may go ahead; see
+/// [`RotatingFileBuilder::rotation_veto`].
+pub type RotationVeto = Arc<dyn Fn(&RotationStats) -> bool + Send + Sync>;
+
+/// Asks for a rotation the thresholds don't call for; see
+/// [`RotatingFileBuilder::rotate_when`].
+pub type RotationTrigger = Arc<dyn Fn(&RotationStats) -> bool + Send + Sync>;
+
+/// Decides whether a rotated file is compressed; see
+/// [`RotatingFileBuilder::compress_if`].
+pub type CompressFilter = Arc<dyn Fn(&Path) -> bool + Send + Sync>;
+
+/// Picks the time an archive is named after; see
+/// [`RotatingFileBuilder::archive_timestamp`].
+pub type TimestampSource = Arc<dyn Fn(&Path) -> Option<DateTime<Local>> + Send + Sync>;
+
+/// Hears when the hot path was swapped for a new file; see
+/// [`RotatingFileBuilder::on_swap`].
+pub type SwapHook = Arc<dyn Fn(&Path, &Path) + Send + Sync>;
+
+/// Hears how far a compression got; see
+/// [`RotatingFileBuilder::compression_progress`].
+pub type CompressionProgress = Arc<dyn Fn(u64, Option<u64>) + Send + Sync>;
+
+/// Everything a [`RotatingFileBuilder`] configures besides the path and
+/// rotation mode.
+///
+/// [`RotationConfig::default()`] is the baseline every builder starts from:
+/// fast gzip, archives named by local time in the `%Y-%m-%d_%H-%M-%S` format,
+/// and no retention. Override any of those with the `with_*` methods and
+/// hand the result to [`RotatingFileBuilder::config`] to layer further
+/// settings on top of it.
+pub struct RotationConfig {
+    pub(crate) executor: Arc<dyn CompressionExecutor>,
+    pub(crate) codec: Codec,
+    pub(crate) extra_codecs: Vec<Codec>,
+    pub(crate) naming: Naming,
+    pub(crate) collision: Collision,
+    pub(crate) rotate_on_write: bool,
+    pub(crate) rotation_barrier: bool,
+    pub(crate) write_rate_limit: Option<u64>,
+    pub(crate) rotate_on_flush: bool,
+    pub(crate) rotate_on_shutdown: bool,
+    pub(crate) retention: Option<RetentionPolicy>,
+    pub(crate) disk_budget: Option<DiskBudget>,
+    pub(crate) notify: Option<NotifyEndpoint>,
+    pub(crate) max_file_age: Option<Duration>,
+    pub(crate) daily_boundary: bool,
+    pub(crate) strategy: RotationStrategy,
+    pub(crate) buffer_limit: usize,
+    pub(crate) overflow: Overflow,
+    pub(crate) verify_size: bool,
+    pub(crate) checksum_footer: bool,
+    pub(crate) resumable_compression: bool,
+    pub(crate) background_compression: bool,
+    pub(crate) veto: Option<RotationVeto>,
+    pub(crate) trigger: Option<(RotationTrigger, Duration)>,
+    pub(crate) compress_filter: Option<CompressFilter>,
+    pub(crate) timestamp_source: Option<TimestampSource>,
+    pub(crate) on_swap: Option<SwapHook>,
+    pub(crate) compression_progress: Option<CompressionProgress>,
+    pub(crate) compression_threads: usize,
+    pub(crate) compression_memory_budget: Option<usize>,
+    pub(crate) compression_memory: MemoryGauge,
+    pub(crate) max_rotations: Option<(usize, Duration)>,
+    pub(crate) open_retry: Option<(usize, Duration)>,
+    pub(crate) rotation_cooldown: Option<Duration>,
+    pub(crate) flush: FlushStrategy,
+    pub(crate) staging_dir: Option<PathBuf>,
+    pub(crate) recreate_dir: bool,
+    pub(crate) block_alignment: Option<(usize, bool)>,
+    pub(crate) sync_archives: bool,
+    pub(crate) immutable_archives: bool,
+    pub(crate) fadvise: bool,
+    pub(crate) defer_compression: bool,
+    pub(crate) direct_compression: bool,
+    pub(crate) compress_active: bool,
+    pub(crate) verify_rotation: bool,
+    pub(crate) archive_mtime: ArchiveMtime,
+    pub(crate) archive_permissions: ArchivePermissions,
+    pub(crate) checkpoint: bool,
+    pub(crate) line_ending: Option<LineEnding>,
+    pub(crate) line_counting: LineCounting,
+    pub(crate) line_timestamps: Option<String>,
+    pub(crate) max_line_bytes: Option<(usize, bool)>,
+    pub(crate) initial_counts: Option<(usize, usize)>,
+    pub(crate) truncate_on_open: bool,
+    pub(crate) done_markers: bool,
+    pub(crate) latest_link: bool,
+    pub(crate) line_index: Option<usize>,
+    #[cfg(unix)]
+    pub(crate) dir_mode: Option<u32>,
+    #[cfg(feature = "json")]
+    pub(crate) json_key: Option<String>,
+    #[cfg(feature = "regex")]
+    pub(crate) rotate_after_match: Option<regex::Regex>,
+    /// Held while archives appear or disappear, so retention always sees
+    /// each archive exactly once.
+    pub(crate) archive_lock: tokio::sync::Mutex<()>,
+    pub(crate) pins: Pins,
+}
+
+pub(crate) const DEFAULT_BUFFER_LIMIT: usize = 1024 * 1024;
+
+impl RotationConfig {
+    /// The time to name the archive of the active file at `path` after.
+    pub(crate) fn archive_time(&self, path: &Path) -> DateTime<Local> {
+        self.timestamp_source
+            .as_ref()
+            .and_then(|source| source(path))
+            .unwrap_or_else(Local::now)
+    }
+
+    /// Whether [`RotatingFileBuilder::compress_if`] allows compressing the
+    /// file at `path`.
+    pub(crate) fn compress_filter(&self, path: &Path) -> bool {
+        self.compress_filter
+            .as_ref()
+            .is_none_or(|compress| compress(path))
+    }
+
+    /// The codec rotated files are compressed with.
+    pub fn codec(&self) -> Codec {
+        self.codec.clone()
+    }
+
+    /// The `chrono` format of the local-time timestamp in archive names.
+    pub fn timestamp_format(&self) -> &str {
+        &self.naming.timestamp_format
+    }
+
+    /// Which archives are kept, if any are ever deleted.
+    pub fn retention(&self) -> Option<&RetentionPolicy> {
+        self.retention.as_ref()
+    }
+
+    /// Compresses rotated files with `codec`, like
+    /// [`RotatingFileBuilder::codec`].
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Names archives with the `chrono` `format`, like
+    /// [`RotatingFileBuilder::timestamp_format`].
+    pub fn with_timestamp_format(mut self, format: impl Into<String>) -> Self {
+        self.naming.timestamp_format = format.into();
+        self
+    }
+
+    /// Deletes archives that `policy` no longer keeps, like
+    /// [`RotatingFileBuilder::retention`].
+    pub fn with_retention(mut self, policy: RetentionPolicy) -> Self {
+        self.retention = Some(policy);
+        self
+    }
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        Self {
+            executor: Arc::new(TokioBlocking),
+            codec: Codec::default(),
+            extra_codecs: Vec::new(),
+            naming: Naming::default(),
+            collision: Collision::default(),
+            rotate_on_write: true,
+            rotation_barrier: false,
+            write_rate_limit: None,
+            rotate_on_flush: false,
+            rotate_on_shutdown: false,
+            retention: None,
+            disk_budget: None,
+            notify: None,
+            max_file_age: None,
+            daily_boundary: false,
+            strategy: RotationStrategy::default(),
+            buffer_limit: DEFAULT_BUFFER_LIMIT,
+            overflow: Overflow::default(),
+            verify_size: false,
+            checksum_footer: false,
+            resumable_compression: false,
+            background_compression: false,
+            veto: None,
+            trigger: None,
+            compress_filter: None,
+            timestamp_source: None,
+            on_swap: None,
+            compression_progress: None,
+            compression_threads: 1,
+            compression_memory_budget: None,
+            compression_memory: MemoryGauge::default(),
+            max_rotations: None,
+            open_retry: None,
+            rotation_cooldown: None,
+            flush: FlushStrategy::default(),
+            staging_dir: None,
+            recreate_dir: false,
+            block_alignment: None,
+            sync_archives: false,
+            immutable_archives: false,
+            fadvise: false,
+            defer_compression: false,
+            direct_compression: false,
+            compress_active: false,
+            verify_rotation: false,
+            archive_mtime: ArchiveMtime::default(),
+            archive_permissions: ArchivePermissions::default(),
+            checkpoint: false,
+            line_ending: None,
+            line_counting: LineCounting::default(),
+            line_timestamps: None,
+            max_line_bytes: None,
+            initial_counts: None,
+            truncate_on_open: false,
+            done_markers: false,
+            latest_link: false,
+            line_index: None,
+            #[cfg(unix)]
+            dir_mode: None,
+            #[cfg(feature = "json")]
+            json_key: None,
+            #[cfg(feature = "regex")]
+            rotate_after_match: None,
+            archive_lock: Default::default(),
+            pins: Pins::default(),
+        }
+    }
+}
+
+/// Every problem [`RotatingFileBuilder::build`] found with a configuration.
+///
+/// Returned as the inner error of an [`ErrorKind::InvalidInput`] error.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid rotating file configuration: ")?;
+        write!(f, "{}", self.problems.join("; "))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Configures and opens a [`RotatingFile`].
+pub struct RotatingFileBuilder {
+    path: PathBuf,
+    mode: RotationMode,
+    config: RotationConfig,
+}
+
+impl RotatingFileBuilder {
+    pub(crate) fn new(path: PathBuf, mode: RotationMode) -> Self {
+        Self {
+            path,
+            mode,
+            config: RotationConfig::default(),
+        }
+    }
+
+    /// Replaces every setting made so far with `config`.
+    pub fn config(mut self, config: RotationConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Runs compression on `executor` instead of Tokio's blocking pool.
+    pub fn compression_executor(mut self, executor: Arc<dyn CompressionExecutor>) -> Self {
+        self.config.executor = executor;
+        self
+    }
+
+    /// Compresses rotated files with `codec`. Defaults to fast gzip.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.config.codec = codec;
+        self
+    }
+
+    /// Also compresses every rotated file with each of `codecs`, e.g. to
+    /// have both `.gz` and `.zst` archives while consumers migrate from one
+    /// to the other. The rotated file is only removed once every archive is
+    /// written.
+    ///
+    /// Each codec costs a full extra compression pass and an extra copy on
+    /// disk. Every copy is an archive of its own to
+    /// [`RotatingFile::archives`] and retention, which
+    /// [`codec`](Self::codec) alone decides the name of. Content-addressed
+    /// archives are only written with the main codec.
+    pub fn extra_codecs<I: IntoIterator<Item = Codec>>(mut self, codecs: I) -> Self {
+        self.config.extra_codecs = codecs.into_iter().collect();
+        self
+    }
+
+    /// Prefixes archive timestamps with an incrementing sequence number,
+    /// zero-padded to `width` digits, e.g. `app-000123-2024-01-15_10-20-30.log.gz`.
+    ///
+    /// Numbering resumes after the highest sequence found among existing
+    /// archives.
+    pub fn sequence(mut self, width: usize) -> Self {
+        self.config.naming.sequence_width = Some(width);
+        self
+    }
+
+    /// Formats archive timestamps with the `chrono` `format` instead of
+    /// `%Y-%m-%d_%H-%M-%S`, e.g. `%Y-%m-%d` for one archive per day.
+    pub fn timestamp_format(mut self, format: impl Into<String>) -> Self {
+        self.config.naming.timestamp_format = format.into();
+        self
+    }
+
+    /// Adds milliseconds to archive timestamps, e.g.
+    /// `app-2024-01-15_10-20-30-123.log.gz`.
+    ///
+    /// Without a sequence number, archives rotated within the same second
+    /// would otherwise get the same name. Archives are always ordered by the
+    /// timestamp in their name, so this also keeps them in order on
+    /// filesystems with coarse mtimes.
+    pub fn subsecond_timestamps(mut self, enabled: bool) -> Self {
+        self.config.naming.timestamp_format = match enabled {
+            true => archive::SUBSECOND_TIMESTAMP_FORMAT,
+            false => archive::DEFAULT_TIMESTAMP_FORMAT,
+        }
+        .to_string();
+        self
+    }
+
+    /// Whether a write checks the rotation threshold before it lands in the
+    /// file. Enabled by default.
+    pub fn rotate_on_write(mut self, enabled: bool) -> Self {
+        self.config.rotate_on_write = enabled;
+        self
+    }
+
+    /// Keeps each write through [`SharedRotatingFile`] or
+    /// [`MultiRotatingFile`] in one file. A rotation that comes due partway
+    /// through a write, as when a large buffer reaches the file in pieces,
+    /// waits until the write is done and happens before the next one.
+    ///
+    /// Files can then exceed their limit by up to one write. Writes from
+    /// other tasks already queue while a rotation runs, including its
+    /// compression unless that happens in the background.
+    ///
+    /// [`SharedRotatingFile`]: crate::SharedRotatingFile
+    /// [`MultiRotatingFile`]: crate::MultiRotatingFile
+    pub fn rotation_barrier(mut self, enabled: bool) -> Self {
+        self.config.rotation_barrier = enabled;
+        self
+    }
+
+    /// Whether a flush checks the rotation threshold and rotates if it has
+    /// been reached. Disabled by default.
+    ///
+    /// Combined with `rotate_on_write(false)`, rotation only ever happens at
+    /// flush, so everything written between two flushes ends up in the same
+    /// file. With both enabled, a flush merely catches a threshold that was
+    /// crossed by the last write instead of waiting for the next one.
+    pub fn rotate_on_flush(mut self, enabled: bool) -> Self {
+        self.config.rotate_on_flush = enabled;
+        self
+    }
+
+    /// Whether shutting down rotates the file if anything was written to
+    /// it, so the last of it is archived and compressed along with the rest
+    /// rather than waiting for the next run. Disabled by default. Shutdown
+    /// leaves an empty active file behind.
+    ///
+    /// Either way, shutdown first writes out everything still buffered,
+    /// including an unterminated last line.
+    pub fn rotate_on_shutdown(mut self, enabled: bool) -> Self {
+        self.config.rotate_on_shutdown = enabled;
+        self
+    }
+
+    /// Announces each finished archive to a listener on `endpoint`, e.g. a
+    /// log shipper, by connecting and sending its path, a tab, its size in
+    /// bytes and a newline. An unreachable endpoint doesn't fail the
+    /// rotation; with the `tracing` feature, it's logged.
+    pub fn notify(mut self, endpoint: NotifyEndpoint) -> Self {
+        self.config.notify = Some(endpoint);
+        self
+    }
+
+    /// Counts the file and its archives towards `budget`, which is enforced
+    /// after each rotation; see [`DiskBudget`].
+    pub fn disk_budget(mut self, budget: DiskBudget) -> Self {
+        self.config.disk_budget = Some(budget);
+        self
+    }
+
+    /// Deletes archives that `policy` no longer keeps after each rotation.
+    ///
+    /// Count and age rules are also applied as each new archive appears,
+    /// together with it, so even while archives wait for compression there
+    /// are never more than a [`RetentionRule::KeepCount`] allows. Size rules
+    /// wait until the archive is compressed. With a staging directory,
+    /// archives only count once published.
+    pub fn retention(mut self, policy: RetentionPolicy) -> Self {
+        self.config.retention = Some(policy);
+        self
+    }
+
+    /// Rotates a non-empty file once it has been active for `age`, even if
+    /// the mode's threshold hasn't been reached.
+    ///
+    /// The age is checked whenever the thresholds are, and in the background
+    /// by [`SharedRotatingFile`] and [`NonBlocking`], which rotate an idle
+    /// file at its [`RotatingFile::age_deadline`]. A bare `RotatingFile` has
+    /// no timer of its own, so it waits for the next write (or flush, with
+    /// `rotate_on_flush`) unless its owner calls
+    /// [`RotatingFile::rotate_if_due`]. A file resumed at construction counts
+    /// its age from then.
+    pub fn max_file_age(mut self, age: Duration) -> Self {
+        self.config.max_file_age = Some(age);
+        self
+    }
+
+    /// Also rotates a non-empty file on the first write (or flush, with
+    /// `rotate_on_flush`) of a local day other than the one it was started
+    /// on, so every file covers a single calendar day. A file resumed at
+    /// construction counts as started on the day it was last modified.
+    pub fn daily_boundary(mut self, enabled: bool) -> Self {
+        self.config.daily_boundary = enabled;
+        self
+    }
+
+    /// When the active file is flushed. Defaults to
+    /// [`FlushStrategy::Manual`].
+    ///
+    /// A [`NonBlocking`] writer's task flushes whenever its queue runs dry,
+    /// which `BeforeRotation` turns into a no-op as well.
+    pub fn flush_strategy(mut self, strategy: FlushStrategy) -> Self {
+        self.config.flush = strategy;
+        self
+    }
+
+    /// Caps the bytes a [`NonBlocking`] writer queues ahead of the disk.
+    /// Defaults to 1 MiB.
+    pub fn buffer_limit(mut self, bytes: usize) -> Self {
+        self.config.buffer_limit = bytes;
+        self
+    }
+
+    /// What a [`NonBlocking`] writer does once its buffer limit is reached.
+    /// Defaults to [`Overflow::Block`].
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.config.overflow = overflow;
+        self
+    }
+
+    /// Opens the file and hands it to a background task fed by the returned
+    /// [`NonBlocking`] handle.
+    ///
+    /// The task ends, flushing the file, once every handle is dropped.
+    pub async fn build_non_blocking(
+        self,
+    ) -> Result<(NonBlocking, JoinHandle<Result<(), Error>>), Error> {
+        let (limit, overflow) = (self.config.buffer_limit, self.config.overflow);
+        let file = self.build().await?;
+        Ok(NonBlocking::spawn(file, limit, overflow))
+    }
+
+    /// Keeps archives in `dir` instead of next to the active file, creating
+    /// it if needed.
+    ///
+    /// If `dir` is on another device (possibly through a symlink), rotated
+    /// files are copied there and deleted rather than renamed.
+    pub fn archive_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.config.naming.archive_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Puts each archive into a subdirectory of the archive directory named
+    /// by the rotation time in the `chrono` `format`, e.g. `%Y-%m-%d` or
+    /// `%Y-%m-%d/%H`, creating it as needed. Archives are found in any
+    /// subdirectory as deep as `format`; emptied ones are left in place.
+    pub fn date_directories(mut self, format: impl Into<String>) -> Self {
+        self.config.naming.date_dirs = Some(format.into());
+        self
+    }
+
+    /// Appends what triggered each rotation to the archive timestamp, e.g.
+    /// `app-2024-01-15_10-20-30-bytes.log.gz`. See [`RotationReason::token`].
+    ///
+    /// [`RotationReason::token`]: crate::RotationReason::token
+    pub fn trigger_in_name(mut self, enabled: bool) -> Self {
+        self.config.naming.trigger_in_name = enabled;
+        self
+    }
+
+    /// Checks at construction that rotation will work, rather than
+    /// finding out at the first rotation: that archive names come out as
+    /// something this crate recognizes as an archive again, and that files
+    /// can be created and renamed in the directories rotation uses, by
+    /// briefly creating a probe file in each.
+    pub fn verify_rotation(mut self, enabled: bool) -> Self {
+        self.config.verify_rotation = enabled;
+        self
+    }
+
+    /// Lays archive names out by `template`, e.g.
+    /// `{stem}.{date}.{ext}.{codec}` for `app.2024-01-15_10-20-30.log.gz`.
+    ///
+    /// The placeholders are `{stem}` and `{ext}` of the active file's name,
+    /// `{date}` for the timestamp, `{seq}` for the
+    /// [`sequence`](Self::sequence) number, `{trigger}` for
+    /// [`trigger_in_name`](Self::trigger_in_name), and `{codec}` for the
+    /// codec extension, which must come last. `{CODEC}` upper-cases it, as in
+    /// `.GZ`. Collision counters follow the timestamp. Content-addressed
+    /// names keep their own layout.
+    pub fn naming_template(mut self, template: &str) -> Self {
+        self.config.naming.template = Some(Template::new(template));
+        self
+    }
+
+    /// Makes timestamps in archive names, and the dated directories of
+    /// [`date_directories`](Self::date_directories), valid in file names by
+    /// passing them through `sanitize`. By default, characters the platform
+    /// doesn't allow in file names, such as `:` on Windows, become `_`.
+    ///
+    /// Names are told apart by parsing the timestamp format sanitized the
+    /// same way, so `sanitize` should replace characters one by one and
+    /// leave the `%` specifiers alone.
+    pub fn sanitize_names<F>(mut self, sanitize: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.config.naming.sanitizer = Some(Sanitizer(Arc::new(sanitize)));
+        self
+    }
+
+    /// What to do when a new archive's name is already taken. Defaults to
+    /// [`Collision::Disambiguate`].
+    pub fn on_collision(mut self, collision: Collision) -> Self {
+        self.config.collision = collision;
+        self
+    }
+
+    /// Builds archives in `dir`, creating it if needed, and only moves them
+    /// to the archive directory once compressed.
+    ///
+    /// Observers of the archive directory, e.g. on a network filesystem,
+    /// then never see a partial archive: across devices the finished file is
+    /// copied under a `.tmp` name, synced and renamed into place.
+    pub fn staging_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.config.staging_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Rounds byte thresholds to the nearest multiple of `block` bytes, at
+    /// least one block, where 0 stands for the block size of the active
+    /// file's filesystem (4 KiB off Unix). With `pad`, each file is filled
+    /// up with zero bytes to the next multiple before it's archived.
+    ///
+    /// Writes aren't split, so a file overshoots its threshold by up to a
+    /// write before rotating, and padding then costs up to a block less one
+    /// byte per archive, before compression. Readers of the archives see
+    /// the zeros.
+    pub fn block_alignment(mut self, block: usize, pad: bool) -> Self {
+        self.config.block_alignment = Some((block, pad));
+        self
+    }
+
+    /// Recreates the directory of the active file, and the file in it, if
+    /// they're deleted while the file is open, instead of writing into the
+    /// deleted file and failing to rotate from then on. Disabled by
+    /// default, as a directory deleted on purpose comes back.
+    ///
+    /// Writes look for the file at most once a second, so what's written
+    /// in between goes to the deleted file and is lost with it. A rotation
+    /// that fails also recreates the directory before reopening the file.
+    pub fn recreate_missing_dir(mut self, enabled: bool) -> Self {
+        self.config.recreate_dir = enabled;
+        self
+    }
+
+    /// Creates missing log, archive and staging directories with `mode`
+    /// (before the umask) instead of the default.
+    #[cfg(unix)]
+    pub fn dir_mode(mut self, mode: u32) -> Self {
+        self.config.dir_mode = Some(mode);
+        self
+    }
+
+    /// Chooses between renaming and copy-truncating the active file on
+    /// rotation. Defaults to [`RotationStrategy::RenameReopen`].
+    pub fn strategy(mut self, strategy: RotationStrategy) -> Self {
+        self.config.strategy = strategy;
+        self
+    }
+
+    /// Lets writing resume as soon as the fresh file is open, compressing the
+    /// archive (and applying retention) in a spawned task instead.
+    ///
+    /// [`RotatingFile::close`] and `shutdown` wait for outstanding
+    /// compressions and report the first error any of them hit, as does
+    /// [`RotatingFile::await_compressions`].
+    pub fn background_compression(mut self, enabled: bool) -> Self {
+        self.config.background_compression = enabled;
+        self
+    }
+
+    /// Rotates when `rotate` returns true, consulting it on a write (or
+    /// flush) that no other trigger rotates on, but at most once per
+    /// `interval`, so an expensive check isn't run on every write.
+    pub fn rotate_when<F>(mut self, interval: Duration, rotate: F) -> Self
+    where
+        F: Fn(&RotationStats) -> bool + Send + Sync + 'static,
+    {
+        self.config.trigger = Some((Arc::new(rotate), interval));
+        self
+    }
+
+    /// Asks `allow` before every rotation the threshold or
+    /// [`max_file_age`](Self::max_file_age) calls for. When it returns false
+    /// the rotation is deferred and writing continues in the active file,
+    /// asking again on the next write (or flush). It may be asked more than
+    /// once for a write that has to wait on the file.
+    ///
+    /// A hook that keeps refusing lets the file grow without bound.
+    /// Rotations forced by a JSON key change aren't subject to it.
+    pub fn rotation_veto<F>(mut self, allow: F) -> Self
+    where
+        F: Fn(&RotationStats) -> bool + Send + Sync + 'static,
+    {
+        self.config.veto = Some(Arc::new(allow));
+        self
+    }
+
+    /// Paces writes to about `bytes_per_second`, so bulk logging can't
+    /// saturate the disk. Writes beyond the rate wait, in bursts of up to
+    /// a tenth of a second's worth, which backpressures the caller.
+    ///
+    /// The limit applies to the bytes handed to the file, before line
+    /// timestamps and normalization, and relies on Tokio's timer.
+    pub fn write_rate_limit(mut self, bytes_per_second: u64) -> Self {
+        self.config.write_rate_limit = Some(bytes_per_second);
+        self
+    }
+
+    /// Allows at most `count` threshold rotations in any `window`, as a
+    /// guard against a threshold too small for the write load. Beyond that,
+    /// rotation is deferred and the active file grows until the window
+    /// allows another.
+    ///
+    /// Each run of deferrals counts once in
+    /// [`RotatingFile::times_throttled`], and logs a warning with the
+    /// `tracing` feature.
+    pub fn max_rotations(mut self, count: usize, window: Duration) -> Self {
+        self.config.max_rotations = Some((count, window));
+        self
+    }
+
+    /// Retries opening the active file up to `attempts` more times when it
+    /// fails transiently, e.g. with a sharing violation on Windows right
+    /// after another process closed it. The wait starts at `backoff` and
+    /// doubles with each attempt, on Tokio's timer, which the runtime must
+    /// enable.
+    pub fn open_retry(mut self, attempts: usize, backoff: Duration) -> Self {
+        self.config.open_retry = Some((attempts, backoff));
+        self
+    }
+
+    /// After a failed rotation, holds back threshold rotation for `backoff`,
+    /// doubling with each further failure, instead of retrying on the next
+    /// write. The active file keeps growing meanwhile. Explicit rotations
+    /// aren't held back.
+    pub fn rotation_cooldown(mut self, backoff: Duration) -> Self {
+        self.config.rotation_cooldown = Some(backoff);
+        self
+    }
+
+    /// Saves the active file's byte and line counts to an `app.log.checkpoint`
+    /// sidecar on [`RotatingFile::close`], so reopening only has to count
+    /// what was appended since.
+    ///
+    /// A file that has shrunk below its checkpoint, e.g. truncated by hand,
+    /// is counted from scratch instead. Rotation deletes the checkpoint.
+    pub fn checkpoint(mut self, enabled: bool) -> Self {
+        self.config.checkpoint = enabled;
+        self
+    }
+
+    /// Leaves rotated files plain, so rotation is only a rename, until
+    /// [`RotatingFile::compress_pending`] is called.
+    pub fn defer_compression(mut self, enabled: bool) -> Self {
+        self.config.defer_compression = enabled;
+        self
+    }
+
+    /// Asks `compress` whether to compress each file, e.g. by its extension
+    /// or by sniffing already-compressed content. Files it refuses stay
+    /// plain archives, and aren't compressed by
+    /// [`RotatingFile::compress_pending`] or [`RotatingFile::repair`] either.
+    ///
+    /// On rotation it's given the active file just before it's archived.
+    pub fn compress_if<F>(mut self, compress: F) -> Self
+    where
+        F: Fn(&Path) -> bool + Send + Sync + 'static,
+    {
+        self.config.compress_filter = Some(Arc::new(compress));
+        self
+    }
+
+    /// Calls `on_swap` with the hot path and the archive at the instant a
+    /// rotation swaps them, before anything is written to the new file.
+    ///
+    /// The file keeps its path: with [`RotationStrategy::RenameReopen`] the
+    /// old file was just moved to the archive name, still plain unless
+    /// compressed directly, and a new one created at the hot path. A tailer
+    /// that reads its old descriptor to the end and then reopens the hot
+    /// path misses nothing. With [`RotationStrategy::CopyTruncate`] the hot
+    /// path was truncated in place instead, and readers start over from
+    /// the beginning.
+    ///
+    /// It runs while rotation holds up writes to the file, so it must be
+    /// quick.
+    ///
+    /// [`RotationStrategy::RenameReopen`]: crate::RotationStrategy::RenameReopen
+    /// [`RotationStrategy::CopyTruncate`]: crate::RotationStrategy::CopyTruncate
+    pub fn on_swap<F>(mut self, on_swap: F) -> Self
+    where
+        F: Fn(&Path, &Path) + Send + Sync + 'static,
+    {
+        self.config.on_swap = Some(Arc::new(on_swap));
+        self
+    }
+
+    /// Names archives after the time `source` returns instead of the
+    /// rotation time, e.g. the event time of the last record when
+    /// backfilling old logs. It's given the active file just before it's
+    /// archived, and the rotation time is used when it returns `None`.
+    ///
+    /// Collisions between archives named after the same time are resolved
+    /// per [`on_collision`](Self::on_collision), and retention by age goes
+    /// by the returned time.
+    pub fn archive_timestamp<F>(mut self, source: F) -> Self
+    where
+        F: Fn(&Path) -> Option<DateTime<Local>> + Send + Sync + 'static,
+    {
+        self.config.timestamp_source = Some(Arc::new(source));
+        self
+    }
+
+    /// Calls `progress` with the bytes read so far and the total, when
+    /// known, every 256 KiB of each compression and once it has read
+    /// everything.
+    ///
+    /// It's called from the thread doing the compression, so it must be
+    /// cheap and must not block.
+    pub fn compression_progress<F>(mut self, progress: F) -> Self
+    where
+        F: Fn(u64, Option<u64>) + Send + Sync + 'static,
+    {
+        self.config.compression_progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Gzips each archive on up to `threads` threads, in blocks of 1 MiB
+    /// written as separate gzip members, which gzip readers decode as one
+    /// stream. Other codecs and compressing the active file stay on one
+    /// thread. Defaults to 1.
+    pub fn parallel_compression(mut self, threads: usize) -> Self {
+        self.config.compression_threads = threads;
+        self
+    }
+
+    /// Caps the memory that the blocks of [`parallel_compression`] hold at
+    /// once, across their input and output, by shrinking blocks and keeping
+    /// fewer in flight. Must be at least 64 KiB. The encoders' own working
+    /// state, a few hundred KiB per thread, comes on top. See
+    /// [`RotatingFile::compression_peak_memory`].
+    ///
+    /// [`parallel_compression`]: Self::parallel_compression
+    pub fn compression_memory_budget(mut self, bytes: usize) -> Self {
+        self.config.compression_memory_budget = Some(bytes);
+        self
+    }
+
+    /// Compresses the active file straight into its archive on rotation, so
+    /// a plain archive never exists, and only then removes (or, with
+    /// [`RotationStrategy::CopyTruncate`], truncates) it.
+    ///
+    /// Writes wait for the compression, even with
+    /// [`background_compression`](Self::background_compression).
+    pub fn direct_compression(mut self, enabled: bool) -> Self {
+        self.config.direct_compression = enabled;
+        self
+    }
+
+    /// Compresses the active file itself as it's written, so no plain text
+    /// ever reaches the disk, and rotation only renames it.
+    ///
+    /// Writes are held in memory, up to 64 KiB, and appended as a complete
+    /// member of the codec on each flush, so the file always decodes as a
+    /// whole. Frequent flushes compress worse, held writes are lost in a
+    /// crash, and the active file can only be read by decompressing it from
+    /// the start. Thresholds count the uncompressed bytes.
+    pub fn compress_active(mut self, enabled: bool) -> Self {
+        self.config.compress_active = enabled;
+        self
+    }
+
+    /// Doesn't read an existing file at construction and counts from zero,
+    /// as [`initial_counts(0, 0)`](Self::initial_counts) does, for when
+    /// resuming a file's counts isn't needed.
+    pub fn skip_startup_scan(self) -> Self {
+        self.initial_counts(0, 0)
+    }
+
+    /// Empties an existing file at construction instead of resuming it,
+    /// so every run starts a fresh file. Nothing is archived.
+    pub fn truncate_on_open(mut self, enabled: bool) -> Self {
+        self.config.truncate_on_open = enabled;
+        self
+    }
+
+    /// Rewrites the ending of every written line to `ending`. Lines are
+    /// then buffered until complete, so an ending split across two writes
+    /// is still recognized.
+    pub fn line_ending(mut self, ending: LineEnding) -> Self {
+        self.config.line_ending = Some(ending);
+        self
+    }
+
+    /// How lines are counted towards [`RotationMode::Lines`]. Defaults to
+    /// [`LineCounting::Lf`].
+    pub fn line_counting(mut self, counting: LineCounting) -> Self {
+        self.config.line_counting = counting;
+        self
+    }
+
+    /// Prefixes every written line with the local time in the `chrono`
+    /// `format`, followed by a space. Lines are buffered until complete, and
+    /// the counts and rotation limits include the prefix.
+    ///
+    /// The clock is read and formatted once per line, which costs more than
+    /// the write itself for short lines.
+    pub fn line_timestamps(mut self, format: impl Into<String>) -> Self {
+        self.config.line_timestamps = Some(format.into());
+        self
+    }
+
+    /// Caps how much of an unterminated line is buffered for the features
+    /// that wait for whole lines. Once a line reaches `bytes` without a
+    /// newline, what's buffered is written as is, and with `rotate` the
+    /// file rotates after it.
+    ///
+    /// This splits the logical line: its continuation is written without a
+    /// timestamp, and doesn't count towards key or pattern triggers.
+    pub fn max_line_bytes(mut self, bytes: usize, rotate: bool) -> Self {
+        self.config.max_line_bytes = Some((bytes, rotate));
+        self
+    }
+
+    /// Starts counting from `lines` and `bytes` instead of reading the
+    /// existing file to count them, e.g. with counts saved by a previous
+    /// session. Takes precedence over [`checkpoint`](Self::checkpoint).
+    pub fn initial_counts(mut self, lines: usize, bytes: usize) -> Self {
+        self.config.initial_counts = Some((lines, bytes));
+        self
+    }
+
+    /// Writes an index next to each compressed archive, `<archive>.idx`,
+    /// giving the offset into the decompressed content of every `every`th
+    /// line, for seeking without decompressing everything before it.
+    ///
+    /// Each index line is `<line> <offset>`, with lines counted from 0 and
+    /// starting at `0 0`. It's built while compressing, without another
+    /// read. Content-addressed archives get no index.
+    pub fn line_index(mut self, every: usize) -> Self {
+        self.config.line_index = Some(every);
+        self
+    }
+
+    /// After each archive is finished, creates an empty `<archive>.done`
+    /// file next to it for watchers that wait for a marker. Retention
+    /// removes the marker along with its archive.
+    pub fn done_markers(mut self, enabled: bool) -> Self {
+        self.config.done_markers = enabled;
+        self
+    }
+
+    /// Keeps a symlink such as `app.latest.log.gz` in the archive directory,
+    /// pointing at the newest finished archive. It's replaced atomically as
+    /// each archive is finished, and left alone when an older one finishes
+    /// late. Does nothing on platforms other than Unix.
+    pub fn latest_link(mut self, enabled: bool) -> Self {
+        self.config.latest_link = enabled;
+        self
+    }
+
+    /// Sets the mtime of compressed archives, e.g. to their source's for
+    /// tools that sort by mtime. Defaults to [`ArchiveMtime::Now`].
+    pub fn archive_mtime(mut self, mtime: ArchiveMtime) -> Self {
+        self.config.archive_mtime = mtime;
+        self
+    }
+
+    /// Sets the permissions of compressed archives. Defaults to
+    /// [`ArchivePermissions::Default`].
+    pub fn archive_permissions(mut self, permissions: ArchivePermissions) -> Self {
+        self.config.archive_permissions = permissions;
+        self
+    }
+
+    /// Syncs each compressed archive, and the directory holding it, to disk
+    /// before deleting its source, so a crash can't lose both. Disabled by
+    /// default, as syncing is slow. Syncs run on the compression executor.
+    pub fn sync_archives(mut self, enabled: bool) -> Self {
+        self.config.sync_archives = enabled;
+        self
+    }
+
+    /// Sets the immutable flag of `chattr +i` on each finished archive, so
+    /// it can't be changed or deleted until the flag is cleared. Retention
+    /// and the disk budget clear it before deleting an archive. Disabled by
+    /// default.
+    ///
+    /// Setting and clearing the flag needs `CAP_LINUX_IMMUTABLE`; without
+    /// it rotations report `PermissionDenied`. Linux only, ignored
+    /// elsewhere.
+    pub fn immutable_archives(mut self, enabled: bool) -> Self {
+        self.config.immutable_archives = enabled;
+        self
+    }
+
+    /// Tells the kernel, on Linux, that rotated data is read once in order
+    /// and not needed afterwards: compression reads its input sequentially,
+    /// then drops it and each finished archive from the page cache, so logs
+    /// don't push out hotter data. Does nothing elsewhere.
+    ///
+    /// Only clean pages are dropped; combine with
+    /// [`sync_archives`](Self::sync_archives) to drop archives that were
+    /// just written, too.
+    pub fn fadvise(mut self, enabled: bool) -> Self {
+        self.config.fadvise = enabled;
+        self
+    }
+
+    /// Decompresses each new archive and checks it yields exactly as many
+    /// bytes as the source before the source is deleted.
+    ///
+    /// On a mismatch the source is kept, the archive is discarded and the
+    /// rotation fails with [`ErrorKind::InvalidData`]. A [`Codec::Custom`]
+    /// is decoded with [`Compressor::decode`](crate::Compressor::decode).
+    pub fn verify_size(mut self, enabled: bool) -> Self {
+        self.config.verify_size = enabled;
+        self
+    }
+
+    /// Ends each compressed archive with the CRC-32 and length of its
+    /// content, so it can be checked on its own with
+    /// [`verify_checksum_footer`]. Gzip archives get an empty last member
+    /// carrying them in its extra field, zstd archives a skippable frame;
+    /// both decode to nothing, so `gunzip`, `zstd` and other readers are
+    /// unaffected. Plain archives get none.
+    ///
+    /// Consolidation that concatenates archives leaves each footer in
+    /// place, so only the last archive's is found and it no longer matches.
+    ///
+    /// [`verify_checksum_footer`]: crate::verify_checksum_footer
+    pub fn checksum_footer(mut self, enabled: bool) -> Self {
+        self.config.checksum_footer = enabled;
+        self
+    }
+
+    /// Compresses archives as independent gzip members or zstd frames of
+    /// 1 MiB of content each, so that a compression interrupted by a crash
+    /// can be resumed. The next compression of the same archive, e.g. by
+    /// [`RotatingFile::repair`], keeps the whole members of the `.tmp` left
+    /// behind and only compresses the rest. What they hold is read again,
+    /// but not compressed again.
+    ///
+    /// With [`compress_active`](Self::compress_active), a member torn by a
+    /// crash is cut off the active file when it's reopened, so that new
+    /// members follow whole ones.
+    ///
+    /// Each member starts afresh, so archives come out slightly larger.
+    pub fn resumable_compression(mut self, enabled: bool) -> Self {
+        self.config.resumable_compression = enabled;
+        self
+    }
+
+    /// Rotates whenever the value at `pointer` (a JSON pointer such as
+    /// `/session/id`) differs from the previous line's, so each file holds a
+    /// single value.
+    ///
+    /// Every complete line is parsed as JSON, which costs far more than the
+    /// other modes. Lines that don't parse or lack the key never trigger a
+    /// rotation, and the first line after opening only records its key.
+    /// Unterminated lines are buffered until their newline arrives.
+    #[cfg(feature = "json")]
+    pub fn rotate_on_json_change(mut self, pointer: impl Into<String>) -> Self {
+        self.config.json_key = Some(pointer.into());
+        self
+    }
+
+    /// Rotates after every line matching `pattern`, such as a restart
+    /// banner, so the next write starts a new file. The line is matched
+    /// without its line ending; invalid UTF-8 is replaced first.
+    ///
+    /// Unterminated lines are buffered until their newline arrives, as with
+    /// [`rotate_on_json_change`](Self::rotate_on_json_change).
+    #[cfg(feature = "regex")]
+    pub fn rotate_after_match(mut self, pattern: regex::Regex) -> Self {
+        self.config.rotate_after_match = Some(pattern);
+        self
+    }
+
+    /// Names compressed archives by a hash of their plaintext content, e.g.
+    /// `app-<sha256 prefix>.log.gz`, instead of by timestamp. Rotating content
+    /// that was already archived leaves the existing archive in place.
+    #[cfg(feature = "content-hash")]
+    pub fn content_addressed(mut self) -> Self {
+        self.config.naming.content_addressed = true;
+        self
+    }
+
+    /// Checks the configuration for coherence and opens the file.
+    ///
+    /// All problems are collected into a single [`ConfigError`] rather than
+    /// reporting only the first. The file and directories must be writable,
+    /// which on Linux is asked of `access` so a read-only mount is caught
+    /// too.
+    ///
+    /// Of retention deleting faster than compression, only a
+    /// [`RetentionRule::KeepCount`] below the width of the executor is
+    /// caught, and only for one that reports its width through
+    /// [`CompressionExecutor::threads`], like [`CompressionPool`]. The
+    /// default executor is unbounded, so nothing is known to compare with;
+    /// size rules wait for compression, age rules depend on how fast the
+    /// file fills, and [`compress_active`](Self::compress_active)
+    /// compresses as it writes, so none of those are checked.
+    ///
+    /// [`CompressionPool`]: crate::CompressionPool
+    pub async fn build(self) -> Result<RotatingFile, Error> {
+        let problems = self.problems().await;
+        if !problems.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                ConfigError { problems },
+            ));
+        }
+        #[cfg(feature = "tracing")]
+        for warning in self.warnings() {
+            tracing::warn!("{}", warning);
+        }
+        RotatingFile::open(self.path, self.mode, self.config).await
+    }
+
+    /// Describes options that are accepted but won't work as configured on
+    /// the filesystems of the log and archive directories, e.g. a
+    /// [`latest_link`](Self::latest_link) on FAT. Best effort, see
+    /// [`filesystem_type`]; [`build`](Self::build) logs them with
+    /// `tracing`.
+    ///
+    /// [`filesystem_type`]: crate::filesystem_type
+    pub fn warnings(&self) -> Vec<String> {
+        let config = &self.config;
+        let mut dirs = vec![crate::archive::directory(&self.path)];
+        dirs.extend(config.naming.archive_dir.as_deref());
+        dirs.extend(config.staging_dir.as_deref());
+        crate::filesystem::warnings(config, &dirs)
+    }
+
+    async fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let path = self.path.display();
+        let config = &self.config;
+
+        if self.path.file_name().is_none() {
+            problems.push(format!("{} does not name a file", path));
+        } else if self.path.extension().is_none() {
+            problems.push(format!(
+                "{} has no extension to place the archive timestamp before",
+                path
+            ));
+        }
+        match tokio::fs::metadata(&self.path).await {
+            Ok(m) if m.is_dir() => problems.push(format!("{} is a directory", path)),
+            Ok(m) if !writable(&self.path, &m) => problems.push(format!("{} is read-only", path)),
+            _ => {}
+        }
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            match tokio::fs::metadata(dir).await {
+                Ok(m) if !m.is_dir() => {
+                    problems.push(format!("{} is not a directory", dir.display()))
+                }
+                Ok(m) if !writable(dir, &m) => {
+                    problems.push(format!("{} is read-only", dir.display()))
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(dir) = &config.naming.archive_dir {
+            if let Ok(m) = tokio::fs::metadata(dir).await {
+                if !m.is_dir() {
+                    problems.push(format!("archive_dir {} is not a directory", dir.display()));
+                } else if !writable(dir, &m) {
+                    problems.push(format!("archive_dir {} is read-only", dir.display()));
+                }
+            }
+        }
+
+        mode_problems(&self.mode, false, &mut problems);
+        if !config.rotate_on_write && !config.rotate_on_flush {
+            problems.push("rotation is disabled on both write and flush".to_string());
+        }
+        if config.max_file_age.is_some_and(|age| age.is_zero()) {
+            problems.push("max_file_age must be nonzero".to_string());
+        }
+        if config.line_index == Some(0) {
+            problems.push("line_index needs an interval above zero".to_string());
+        }
+        if config.max_rotations.is_some_and(|(count, _)| count == 0) {
+            problems.push("max_rotations needs a count above zero".to_string());
+        }
+        if config.naming.date_dirs.is_some() && config.staging_dir.is_some() {
+            problems.push("date_directories and staging_dir are exclusive".to_string());
+        }
+        if config
+            .compression_memory_budget
+            .is_some_and(|budget| budget < MIN_MEMORY_BUDGET)
+        {
+            problems.push("compression_memory_budget must be at least 64 KiB".to_string());
+        }
+        if config.write_rate_limit == Some(0) {
+            problems.push("write_rate_limit must be nonzero".to_string());
+        }
+        if matches!(config.max_line_bytes, Some((0, _))) {
+            problems.push("max_line_bytes must be nonzero".to_string());
+        }
+        if let Some(template) = &config.naming.template {
+            problems.extend(template.problems.iter().cloned());
+            for literal in template.literals() {
+                if config.naming.sanitize(literal) != literal {
+                    problems.push(format!(
+                        "naming template text {:?} isn't valid in file names",
+                        literal
+                    ));
+                }
+            }
+            if template.has(&Part::Seq) != config.naming.sequence_width.is_some() {
+                problems.push("naming template needs {seq} exactly when sequence is set".into());
+            }
+            if template.has(&Part::Trigger) != config.naming.trigger_in_name {
+                problems.push(
+                    "naming template needs {trigger} exactly when trigger_in_name is set".into(),
+                );
+            }
+        }
+
+        if config.compress_active {
+            if config.codec == Codec::None {
+                problems.push("compress_active needs a codec".to_string());
+            }
+            let exclusive = [
+                (config.direct_compression, "direct_compression"),
+                (config.defer_compression, "defer_compression"),
+                (!config.extra_codecs.is_empty(), "extra_codecs"),
+                (config.line_index.is_some(), "line_index"),
+                (config.checkpoint, "checkpoint"),
+                (config.naming.content_addressed, "content_addressed"),
+                (config.checksum_footer, "checksum_footer"),
+                (
+                    config.block_alignment.is_some_and(|(_, pad)| pad),
+                    "block_alignment padding",
+                ),
+            ];
+            for (_, option) in exclusive.iter().filter(|(set, _)| *set) {
+                problems.push(format!("compress_active and {} are exclusive", option));
+            }
+        }
+        if config.direct_compression && config.defer_compression {
+            problems.push("direct_compression and defer_compression are exclusive".to_string());
+        }
+        if config.immutable_archives {
+            // Each of these rewrites or replaces archives once finished
+            let rewriting = [
+                (config.defer_compression, "defer_compression"),
+                (
+                    config
+                        .retention
+                        .as_ref()
+                        .is_some_and(|p| p.consolidate.is_some()),
+                    "consolidation",
+                ),
+            ];
+            for (_, option) in rewriting.iter().filter(|(set, _)| *set) {
+                problems.push(format!("immutable_archives and {} are exclusive", option));
+            }
+        }
+
+        if config.buffer_limit == 0 {
+            problems.push("buffer_limit must be nonzero".to_string());
+        }
+
+        for codec in std::iter::once(&config.codec).chain(&config.extra_codecs) {
+            if let Codec::Gzip(level) = codec {
+                if *level > 9 {
+                    problems.push(format!("gzip level {} is out of range 0-9", level));
+                }
+            }
+            if let Some(extension) = codec.custom_extension() {
+                if extension.is_empty() || extension.contains(['.', '/']) {
+                    problems.push(format!("custom extension {:?} isn't one word", extension));
+                }
+                if config.compress_active {
+                    problems.push(format!(
+                        "compress_active can't decode the custom codec {}",
+                        extension
+                    ));
+                }
+            }
+        }
+        for (i, extra) in config.extra_codecs.iter().enumerate() {
+            let mut earlier = std::iter::once(&config.codec).chain(&config.extra_codecs[..i]);
+            if extra.extension().is_none() || config.codec.extension().is_none() {
+                problems.push("extra_codecs can't involve Codec::None".to_string());
+            } else if earlier.any(|c| c.extension() == extra.extension()) {
+                problems.push(format!("extra codec {:?} repeats an extension", extra));
+            }
+        }
+
+        if let Some(policy) = &config.retention {
+            for rule in policy.rules() {
+                let deletes_everything = match rule {
+                    RetentionRule::KeepCount(n) => *n == 0,
+                    RetentionRule::KeepSize(n) => *n == 0,
+                    RetentionRule::KeepAge(age) => age.is_zero(),
+                };
+                if deletes_everything {
+                    problems.push(format!(
+                        "retention rule {:?} would delete every archive as soon as it is written",
+                        rule
+                    ));
+                }
+                // Plain archives count towards the rule while they wait, so
+                // a pool wider than it only ever compresses archives that
+                // retention is about to delete
+                let compressing = config.background_compression
+                    && config.codec != Codec::None
+                    && !config.defer_compression;
+                if let (RetentionRule::KeepCount(n), Some(threads), true) =
+                    (rule, config.executor.threads(), compressing)
+                {
+                    if *n < threads {
+                        problems.push(format!(
+                            "retention keeps {} archives, fewer than the {} the compression pool works on at once",
+                            n, threads
+                        ));
+                    }
+                }
+            }
+        }
+        if config.verify_rotation && self.path.extension().is_some() {
+            self.rotation_problems(&mut problems).await;
+        }
+        problems
+    }
+
+    /// Dry-runs the naming and file operations of a rotation.
+    async fn rotation_problems(&self, problems: &mut Vec<String>) {
+        let naming = &self.config.naming;
+        let plain = naming.archive_path(&self.path, &Local::now(), 1, RotationReason::Bytes, 0);
+        let compressed = naming.output_path(&self.config.codec, &plain);
+        if !naming.content_addressed {
+            for archive in [&plain, &compressed] {
+                let parsed = naming.parse(&self.path, archive);
+                let sequence = naming.sequence_width.map(|_| 1);
+                if parsed.is_none_or(|info| info.sequence != sequence) {
+                    problems.push(format!(
+                        "archive {} would not be recognized as an archive",
+                        archive.display()
+                    ));
+                }
+            }
+        }
+
+        let mut dirs = vec![archive::directory(&self.path), naming.directory(&self.path)];
+        dirs.extend(self.config.staging_dir.as_deref());
+        dirs.dedup();
+        for dir in dirs {
+            // Created when the file is opened
+            if !tokio::fs::try_exists(dir).await.unwrap_or(true) {
+                continue;
+            }
+            let probe = dir.join(format!(
+                ".{}.probe",
+                self.path.file_name().unwrap().to_string_lossy()
+            ));
+            let renamed = probe.with_extension("probe.renamed");
+            let result = async {
+                tokio::fs::write(&probe, b"").await?;
+                tokio::fs::rename(&probe, &renamed).await?;
+                tokio::fs::remove_file(&renamed).await
+            };
+            match result.await {
+                Ok(()) => {}
+                Err(e) => {
+                    let _ = tokio::fs::remove_file(&probe).await;
+                    problems.push(format!(
+                        "cannot create and rename files in {}: {}",
+                        dir.display(),
+                        e
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Describes what's wrong with `mode`, which is inside a
+/// [`RotationMode::All`] if `nested`.
+fn mode_problems(mode: &RotationMode, nested: bool, problems: &mut Vec<String>) {
+    match *mode {
+        RotationMode::Lines(0)
+        | RotationMode::Bytes(0)
+        | RotationMode::Chars(0)
+        | RotationMode::Writes(0) => {
+            problems.push("rotation threshold must be nonzero".to_string())
+        }
+        RotationMode::Adaptive { .. } if nested => {
+            problems.push("RotationMode::All can't hold adaptive rotation".to_string())
+        }
+        RotationMode::Adaptive {
+            files,
+            window,
+            initial_bytes,
+        } if files == 0 || window.is_zero() || initial_bytes == 0 => {
+            problems.push("adaptive rotation needs nonzero files, window and bytes".to_string())
+        }
+        RotationMode::All(ref modes) if modes.is_empty() => {
+            problems.push("RotationMode::All needs at least one mode".to_string())
+        }
+        RotationMode::All(ref modes) => {
+            for mode in modes {
+                mode_problems(mode, true, problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fresh_dir;
+
+    #[tokio::test]
+    async fn build_lists_every_problem() {
+        let dir = fresh_dir("build_lists_every_problem").await;
+        let err = RotatingFile::builder(dir.join("app"), RotationMode::Bytes(0))
+            .codec(Codec::Gzip(12))
+            .retention(RetentionPolicy::any(vec![RetentionRule::KeepCount(0)]))
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let config = err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<ConfigError>()
+            .unwrap();
+        assert_eq!(config.problems.len(), 4, "{:?}", config.problems);
+        assert!(err.to_string().contains("gzip level 12"));
+    }
+
+    #[tokio::test]
+    async fn retention_outpacing_compression() {
+        let dir = fresh_dir("retention_outpacing_compression").await;
+        let builder = |keep| {
+            RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+                .background_compression(true)
+                .compression_executor(Arc::new(crate::CompressionPool::new(4).unwrap()))
+                .retention(RetentionPolicy::any(vec![RetentionRule::KeepCount(keep)]))
+        };
+        let err = builder(2).build().await.err().unwrap();
+        assert!(err.to_string().contains("fewer than the 4"), "{}", err);
+        assert!(builder(4).build().await.is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn read_only_mount() {
+        let mounts = std::fs::read_to_string("/proc/mounts").unwrap();
+        let dir = match mounts
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<_> = line.split(' ').collect();
+                fields[3]
+                    .split(',')
+                    .any(|o| o == "ro")
+                    .then(|| PathBuf::from(fields[1]))
+            })
+            .find(|dir| dir.is_dir())
+        {
+            Some(dir) => dir,
+            // Nothing read-only to try
+            None => return,
+        };
+        let err = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("is read-only"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn verify_rotation() {
+        let dir = fresh_dir("verify_rotation").await;
+        // The sequence number runs into the timestamp's seconds
+        let builder = || {
+            RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+                .sequence(3)
+                .naming_template("{stem}-{date}{seq}.{ext}.{codec}")
+        };
+        assert!(builder().build().await.is_ok());
+        let err = builder().verify_rotation(true).build().await.err().unwrap();
+        assert!(
+            err.to_string().contains("would not be recognized"),
+            "{}",
+            err
+        );
+
+        let file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .verify_rotation(true)
+            .build()
+            .await;
+        assert!(file.is_ok());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn filesystem_warnings() {
+        use crate::{filesystem_type, Filesystem};
+
+        let builder = |dir: &Path| {
+            RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+                .sync_archives(true)
+                .fadvise(true)
+                .immutable_archives(true)
+        };
+        let shm = Path::new("/dev/shm");
+        if filesystem_type(shm) == Some(Filesystem::Tmpfs) {
+            let warnings = builder(shm).warnings();
+            assert_eq!(warnings.len(), 3, "{:?}", warnings);
+            assert!(warnings[0].contains("sync_archives has no effect"));
+            assert!(warnings[2].contains("immutable flag"));
+        }
+        let dir = Path::new("out/filesystem_warnings");
+        std::fs::create_dir_all(dir).unwrap();
+        if let Some(fs @ (Filesystem::Ext | Filesystem::Xfs | Filesystem::Btrfs)) =
+            filesystem_type(dir)
+        {
+            assert!(fs.dir_sync() && fs.page_cache() && fs.immutable());
+            assert!(builder(dir).warnings().is_empty());
+        }
+        assert!(!Filesystem::Fuse.dir_sync());
+    }
+
+    #[test]
+    fn default_config() {
+        let config = RotationConfig::default();
+        assert_eq!(config.codec(), Codec::Gzip(1));
+        assert_eq!(config.timestamp_format(), "%Y-%m-%d_%H-%M-%S");
+        assert!(config.retention().is_none());
+
+        let config = config
+            .with_codec(Codec::None)
+            .with_timestamp_format("%Y-%m-%d")
+            .with_retention(RetentionPolicy::any(vec![RetentionRule::KeepCount(3)]));
+        let builder = RotatingFile::builder("out/default_config/app.log", RotationMode::Lines(1))
+            .config(config)
+            .sequence(2);
+        assert_eq!(builder.config.codec(), Codec::None);
+        assert_eq!(builder.config.timestamp_format(), "%Y-%m-%d");
+        assert!(builder.config.retention().is_some());
+        assert_eq!(builder.config.naming.sequence_width, Some(2));
+    }
+}
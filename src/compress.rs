@@ -0,0 +1,1804 @@
+use crate::{archive, builder::RotationConfig};
+use flate2::{write::GzEncoder, Compression};
+use futures::channel::oneshot;
+use std::{
+    collections::VecDeque,
+    io::{BufRead, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+};
+
+/// A unit of blocking compression work.
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Somewhere to run blocking compression jobs.
+///
+/// The default is [`TokioBlocking`], which hands jobs to Tokio's blocking
+/// pool. Services that size that pool tightly can plug in a
+/// [`CompressionPool`] (or their own executor) to keep compression isolated.
+pub trait CompressionExecutor: Send + Sync {
+    fn execute(&self, job: Job);
+
+    /// How many jobs run at once, if that's bounded.
+    fn threads(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Runs compression through `tokio::task::spawn_blocking`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioBlocking;
+
+impl CompressionExecutor for TokioBlocking {
+    fn execute(&self, job: Job) {
+        tokio::task::spawn_blocking(job);
+    }
+}
+
+/// A fixed-size pool of dedicated compression threads.
+///
+/// Jobs are queued and picked up by the first idle thread, so a pool of
+/// `n` threads never compresses more than `n` files at once.
+pub struct CompressionPool {
+    sender: Mutex<mpsc::Sender<Job>>,
+    threads: usize,
+}
+
+impl CompressionPool {
+    pub fn new(threads: usize) -> Result<Self, Error> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("rotating-file-compress-{}", i))
+                .spawn(move || loop {
+                    let job = match receiver.lock() {
+                        Ok(rx) => rx.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })?;
+        }
+        Ok(Self {
+            sender: Mutex::new(sender),
+            threads: threads.max(1),
+        })
+    }
+}
+
+impl CompressionExecutor for CompressionPool {
+    fn execute(&self, job: Job) {
+        if let Ok(sender) = self.sender.lock() {
+            let _ = sender.send(job);
+        }
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(self.threads)
+    }
+}
+
+/// Runs `f` on `executor` and waits for its result.
+pub(crate) async fn run<F, T>(executor: &dyn CompressionExecutor, f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    executor.execute(Box::new(move || {
+        let _ = tx.send(f());
+    }));
+    rx.await
+        .map_err(|_| Error::other("compression job was dropped"))?
+}
+
+/// Compression supplied by the user, such as a proprietary codec or an
+/// external process, for [`Codec::Custom`]. The crate still takes care of
+/// rotating, naming and retaining the archives.
+///
+/// Readers such as [`Replay`](crate::Replay) return these archives as
+/// stored, and [`compress_active`] is refused with it.
+/// [`verify_size`] decodes archives with [`decode`](Self::decode).
+///
+/// [`compress_active`]: crate::RotatingFileBuilder::compress_active
+/// [`verify_size`]: crate::RotatingFileBuilder::verify_size
+pub trait Compressor: Send + Sync {
+    /// Compresses all of `input` into `output`.
+    fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), Error>;
+
+    /// The extension appended to archives, without the dot, e.g. `xz`.
+    fn extension(&self) -> &str;
+
+    /// Decompresses all of `input` into `output`. The default refuses with
+    /// [`ErrorKind::Unsupported`], which fails every verification.
+    fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), Error> {
+        let _ = (input, output);
+        Err(undecodable())
+    }
+}
+
+/// How rotated files are compressed.
+#[derive(Clone)]
+pub enum Codec {
+    /// Leaves rotated files as plain files.
+    None,
+    /// Gzip at levels 0 to 9. Level 0 stores the data uncompressed inside a
+    /// valid gzip container, which costs no CPU but keeps `.gz` tooling
+    /// working.
+    Gzip(u32),
+    /// Zstandard at the given level. Zstandard itself treats 0 as "default",
+    /// so level 0 here selects the fastest level instead, whose output is
+    /// close to the input size.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+    /// A [`Compressor`] of the user's. Codecs are equal when they are the
+    /// same `Arc`.
+    Custom(Arc<dyn Compressor>),
+}
+
+impl std::fmt::Debug for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::None => f.write_str("None"),
+            Codec::Gzip(level) => f.debug_tuple("Gzip").field(level).finish(),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd(level) => f.debug_tuple("Zstd").field(level).finish(),
+            Codec::Custom(compressor) => f
+                .debug_tuple("Custom")
+                .field(&compressor.extension())
+                .finish(),
+        }
+    }
+}
+
+impl PartialEq for Codec {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Codec::None, Codec::None) => true,
+            (Codec::Gzip(a), Codec::Gzip(b)) => a == b,
+            #[cfg(feature = "zstd")]
+            (Codec::Zstd(a), Codec::Zstd(b)) => a == b,
+            (Codec::Custom(a), Codec::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Codec {}
+
+/// The built-in codecs, where [`Codec::None`] copies its input and has an
+/// empty extension.
+impl Compressor for Codec {
+    fn encode(&self, mut input: &mut dyn Read, output: &mut dyn Write) -> Result<(), Error> {
+        encode_or_copy(self, &mut input, output)
+    }
+
+    fn extension(&self) -> &str {
+        Codec::extension(self).unwrap_or("")
+    }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Gzip(Compression::fast().level())
+    }
+}
+
+/// The modification time given to compressed archives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ArchiveMtime {
+    /// When compression finished.
+    #[default]
+    Now,
+    /// The mtime of the rotated file, i.e. its last write.
+    Source,
+    Fixed(std::time::SystemTime),
+}
+
+/// The permissions given to compressed archives.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum ArchivePermissions {
+    /// Whatever newly created files get.
+    #[default]
+    Default,
+    /// Those of the rotated file.
+    Source,
+    Fixed(std::fs::Permissions),
+}
+
+/// Extensions of every codec, whether or not it is compiled in, so
+/// archives are recognized regardless of the enabled features.
+pub(crate) const COMPRESSED_EXTENSIONS: &[&str] = &["gz", "zst"];
+
+impl Codec {
+    /// The extension appended to compressed archives, if any.
+    pub fn extension(&self) -> Option<&str> {
+        match self {
+            Codec::None => None,
+            Codec::Gzip(_) => Some("gz"),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd(_) => Some("zst"),
+            Codec::Custom(compressor) => Some(compressor.extension()),
+        }
+    }
+
+    /// The extension of a [`Codec::Custom`].
+    pub(crate) fn custom_extension(&self) -> Option<&str> {
+        match self {
+            Codec::Custom(compressor) => Some(compressor.extension()),
+            _ => None,
+        }
+    }
+
+    fn encode<R: Read, W: std::io::Write>(&self, input: &mut R, output: W) -> Result<(), Error> {
+        match *self {
+            Codec::None => Ok(()),
+            Codec::Gzip(level) => {
+                let mut gz = GzEncoder::new(output, Compression::new(level.min(9)));
+                std::io::copy(input, &mut gz)?;
+                gz.finish()?;
+                Ok(())
+            }
+            #[cfg(feature = "zstd")]
+            Codec::Zstd(level) => {
+                let level = match level {
+                    0 => zstd::zstd_safe::min_c_level(),
+                    level => level,
+                };
+                let mut zst = zstd::Encoder::new(output, level)?;
+                std::io::copy(input, &mut zst)?;
+                zst.finish()?;
+                Ok(())
+            }
+            Codec::Custom(ref compressor) => {
+                let mut output = output;
+                compressor.encode(input, &mut output)
+            }
+        }
+    }
+
+    /// Decompresses the archive at `path`, counting the output bytes.
+    fn decoded_len(&self, path: &Path) -> Result<u64, Error> {
+        let input = std::fs::File::open(path)?;
+        match self {
+            Codec::None => Ok(input.metadata()?.len()),
+            Codec::Gzip(_) => std::io::copy(
+                &mut flate2::read::MultiGzDecoder::new(input),
+                &mut std::io::sink(),
+            ),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd(_) => std::io::copy(&mut zstd::Decoder::new(input)?, &mut std::io::sink()),
+            Codec::Custom(compressor) => {
+                let mut counter = Counter(0);
+                compressor.decode(&mut std::io::BufReader::new(input), &mut counter)?;
+                Ok(counter.0)
+            }
+        }
+    }
+}
+
+/// Counts the bytes written to it and drops them.
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn undecodable() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "archives of a custom compressor can't be decoded",
+    )
+}
+
+/// Plain writes waiting to be appended to an active file compressed by
+/// [`RotatingFileBuilder::compress_active`], and compressed members waiting
+/// to reach it.
+///
+/// [`RotatingFileBuilder::compress_active`]: crate::RotatingFileBuilder::compress_active
+#[derive(Default)]
+pub(crate) struct ActiveStream {
+    plain: Vec<u8>,
+    pub(crate) outgoing: Vec<u8>,
+}
+
+/// How much plain data an [`ActiveStream`] holds before compressing it.
+const ACTIVE_MEMBER_BYTES: usize = 64 * 1024;
+
+impl ActiveStream {
+    pub(crate) fn push(&mut self, buf: &[u8]) {
+        self.plain.extend_from_slice(buf);
+    }
+
+    pub(crate) fn full(&self) -> bool {
+        self.plain.len() >= ACTIVE_MEMBER_BYTES
+    }
+
+    /// Compresses the plain data held into one complete member, queued in
+    /// `outgoing`.
+    pub(crate) fn seal(&mut self, codec: &Codec) -> Result<(), Error> {
+        if !self.plain.is_empty() {
+            codec.encode(&mut self.plain.as_slice(), &mut self.outgoing)?;
+            self.plain.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Counts the lines and bytes that `path`, compressed with `codec` by
+/// [`ActiveStream`], decodes to, up to a member torn by a crash. Blocking.
+pub(crate) fn decoded_counts(
+    path: &Path,
+    codec: &Codec,
+    newlines: &mut crate::lines::NewlineCounter,
+) -> Result<(usize, usize, usize), Error> {
+    let input = std::fs::File::open(path)?;
+    let mut decoder: Box<dyn Read> = match codec {
+        Codec::None => Box::new(input),
+        Codec::Gzip(_) => Box::new(flate2::read::MultiGzDecoder::new(input)),
+        #[cfg(feature = "zstd")]
+        Codec::Zstd(_) => Box::new(zstd::Decoder::new(input)?),
+        Codec::Custom(_) => return Err(undecodable()),
+    };
+    let (mut lines, mut bytes, mut chars, mut pending) = (0, 0, 0, 0);
+    let mut buf = [0u8; 8192];
+    while let Ok(n) = decoder.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        lines += newlines.count(&buf[..n]);
+        bytes += n;
+        chars += crate::countchars(&buf[..n], &mut pending);
+    }
+    Ok((lines, bytes, chars))
+}
+
+/// Encodes with `codec`, split across threads if the config asks for it.
+/// Appends a checksum footer afterwards with
+/// [`RotatingFileBuilder::checksum_footer`].
+///
+/// [`RotatingFileBuilder::checksum_footer`]: crate::RotatingFileBuilder::checksum_footer
+fn encode_with<R: Read, W: Write>(
+    config: &RotationConfig,
+    codec: &Codec,
+    input: &mut R,
+    skip: u64,
+    mut output: W,
+) -> Result<(), Error> {
+    let mut input = ChecksumReader::new(input);
+    // What a resumed archive already holds is only read, for the checksum
+    std::io::copy(&mut (&mut input).take(skip), &mut std::io::sink())?;
+    match codec {
+        Codec::Gzip(level) if config.compression_threads > 1 => encode_parallel(
+            &mut input,
+            &mut output,
+            Compression::new((*level).min(9)),
+            config.compression_threads,
+            config.compression_memory_budget,
+            &config.compression_memory,
+        )?,
+        codec if config.resumable_compression => encode_members(codec, &mut input, &mut output)?,
+        codec => codec.encode(&mut input, &mut output)?,
+    }
+    if config.checksum_footer {
+        if let Some(footer) = footer(codec, input.crc.sum(), input.len) {
+            output.write_all(&footer)?;
+        }
+    }
+    Ok(())
+}
+
+/// How much content each member of a resumable archive holds.
+const RESUMABLE_MEMBER: usize = 1024 * 1024;
+
+/// Encodes `input` as one member or frame per [`RESUMABLE_MEMBER`] bytes,
+/// so that a crash tears at most the one being written.
+fn encode_members<R: Read, W: Write>(
+    codec: &Codec,
+    input: &mut R,
+    output: &mut W,
+) -> Result<(), Error> {
+    let mut plain = Vec::with_capacity(RESUMABLE_MEMBER);
+    loop {
+        plain.clear();
+        (&mut *input)
+            .take(RESUMABLE_MEMBER as u64)
+            .read_to_end(&mut plain)?;
+        if plain.is_empty() {
+            return Ok(());
+        }
+        codec.encode(&mut plain.as_slice(), &mut *output)?;
+    }
+}
+
+/// Finds the prefix of the archive at `path` made of whole members or
+/// frames of `codec`, returning its length and how much it decodes to.
+/// Stops at the first torn or empty one, the latter being a checksum
+/// footer. Blocking.
+pub(crate) fn valid_prefix(path: &Path, codec: &Codec) -> Result<(u64, u64), Error> {
+    let file = std::fs::File::open(path)?;
+    match codec {
+        Codec::None => {
+            let len = file.metadata()?.len();
+            return Ok((len, len));
+        }
+        // Nothing can be told apart, so it all goes
+        Codec::Custom(_) => return Ok((0, 0)),
+        _ => {}
+    }
+    let mut input = Consumed {
+        inner: std::io::BufReader::new(file),
+        consumed: 0,
+    };
+    let (mut end, mut decoded) = (0, 0);
+    while !input.fill_buf()?.is_empty() {
+        let member = match codec {
+            Codec::None | Codec::Custom(_) => unreachable!(),
+            Codec::Gzip(_) => std::io::copy(
+                &mut flate2::bufread::GzDecoder::new(&mut input),
+                &mut std::io::sink(),
+            ),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd(_) => zstd::Decoder::with_buffer(&mut input).and_then(|decoder| {
+                std::io::copy(&mut decoder.single_frame(), &mut std::io::sink())
+            }),
+        };
+        match member {
+            Ok(n) if n > 0 => {
+                decoded += n;
+                end = input.consumed;
+            }
+            _ => break,
+        }
+    }
+    Ok((end, decoded))
+}
+
+/// Counts what's read or consumed through it.
+struct Consumed<R> {
+    inner: R,
+    consumed: u64,
+}
+
+impl<R: BufRead> Read for Consumed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        self.consumed += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Consumed<R> {
+    fn fill_buf(&mut self) -> Result<&[u8], Error> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.inner.consume(n);
+        self.consumed += n as u64;
+    }
+}
+
+/// The plain archive that the partial output `temp` was compressed from.
+pub(crate) fn partial_source(temp: &Path) -> Option<PathBuf> {
+    let name = temp.file_name()?.to_str()?.strip_suffix(".tmp")?;
+    Some(temp.with_file_name(archive::strip_codec(name)?))
+}
+
+/// Sums up what's read through it for a checksum footer.
+struct ChecksumReader<R> {
+    inner: R,
+    crc: flate2::Crc,
+    len: u64,
+}
+
+impl<R> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            crc: flate2::Crc::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// The ID of the gzip extra subfield holding a checksum footer.
+const FOOTER_ID: [u8; 2] = *b"RF";
+
+/// The magic number of the zstd skippable frame holding a checksum footer.
+const FOOTER_MAGIC: u32 = 0x184D_2A5E;
+
+const GZIP_FOOTER_LEN: usize = 38;
+const ZSTD_FOOTER_LEN: usize = 20;
+
+/// The checksum footer for content of `len` bytes with the CRC-32 `crc`:
+/// an empty gzip member whose extra field holds them, or a zstd skippable
+/// frame. Decoders treat either as no data at all. Plain archives get none.
+fn footer(codec: &Codec, crc: u32, len: u64) -> Option<Vec<u8>> {
+    let mut payload = crc.to_le_bytes().to_vec();
+    payload.extend_from_slice(&len.to_le_bytes());
+    let mut footer = Vec::new();
+    match codec {
+        Codec::None | Codec::Custom(_) => return None,
+        Codec::Gzip(_) => {
+            // Magic, deflate, FEXTRA, no mtime, no flags, unknown OS
+            footer.extend_from_slice(&[0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 255]);
+            footer.extend_from_slice(&(4 + payload.len() as u16).to_le_bytes());
+            footer.extend_from_slice(&FOOTER_ID);
+            footer.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+            footer.extend_from_slice(&payload);
+            // An empty final block, then the CRC and size of no data
+            footer.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        }
+        #[cfg(feature = "zstd")]
+        Codec::Zstd(_) => {
+            footer.extend_from_slice(&FOOTER_MAGIC.to_le_bytes());
+            footer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            footer.extend_from_slice(&payload);
+        }
+    }
+    Some(footer)
+}
+
+/// Parses the CRC-32 and length out of the checksum footer at the end of
+/// `data`, if it has one.
+fn parse_footer(data: &[u8]) -> Option<(u32, u64)> {
+    let gzip = data
+        .len()
+        .checked_sub(GZIP_FOOTER_LEN)
+        .map(|start| &data[start..])
+        .filter(|footer| footer[..4] == [0x1f, 0x8b, 8, 4] && footer[12..14] == FOOTER_ID)
+        .map(|footer| &footer[16..28]);
+    let zstd = data
+        .len()
+        .checked_sub(ZSTD_FOOTER_LEN)
+        .map(|start| &data[start..])
+        .filter(|footer| footer[..4] == FOOTER_MAGIC.to_le_bytes() && footer[4..8] == [12, 0, 0, 0])
+        .map(|footer| &footer[8..]);
+    let payload = gzip.or(zstd)?;
+    Some((
+        u32::from_le_bytes(std::convert::TryFrom::try_from(&payload[..4]).unwrap()),
+        u64::from_le_bytes(std::convert::TryFrom::try_from(&payload[4..]).unwrap()),
+    ))
+}
+
+/// Checks an archive written with [`RotatingFileBuilder::checksum_footer`]
+/// against its footer, without any sidecar file. Returns whether the
+/// decompressed content matches, or an `InvalidData` error if there is no
+/// footer.
+///
+/// [`RotatingFileBuilder::checksum_footer`]: crate::RotatingFileBuilder::checksum_footer
+pub async fn verify_checksum_footer<P: AsRef<Path>>(archive: P) -> Result<bool, Error> {
+    let archive = archive.as_ref().to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let data = std::fs::read(&archive)?;
+        let (crc, len) = parse_footer(&data).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} has no checksum footer", archive.display()),
+            )
+        })?;
+        let mut decoded = ChecksumReader::new(decoder(&archive)?);
+        std::io::copy(&mut decoded, &mut std::io::sink())?;
+        Ok(decoded.crc.sum() == crc && decoded.len == len)
+    })
+    .await
+    .map_err(Error::other)?
+}
+
+/// Input bytes per block of parallel compression, unless the memory budget
+/// needs smaller ones.
+const PARALLEL_BLOCK: usize = 1024 * 1024;
+
+/// The smallest memory budget parallel compression accepts.
+pub(crate) const MIN_MEMORY_BUDGET: usize = 64 * 1024;
+
+/// The most a gzip member of `n` input bytes can take, stored blocks and
+/// all.
+fn gzip_bound(n: usize) -> usize {
+    n + n / 16384 * 5 + 64
+}
+
+/// The memory held by blocks in flight during parallel compression.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryGauge {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl MemoryGauge {
+    fn add(&self, n: usize) {
+        let now = self.current.fetch_add(n, Ordering::SeqCst) + n;
+        self.peak.fetch_max(now, Ordering::SeqCst);
+    }
+
+    fn sub(&self, n: usize) {
+        self.current.fetch_sub(n, Ordering::SeqCst);
+    }
+
+    pub(crate) fn peak(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+}
+
+/// Gzips `input` in blocks on up to `threads` threads, as one gzip member
+/// per block, which gzip readers decode as a single stream. With a
+/// `budget`, blocks shrink and fewer are in flight so that their buffers
+/// never hold more than it.
+fn encode_parallel<R: Read, W: Write>(
+    input: &mut R,
+    mut output: W,
+    level: Compression,
+    threads: usize,
+    budget: Option<usize>,
+    gauge: &MemoryGauge,
+) -> Result<(), Error> {
+    let (block, in_flight) = match budget {
+        Some(budget) => {
+            // Solves block + gzip_bound(block) <= budget, roughly
+            let block =
+                (budget.saturating_sub(64) * 16384 / (2 * 16384 + 5)).clamp(1, PARALLEL_BLOCK);
+            let each = block + gzip_bound(block);
+            (block, (budget / each).clamp(1, threads))
+        }
+        None => (PARALLEL_BLOCK, threads),
+    };
+    thread::scope(|scope| {
+        let mut pending = VecDeque::new();
+        let written = (|| loop {
+            while pending.len() >= in_flight {
+                write_block(pending.pop_front().unwrap(), Some(&mut output), gauge)?;
+            }
+            let mut plain = Vec::with_capacity(block);
+            gauge.add(plain.capacity());
+            let read = (&mut *input).take(block as u64).read_to_end(&mut plain);
+            if read.is_err() || plain.is_empty() {
+                gauge.sub(plain.capacity());
+                return read.map(drop);
+            }
+            pending.push_back(scope.spawn(move || {
+                let reserved = gzip_bound(plain.len());
+                let mut gz = GzEncoder::new(Vec::with_capacity(reserved), level);
+                gauge.add(reserved);
+                let compressed = gz.write_all(&plain).and_then(|()| gz.finish());
+                gauge.sub(plain.capacity());
+                match &compressed {
+                    // Accounts for growth past the bound, should it ever happen
+                    Ok(compressed) => gauge.add(compressed.capacity().saturating_sub(reserved)),
+                    Err(_) => gauge.sub(reserved),
+                }
+                compressed
+            }));
+        })();
+        // After a failure, the rest is only waited for
+        let mut result = written;
+        for compressed in pending {
+            let output = result.is_ok().then_some(&mut output);
+            if let Err(e) = write_block(compressed, output, gauge) {
+                result = result.and(Err(e));
+            }
+        }
+        result.and_then(|()| output.flush())
+    })
+}
+
+/// Waits for a block of [`encode_parallel`] and writes it to `output`, if
+/// there is one.
+fn write_block<W: Write>(
+    block: thread::ScopedJoinHandle<'_, Result<Vec<u8>, Error>>,
+    output: Option<&mut W>,
+    gauge: &MemoryGauge,
+) -> Result<(), Error> {
+    let compressed = block
+        .join()
+        .map_err(|_| Error::other("compression thread panicked"))??;
+    let written = output.map_or(Ok(()), |output| output.write_all(&compressed));
+    gauge.sub(compressed.capacity());
+    written
+}
+
+/// Reads the archive at `path` whole, decompressing it according to its
+/// extension. Blocking.
+pub(crate) fn decode_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut decoded = Vec::new();
+    decoder(path)?.read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// Opens the archive at `path` for reading, decompressing it according to
+/// its extension. Blocking.
+fn decoder(path: &Path) -> Result<Box<dyn Read + Send>, Error> {
+    let input = std::fs::File::open(path)?;
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    Ok(match extension.map(str::to_ascii_lowercase).as_deref() {
+        Some("gz") => Box::new(flate2::read::MultiGzDecoder::new(input)),
+        #[cfg(feature = "zstd")]
+        Some("zst") => Box::new(zstd::Decoder::new(input)?),
+        #[cfg(not(feature = "zstd"))]
+        Some("zst") => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} needs the zstd feature", path.display()),
+            ))
+        }
+        _ => Box::new(std::io::BufReader::new(input)),
+    })
+}
+
+/// Decodes `sources` in order and encodes them as one stream with `codec`
+/// into `output`. Blocking.
+pub(crate) fn recompress(sources: &[PathBuf], codec: &Codec, output: &Path) -> Result<(), Error> {
+    let mut input: Box<dyn Read + Send> = Box::new(std::io::empty());
+    for source in sources {
+        input = Box::new(input.chain(decoder(source)?));
+    }
+    let mut file = std::fs::File::create(output).map_err(|e| crate::diagnose_create(e, output))?;
+    encode_or_copy(codec, &mut input, &mut file)?;
+    file.sync_all()
+}
+
+/// Encodes `input` with `codec`, or copies it as is with [`Codec::None`].
+fn encode_or_copy<R: Read, W: Write>(
+    codec: &Codec,
+    input: &mut R,
+    mut output: W,
+) -> Result<(), Error> {
+    match codec {
+        Codec::None => std::io::copy(input, &mut output).map(drop),
+        codec => codec.encode(input, output),
+    }
+}
+
+/// Compresses the file at `src` into `dst` with `codec`, whose level it
+/// carries, the way rotation compresses archives but without any
+/// [`RotatingFile`](crate::RotatingFile). `src` is read as is, whatever its
+/// extension, and left in place. `dst` appears only once it's complete and
+/// synced.
+pub async fn compress_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dst: Q,
+    codec: Codec,
+) -> Result<(), Error> {
+    let (src, dst) = (src.as_ref().to_path_buf(), dst.as_ref().to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let mut input = std::io::BufReader::new(std::fs::File::open(&src)?);
+        let temp = temp_path(&dst);
+        let written = std::fs::File::create(&temp)
+            .map_err(|e| crate::diagnose_create(e, &temp))
+            .and_then(|mut output| {
+                encode_or_copy(&codec, &mut input, &mut output)?;
+                output.sync_all()
+            });
+        if let Err(e) = written {
+            let _ = std::fs::remove_file(&temp);
+            return Err(e);
+        }
+        std::fs::rename(&temp, &dst)
+    })
+    .await
+    .map_err(Error::other)?
+}
+
+/// Encodes `input` with `codec` into `tempfile`, which is removed again if
+/// it fails verification, and stamps it with the configured mtime and
+/// permissions.
+fn encode_to<R: Read>(
+    config: &RotationConfig,
+    codec: &Codec,
+    input: &mut R,
+    tempfile: &Path,
+    source: &std::fs::Metadata,
+) -> Result<(), Error> {
+    let source_len = source.len();
+    let (output, skip) = match config.resumable_compression {
+        true => resume(tempfile, codec, source_len)?,
+        false => (create_temp(tempfile)?, 0),
+    };
+    match &config.compression_progress {
+        Some(progress) => {
+            let mut input = ProgressReader::new(input, &**progress, source_len);
+            encode_with(config, codec, &mut input, skip, output)?;
+            input.finish();
+        }
+        None => encode_with(config, codec, input, skip, output)?,
+    }
+    if config.verify_size {
+        let decoded = codec.decoded_len(tempfile);
+        if decoded.as_ref().ok() != Some(&source_len) {
+            let _ = std::fs::remove_file(tempfile);
+            let found = match decoded {
+                Ok(n) => n.to_string(),
+                Err(e) => format!("an error ({})", e),
+            };
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "archive of {} decompressed to {} instead of {} bytes",
+                    tempfile.display(),
+                    found,
+                    source_len
+                ),
+            ));
+        }
+    }
+    let mtime = match config.archive_mtime {
+        ArchiveMtime::Now => None,
+        ArchiveMtime::Source => Some(source.modified()?),
+        ArchiveMtime::Fixed(time) => Some(time),
+    };
+    if let Some(mtime) = mtime {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(tempfile)?
+            .set_modified(mtime)?;
+    }
+    let permissions = match &config.archive_permissions {
+        ArchivePermissions::Default => None,
+        ArchivePermissions::Source => Some(source.permissions()),
+        ArchivePermissions::Fixed(permissions) => Some(permissions.clone()),
+    };
+    if let Some(permissions) = permissions {
+        std::fs::set_permissions(tempfile, permissions)?;
+    }
+    Ok(())
+}
+
+fn create_temp(tempfile: &Path) -> Result<std::fs::File, Error> {
+    std::fs::File::create(tempfile).map_err(|e| crate::diagnose_create(e, tempfile))
+}
+
+/// Opens `tempfile` to carry on after its whole members, if it's left from
+/// an interrupted compression of the `source_len` bytes, or creates it.
+/// Returns the file and how much of the source it already holds.
+fn resume(tempfile: &Path, codec: &Codec, source_len: u64) -> Result<(std::fs::File, u64), Error> {
+    if let Ok((end, decoded)) = valid_prefix(tempfile, codec) {
+        if decoded <= source_len {
+            let mut output = std::fs::OpenOptions::new().write(true).open(tempfile)?;
+            output.set_len(end)?;
+            output.seek(SeekFrom::End(0))?;
+            return Ok((output, decoded));
+        }
+    }
+    Ok((create_temp(tempfile)?, 0))
+}
+
+/// Compresses the archive `path` of the active file `base` on the
+/// configured executor, removing the original.
+pub(crate) async fn compress_on_executor(
+    config: &Arc<RotationConfig>,
+    base: &Path,
+    path: &Path,
+) -> Result<PathBuf, Error> {
+    compress_as_on_executor(config, base, path, path, true).await
+}
+
+/// Runs [`encode_archive`] on the configured executor and commits the
+/// result.
+pub(crate) async fn compress_as_on_executor(
+    config: &Arc<RotationConfig>,
+    base: &Path,
+    source: &Path,
+    archive: &Path,
+    remove_source: bool,
+) -> Result<PathBuf, Error> {
+    let encoded = encode_on_executor(config, base, source, archive, remove_source).await?;
+    let _lock = config.archive_lock.lock().await;
+    encoded.commit(config).await?.ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("{} was removed while compressing", source.display()),
+        )
+    })
+}
+
+/// Runs [`encode_archive`] on the configured executor.
+pub(crate) async fn encode_on_executor(
+    config: &Arc<RotationConfig>,
+    base: &Path,
+    source: &Path,
+    archive: &Path,
+    remove_source: bool,
+) -> Result<Encoded, Error> {
+    let job_config = config.clone();
+    let (base, source, archive) = (
+        base.to_path_buf(),
+        source.to_path_buf(),
+        archive.to_path_buf(),
+    );
+    run(config.executor.as_ref(), move || {
+        encode_archive(&base, &source, &archive, remove_source, &job_config)
+    })
+    .await
+}
+
+/// A compressed archive written by [`encode_archive`] under temporary
+/// names, waiting for [`Encoded::commit`].
+pub(crate) struct Encoded {
+    /// `(temporary, final)` names of everything to rename into place.
+    renames: Vec<(PathBuf, PathBuf)>,
+    archive: PathBuf,
+    source: PathBuf,
+    remove_source: bool,
+}
+
+impl Encoded {
+    /// Renames the output into place and removes the source, returning the
+    /// archive's path. Callers hold `archive_lock`, so retention sees the
+    /// source or the archive but never both or neither.
+    ///
+    /// If retention removed the source while it was compressed, the output
+    /// is discarded and `None` returned.
+    pub(crate) async fn commit(
+        self,
+        config: &Arc<RotationConfig>,
+    ) -> Result<Option<PathBuf>, Error> {
+        if !self.renames.is_empty() && !tokio::fs::try_exists(&self.source).await? {
+            for (temp, _) in &self.renames {
+                let _ = tokio::fs::remove_file(temp).await;
+            }
+            return Ok(None);
+        }
+        for (temp, path) in self.renames {
+            tokio::fs::rename(&temp, &path).await?;
+            if config.sync_archives {
+                let executor = config.executor.clone();
+                let config = config.clone();
+                run(&*executor, move || sync_archive(&config, &path)).await?;
+            }
+        }
+        if self.remove_source {
+            tokio::fs::remove_file(&self.source).await?;
+            config.pins.moved(&self.source, &self.archive);
+        }
+        Ok(Some(self.archive))
+    }
+}
+
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_owned();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(feature = "content-hash")]
+struct HashingReader<R> {
+    inner: R,
+    hasher: sha2::Sha256,
+}
+
+#[cfg(feature = "content-hash")]
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        use sha2::Digest;
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Records the offsets of every `every`th line while reading.
+struct IndexingReader<R> {
+    inner: R,
+    every: usize,
+    lines: usize,
+    offset: u64,
+    entries: Vec<(usize, u64)>,
+}
+
+impl<R> IndexingReader<R> {
+    fn new(inner: R, every: usize) -> Self {
+        Self {
+            inner,
+            every,
+            lines: 0,
+            offset: 0,
+            entries: vec![(0, 0)],
+        }
+    }
+
+    /// Writes the entries in the format of [`RotatingFileBuilder::line_index`].
+    ///
+    /// [`RotatingFileBuilder::line_index`]: crate::RotatingFileBuilder::line_index
+    fn write_to(&self, path: &Path) -> Result<(), Error> {
+        let index: String = self
+            .entries
+            .iter()
+            .map(|(line, offset)| format!("{} {}\n", line, offset))
+            .collect();
+        std::fs::write(path, index)
+    }
+}
+
+impl<R: Read> Read for IndexingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        for (i, _) in buf[..n].iter().enumerate().filter(|(_, b)| **b == b'\n') {
+            self.lines += 1;
+            if self.lines % self.every == 0 {
+                self.entries.push((self.lines, self.offset + i as u64 + 1));
+            }
+        }
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// How often [`ProgressReader`] reports, in bytes read.
+const PROGRESS_INTERVAL: u64 = 256 * 1024;
+
+type Progress = dyn Fn(u64, Option<u64>) + Send + Sync;
+
+/// Reports the bytes read through it to a compression progress callback.
+struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a Progress,
+    total: u64,
+    read: u64,
+    reported: Option<u64>,
+}
+
+impl<'a, R> ProgressReader<'a, R> {
+    fn new(inner: R, progress: &'a Progress, total: u64) -> Self {
+        Self {
+            inner,
+            progress,
+            total,
+            read: 0,
+            reported: None,
+        }
+    }
+
+    fn report(&mut self) {
+        self.reported = Some(self.read);
+        // The file may have grown since its size was taken
+        (self.progress)(self.read, Some(self.total.max(self.read)));
+    }
+
+    /// Reports the final count, unless it already was.
+    fn finish(&mut self) {
+        if self.reported != Some(self.read) {
+            self.report();
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.read - self.reported.unwrap_or(0) >= PROGRESS_INTERVAL {
+            self.report();
+        }
+        Ok(n)
+    }
+}
+
+/// Access pattern hints passed by [`advise`].
+#[derive(Clone, Copy)]
+pub(crate) enum Advice {
+    Sequential,
+    DontNeed,
+}
+
+/// With `fadvise`, passes `advice` about all of `file` to the kernel on
+/// Linux. Failures are ignored, as it's only a hint.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub(crate) fn advise(config: &RotationConfig, file: &std::fs::File, advice: Advice) {
+    if !config.fadvise {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let advice = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+        // SAFETY: the descriptor is open for as long as `file` is borrowed
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+    }
+}
+
+/// With `sync_archives`, makes sure the archive at `path` and its directory
+/// entry are on disk before its source is deleted. Blocking.
+fn sync_archive(config: &RotationConfig, path: &Path) -> Result<(), Error> {
+    if !config.sync_archives {
+        return Ok(());
+    }
+    std::fs::File::open(path)?.sync_all()?;
+    // Directories can't be opened for syncing elsewhere
+    #[cfg(unix)]
+    std::fs::File::open(archive::directory(path))?.sync_all()?;
+    Ok(())
+}
+
+/// Compresses `source`, an archive or the active file `base`, into the
+/// compressed form of the plain archive name `archive`. Blocking.
+///
+/// The output is written to `.tmp` files next to the final names, which
+/// [`Encoded::commit`] renames into place. Content-addressed archives are
+/// committed right away.
+#[cfg_attr(not(feature = "content-hash"), allow(unused_variables))]
+pub(crate) fn encode_archive(
+    base: &Path,
+    source: &Path,
+    archive: &Path,
+    remove_source: bool,
+    config: &RotationConfig,
+) -> Result<Encoded, Error> {
+    let codec = &config.codec;
+    let mut encoded = Encoded {
+        renames: Vec::new(),
+        archive: archive.to_path_buf(),
+        source: source.to_path_buf(),
+        remove_source: false,
+    };
+    if *codec == Codec::None {
+        return Ok(encoded);
+    }
+    #[cfg(feature = "content-hash")]
+    if config.naming.content_addressed {
+        encoded.archive = compress_content_addressed(base, source, archive, remove_source, config)?;
+        return Ok(encoded);
+    }
+    let outputfile = config.naming.output_path(codec, archive);
+    let tempfile = temp_path(&outputfile);
+
+    let mut inputfile = std::fs::File::open(source)?;
+    let source_metadata = inputfile.metadata()?;
+    advise(config, &inputfile, Advice::Sequential);
+    match config.line_index {
+        Some(every) => {
+            let mut input = IndexingReader::new(&mut inputfile, every);
+            encode_to(config, codec, &mut input, &tempfile, &source_metadata)?;
+            let index = archive::index_path(&outputfile);
+            input.write_to(&temp_path(&index))?;
+            encoded.renames.push((temp_path(&index), index));
+        }
+        None => encode_to(config, codec, &mut inputfile, &tempfile, &source_metadata)?,
+    }
+    advise(config, &inputfile, Advice::DontNeed);
+    encoded.renames.push((tempfile, outputfile.clone()));
+    for extra in &config.extra_codecs {
+        let extra_output = config.naming.output_path(extra, archive);
+        let extra_temp = temp_path(&extra_output);
+        let mut inputfile = std::fs::File::open(source)?;
+        encode_to(config, extra, &mut inputfile, &extra_temp, &source_metadata)?;
+        encoded.renames.push((extra_temp, extra_output));
+    }
+    encoded.archive = outputfile;
+    encoded.remove_source = remove_source;
+    Ok(encoded)
+}
+
+#[cfg(feature = "content-hash")]
+fn compress_content_addressed(
+    base: &Path,
+    source: &Path,
+    archive: &Path,
+    remove_source: bool,
+    config: &RotationConfig,
+) -> Result<PathBuf, Error> {
+    use sha2::Digest;
+
+    let codec = &config.codec;
+    let tempfile = temp_path(&config.naming.output_path(codec, archive));
+    let inputfile = std::fs::File::open(source)?;
+    let source_metadata = inputfile.metadata()?;
+    let mut input = HashingReader {
+        inner: inputfile,
+        hasher: sha2::Sha256::new(),
+    };
+    encode_to(config, codec, &mut input, &tempfile, &source_metadata)?;
+    let hash: String = input
+        .hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    let outputfile =
+        config
+            .naming
+            .content_path(base, &hash, &config.naming.codec_extension(codec).unwrap());
+    if outputfile.exists() {
+        // Identical content was archived before
+        std::fs::remove_file(&tempfile)?;
+    } else {
+        std::fs::rename(&tempfile, &outputfile)?;
+    }
+    sync_archive(config, &outputfile)?;
+    if remove_source {
+        std::fs::remove_file(source)?;
+    }
+    Ok(outputfile)
+}
+
+/// Compresses the plain archives of `file_name` (e.g. `app.log`) found in
+/// `dir` with `codec`, returning how many were compressed.
+///
+/// Meant for migrating directories of archives written before this crate
+/// was adopted, so no writer needs to be open. Names with and without a
+/// sequence number are both recognized.
+pub async fn compress_existing<P: AsRef<Path>>(
+    dir: P,
+    file_name: &str,
+    codec: Codec,
+) -> Result<usize, Error> {
+    let base = archive::standalone_base(dir.as_ref(), file_name)?;
+    if codec == Codec::None {
+        return Ok(0);
+    }
+    let config = Arc::new(RotationConfig {
+        codec,
+        ..RotationConfig::default()
+    });
+    let plain: Vec<_> = archive::list_standalone(&base, std::slice::from_ref(&config.codec))
+        .await?
+        .into_iter()
+        .filter(|found| !found.compressed)
+        .map(|found| found.path)
+        .collect();
+    for path in &plain {
+        compress_on_executor(&config, &base, path).await?;
+    }
+    Ok(plain.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{archive, tests::fresh_dir, RotatingFile, RotationMode};
+    use tokio::io::AsyncWriteExt;
+
+    async fn rotate_once(name: &str, codec: Codec, data: &[u8]) -> PathBuf {
+        let dir = fresh_dir(name).await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1))
+            .codec(codec)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(data).await.unwrap();
+        file.write_all(b"\n").await.unwrap();
+        file.flush().await.unwrap();
+        file.latest_archive().await.unwrap().unwrap()
+    }
+
+    fn sample() -> Vec<u8> {
+        b"0123456789abcdef".repeat(1024)
+    }
+
+    #[tokio::test]
+    async fn gzip_store() {
+        let data = sample();
+        let archive = rotate_once("gzip_store", Codec::Gzip(0), &data).await;
+        assert_eq!(archive.extension().unwrap(), "gz");
+
+        let compressed = std::fs::read(&archive).unwrap();
+        assert!(compressed.len() >= data.len());
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[tokio::test]
+    async fn no_codec() {
+        let data = sample();
+        let archive = rotate_once("no_codec", Codec::None, &data).await;
+        assert_eq!(archive.extension().unwrap(), "log");
+        assert_eq!(std::fs::read(&archive).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn verify_size_mismatch() {
+        /// Gzip that loses all but the first 20 bytes of its output.
+        struct Truncating;
+
+        impl Compressor for Truncating {
+            fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), Error> {
+                let mut gz = GzEncoder::new(Vec::new(), Compression::fast());
+                std::io::copy(input, &mut gz)?;
+                output.write_all(&gz.finish()?[..20])
+            }
+
+            fn extension(&self) -> &str {
+                "gz"
+            }
+
+            fn decode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), Error> {
+                std::io::copy(&mut flate2::read::GzDecoder::new(input), output).map(drop)
+            }
+        }
+
+        let dir = fresh_dir("verify_size_mismatch").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(1))
+            .codec(Codec::Custom(Arc::new(Truncating)))
+            .verify_size(true)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(&sample()).await.unwrap();
+        let err = file.write_all(b"next").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert!(!archives[0].compressed);
+        assert_eq!(std::fs::read(&archives[0].path).unwrap(), sample());
+        assert!(archive::stale_temps(&path, &file.config.naming)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn compression_progress() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = fresh_dir("compression_progress").await;
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let heard = calls.clone();
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1))
+            .compression_progress(move |read, total| heard.lock().unwrap().push((read, total)))
+            .build()
+            .await
+            .unwrap();
+        let data = sample().repeat(64);
+        file.write_all(&data).await.unwrap();
+        file.write_all(b"next").await.unwrap();
+        file.await_compressions().await.unwrap();
+
+        let calls = calls.lock().unwrap().clone();
+        let total = data.len() as u64;
+        assert!(calls.len() >= 4, "{:?}", calls);
+        assert!(calls.windows(2).all(|w| w[0].0 < w[1].0), "{:?}", calls);
+        assert!(calls.iter().all(|&(_, t)| t == Some(total)));
+        assert_eq!(calls.last().unwrap().0, total);
+    }
+
+    #[tokio::test]
+    async fn verify_size_match() {
+        let dir = fresh_dir("verify_size_match").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1))
+            .verify_size(true)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(&sample()).await.unwrap();
+        file.write_all(b"next").await.unwrap();
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert!(archives[0].compressed);
+    }
+
+    #[cfg(feature = "content-hash")]
+    #[tokio::test]
+    async fn content_addressed() {
+        let dir = fresh_dir("content_addressed").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Lines(1))
+            .content_addressed()
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"same\n").await.unwrap();
+        file.write_all(b"same\n").await.unwrap();
+        file.write_all(b"other\n").await.unwrap();
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        let name = archives[0].path.file_name().unwrap().to_str().unwrap();
+        assert_eq!(name.len(), "app-.log.gz".len() + 16, "{}", name);
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(std::fs::File::open(&archives[0].path).unwrap())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, b"same\n");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn zstd_fastest() {
+        let data = sample();
+        let archive = rotate_once("zstd_fastest", Codec::Zstd(0), &data).await;
+        assert_eq!(archive.extension().unwrap(), "zst");
+        let decoded = zstd::decode_all(std::fs::File::open(&archive).unwrap()).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn extra_codecs() {
+        let dir = fresh_dir("extra_codecs").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(4))
+            .sequence(1)
+            .extra_codecs(vec![Codec::Zstd(0)])
+            .staging_dir(dir.join("staging"))
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"abcd").await.unwrap();
+        file.write_all(b"e").await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        let extensions: Vec<_> = archives
+            .iter()
+            .map(|a| a.path.extension().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(extensions, ["gz", "zst"]);
+        for archive in &archives {
+            assert_eq!(decode_file(&archive.path).unwrap(), b"abcd");
+        }
+        assert_eq!(std::fs::read_dir(dir.join("staging")).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn line_index() {
+        let dir = fresh_dir("line_index").await;
+        let content: String = (0..10).map(|i| format!("line {}\n", i * 100)).collect();
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Lines(10))
+            .line_index(3)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(content.as_bytes()).await.unwrap();
+        file.write_all(b"next\n").await.unwrap();
+
+        let archive = &file.archives().await.unwrap()[0].path;
+        let decoded = decode_file(archive).unwrap();
+        let index = std::fs::read_to_string(archive::index_path(archive)).unwrap();
+        let entries: Vec<(usize, usize)> = index
+            .lines()
+            .map(|entry| {
+                let (line, offset) = entry.split_once(' ').unwrap();
+                (line.parse().unwrap(), offset.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(entries.len(), 4);
+        for (line, offset) in entries {
+            let expected = format!("line {}\n", line * 100);
+            assert!(decoded[offset..].starts_with(expected.as_bytes()));
+        }
+    }
+
+    #[tokio::test]
+    async fn compress_existing_archives() {
+        let dir = fresh_dir("compress_existing_archives").await;
+        let names = [
+            "app-2024-01-01_00-00-00.log",
+            "app-2024-01-02_00-00-00.log",
+            "app-000007-2024-01-03_00-00-00.log",
+        ];
+        for name in names {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+        std::fs::write(dir.join("app-2024-01-04_00-00-00.log.gz"), b"").unwrap();
+        std::fs::write(dir.join("app.log"), b"active").unwrap();
+        std::fs::write(dir.join("other-2024-01-01_00-00-00.log"), b"").unwrap();
+
+        let count = compress_existing(&dir, "app.log", Codec::default())
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+        for name in names {
+            assert!(!dir.join(name).exists());
+            let gz = dir.join(format!("{}.gz", name));
+            assert_eq!(crate::tests::gunzip(&gz), name.as_bytes());
+        }
+        assert!(dir.join("app.log").exists());
+        assert!(dir.join("other-2024-01-01_00-00-00.log").exists());
+    }
+
+    /// How many pages of the file at `path` are in the page cache.
+    #[cfg(target_os = "linux")]
+    fn cached_pages(path: &Path) -> usize {
+        use std::os::unix::io::AsRawFd;
+
+        let file = std::fs::File::open(path).unwrap();
+        let len = file.metadata().unwrap().len() as usize;
+        // SAFETY: the mapping is only handed to mincore, with room for a
+        // byte per page, and unmapped before `file` closes
+        unsafe {
+            let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+            let mut residency = vec![0u8; len.div_ceil(page)];
+            let map = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            );
+            assert_ne!(map, libc::MAP_FAILED);
+            assert_eq!(libc::mincore(map, len, residency.as_mut_ptr()), 0);
+            libc::munmap(map, len);
+            residency.iter().filter(|page| **page & 1 != 0).count()
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn fadvise() {
+        use crate::{filesystem_type, Filesystem};
+
+        async fn archive(name: &str, fadvise: bool) -> PathBuf {
+            let dir = fresh_dir(name).await;
+            // Stored, so the archive spans many pages; synced, so they're
+            // clean and can be dropped
+            let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1))
+                .codec(Codec::Gzip(0))
+                .sync_archives(true)
+                .fadvise(fadvise)
+                .build()
+                .await
+                .unwrap();
+            file.write_all(&vec![b'x'; 1 << 20]).await.unwrap();
+            file.write_all(b"1").await.unwrap();
+            file.archives().await.unwrap().remove(0).path
+        }
+
+        let cached = archive("fadvise_off", false).await;
+        let page_cache = matches!(
+            filesystem_type(&cached),
+            Some(Filesystem::Ext | Filesystem::Xfs | Filesystem::Btrfs)
+        );
+        if !page_cache || cached_pages(&cached) == 0 {
+            // Nothing here shows what fadvise drops
+            return;
+        }
+        assert_eq!(cached_pages(&archive("fadvise", true).await), 0);
+    }
+
+    #[tokio::test]
+    async fn sync_archives() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// Counts the jobs it hands on to Tokio's blocking pool.
+        struct Counting(AtomicUsize);
+
+        impl CompressionExecutor for Counting {
+            fn execute(&self, job: Job) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                TokioBlocking.execute(job);
+            }
+        }
+
+        for &(sync, jobs) in [(false, 2), (true, 4)].iter() {
+            let dir = fresh_dir("sync_archives").await;
+            let executor = Arc::new(Counting(AtomicUsize::new(0)));
+            let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1))
+                .sequence(1)
+                .compression_executor(executor.clone())
+                .sync_archives(sync)
+                .build()
+                .await
+                .unwrap();
+            for _ in 0..3 {
+                file.write_all(b"1").await.unwrap();
+            }
+            // A compression for each archive, then a sync for each
+            assert_eq!(executor.0.load(Ordering::SeqCst), jobs);
+            assert_eq!(file.archives().await.unwrap().len(), 2);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn immutable_archives() {
+        use crate::{filesystem::set_immutable, RetentionPolicy, RetentionRule};
+
+        let dir = fresh_dir("immutable_archives").await;
+        let probe = dir.join("probe");
+        std::fs::write(&probe, b"").unwrap();
+        if set_immutable(&probe, true).is_err() {
+            // Needs CAP_LINUX_IMMUTABLE and a filesystem that takes the flag
+            return;
+        }
+        set_immutable(&probe, false).unwrap();
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1))
+            .sequence(1)
+            .immutable_archives(true)
+            .retention(RetentionPolicy::any(vec![RetentionRule::KeepCount(1)]))
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            file.write_all(b"1").await.unwrap();
+        }
+        // Retention got past the flag of the first archive
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        let archive = &archives[0].path;
+        assert!(std::fs::remove_file(archive).is_err());
+        assert!(set_immutable(archive, false).unwrap());
+    }
+
+    #[tokio::test]
+    async fn archive_mtime() {
+        let dir = fresh_dir("archive_mtime").await;
+        let stamp =
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let mut readonly = std::fs::metadata(&dir).unwrap().permissions();
+        readonly.set_readonly(true);
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1))
+            .archive_mtime(ArchiveMtime::Fixed(stamp))
+            .archive_permissions(ArchivePermissions::Fixed(readonly))
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"1").await.unwrap();
+        file.write_all(b"2").await.unwrap();
+
+        let archive = file.latest_archive().await.unwrap().unwrap();
+        let metadata = std::fs::metadata(&archive).unwrap();
+        assert_eq!(metadata.modified().unwrap(), stamp);
+        assert!(metadata.permissions().readonly());
+    }
+
+    #[tokio::test]
+    async fn parallel_memory_budget() {
+        let dir = fresh_dir("parallel_memory_budget").await;
+        let data: Vec<u8> = (0..4u32 << 20)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 27) as u8 + b'a')
+            .collect();
+        let budget = 256 * 1024;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(data.len()))
+            .parallel_compression(4)
+            .compression_memory_budget(budget)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(&data).await.unwrap();
+        file.write_all(b"\n").await.unwrap();
+        file.flush().await.unwrap();
+
+        let archive = file.latest_archive().await.unwrap().unwrap();
+        assert!(decode_file(&archive).unwrap() == data);
+        let peak = file.compression_peak_memory();
+        assert!(peak > 0 && peak <= budget, "{}", peak);
+    }
+
+    #[tokio::test]
+    async fn checksum_footer() {
+        let dir = fresh_dir("checksum_footer").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(6))
+            .checksum_footer(true)
+            .verify_size(true)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"hello\n").await.unwrap();
+        file.write_all(b"next\n").await.unwrap();
+
+        let archive = file.latest_archive().await.unwrap().unwrap();
+        assert_eq!(decode_file(&archive).unwrap(), b"hello\n");
+        assert!(verify_checksum_footer(&archive).await.unwrap());
+        let mut data = std::fs::read(&archive).unwrap();
+        let crc = data.len() - GZIP_FOOTER_LEN + 16;
+        data[crc] ^= 1;
+        std::fs::write(&archive, data).unwrap();
+        assert!(!verify_checksum_footer(&archive).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn compress_any_file() {
+        let dir = fresh_dir("compress_any_file").await;
+        let src = dir.join("data.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        let dst = dir.join("data.bin.gz");
+        compress_file(&src, &dst, Codec::Gzip(9)).await.unwrap();
+        assert_eq!(decode_file(&dst).unwrap(), data);
+        assert!(std::fs::metadata(&dst).unwrap().len() < data.len() as u64);
+        assert!(src.exists());
+        assert!(!temp_path(&dst).exists());
+    }
+
+    #[tokio::test]
+    async fn resume_after_crash() {
+        let dir = fresh_dir("resume_after_crash").await;
+        let plain = dir.join("app-001-2024-01-01_00-00-00.log");
+        let data: Vec<u8> = (0..3_500_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&plain, &data).unwrap();
+
+        // Two whole members, compressed differently than a rerun would,
+        // then a third torn by the crash
+        let member = |content: &[u8]| {
+            let mut gz = GzEncoder::new(Vec::new(), Compression::fast());
+            gz.write_all(content).unwrap();
+            gz.finish().unwrap()
+        };
+        let mut prefix = member(&data[..RESUMABLE_MEMBER]);
+        prefix.extend(member(&data[RESUMABLE_MEMBER..2 * RESUMABLE_MEMBER]));
+        let torn = member(&data[2 * RESUMABLE_MEMBER..3 * RESUMABLE_MEMBER]);
+        let temp = dir.join("app-001-2024-01-01_00-00-00.log.gz.tmp");
+        std::fs::write(&temp, [&prefix[..], &torn[..torn.len() / 2]].concat()).unwrap();
+
+        let file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1024))
+            .sequence(2)
+            .resumable_compression(true)
+            .verify_size(true)
+            .build()
+            .await
+            .unwrap();
+        let report = file.repair().await.unwrap();
+
+        let archive = dir.join("app-001-2024-01-01_00-00-00.log.gz");
+        assert_eq!(report.resumed, vec![temp.clone()]);
+        assert_eq!(report.compressed, vec![archive.clone()]);
+        let compressed = std::fs::read(&archive).unwrap();
+        assert!(compressed.starts_with(&prefix));
+        assert_eq!(decode_file(&archive).unwrap(), data);
+        assert!(!temp.exists() && !plain.exists());
+    }
+
+    #[tokio::test]
+    async fn custom_compressor() {
+        struct Xor;
+
+        impl Compressor for Xor {
+            fn encode(&self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), Error> {
+                let mut data = Vec::new();
+                input.read_to_end(&mut data)?;
+                data.iter_mut().for_each(|b| *b ^= 0x5a);
+                output.write_all(&data)
+            }
+
+            fn extension(&self) -> &str {
+                "xor"
+            }
+        }
+
+        let dir = fresh_dir("custom_compressor").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(1)
+            .codec(Codec::Custom(Arc::new(Xor)))
+            .retention(crate::RetentionPolicy::any(vec![
+                crate::RetentionRule::KeepCount(2),
+            ]))
+            .build()
+            .await
+            .unwrap();
+        for chunk in [b"11", b"22", b"33", b"44"].iter() {
+            file.write_all(*chunk).await.unwrap();
+        }
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 2);
+        for (archive, plain) in archives.iter().zip([b"22", b"33"].iter()) {
+            assert!(archive.compressed);
+            assert_eq!(archive.path.extension().unwrap(), "xor");
+            let expected: Vec<u8> = plain.iter().map(|b| b ^ 0x5a).collect();
+            assert_eq!(std::fs::read(&archive.path).unwrap(), expected);
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use crate::builder::RotationConfig;
+use std::{io::Error, path::Path};
+
+/// The kind of filesystem a directory lives on, as far as
+/// [`filesystem_type`] can tell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filesystem {
+    /// ext2, ext3 or ext4, which share a magic number.
+    Ext,
+    Xfs,
+    Btrfs,
+    Tmpfs,
+    Overlay,
+    Nfs,
+    /// CIFS or SMB2 network shares.
+    Cifs,
+    /// FAT12, FAT16 or FAT32.
+    Fat,
+    Exfat,
+    Fuse,
+    /// Anything else, by its `statfs` magic number.
+    Other(u64),
+}
+
+impl Filesystem {
+    fn from_magic(magic: u64) -> Self {
+        match magic {
+            0xEF53 => Filesystem::Ext,
+            0x5846_5342 => Filesystem::Xfs,
+            0x9123_683E => Filesystem::Btrfs,
+            0x0102_1994 => Filesystem::Tmpfs,
+            0x794C_7630 => Filesystem::Overlay,
+            0x6969 => Filesystem::Nfs,
+            0xFF53_4D42 | 0xFE53_4D42 => Filesystem::Cifs,
+            0x4D44 => Filesystem::Fat,
+            0x2011_BAB0 => Filesystem::Exfat,
+            0x6573_5546 => Filesystem::Fuse,
+            magic => Filesystem::Other(magic),
+        }
+    }
+
+    /// Whether files can be symlinked to here.
+    pub fn symlinks(&self) -> bool {
+        !matches!(self, Filesystem::Fat | Filesystem::Exfat | Filesystem::Cifs)
+    }
+
+    /// Whether syncing files and directories here makes them survive a crash.
+    pub fn durable(&self) -> bool {
+        *self != Filesystem::Tmpfs
+    }
+
+    /// Whether syncing a directory here makes the renames in it durable.
+    /// FUSE servers may acknowledge it without doing anything.
+    pub fn dir_sync(&self) -> bool {
+        !matches!(self, Filesystem::Tmpfs | Filesystem::Fuse)
+    }
+
+    /// Whether files here are backed by a page cache that can be dropped.
+    pub fn page_cache(&self) -> bool {
+        *self != Filesystem::Tmpfs
+    }
+
+    /// Whether files here take the immutable flag of `chattr +i`. tmpfs
+    /// only does since Linux 6.0, so it isn't counted on.
+    pub fn immutable(&self) -> bool {
+        matches!(self, Filesystem::Ext | Filesystem::Xfs | Filesystem::Btrfs)
+    }
+}
+
+/// Detects the filesystem holding `path` with `statfs`. Best effort: `None`
+/// when that fails, and always off Linux.
+pub fn filesystem_type(path: &Path) -> Option<Filesystem> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+        let mut stat = std::mem::MaybeUninit::<libc::statfs>::uninit();
+        // SAFETY: `path` is NUL-terminated and `stat` is only read once
+        // statfs reports having filled it.
+        let stat = unsafe {
+            if libc::statfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+                return None;
+            }
+            stat.assume_init()
+        };
+        // Magic numbers are 32 bits, whatever width f_type has here
+        Some(Filesystem::from_magic(u64::from(stat.f_type as u32)))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Whether this process may write to `path`, found with the metadata `m`.
+/// On Linux `access` is asked too, which also refuses on a read-only mount
+/// whatever the permission bits say.
+pub(crate) fn writable(path: &Path, m: &std::fs::Metadata) -> bool {
+    if m.permissions().readonly() {
+        return false;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(_) => return true,
+        };
+        // SAFETY: `path` is NUL-terminated
+        unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = path;
+        true
+    }
+}
+
+/// The `FS_IMMUTABLE_FL` inode flag.
+#[cfg(target_os = "linux")]
+const IMMUTABLE_FLAG: libc::c_int = 0x10;
+
+/// Sets or clears the immutable flag of `path`, returning whether it was
+/// set before. Does nothing off Linux.
+pub(crate) fn set_immutable(path: &Path, immutable: bool) -> Result<bool, Error> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let fd = std::fs::File::open(path)?;
+        let mut flags: libc::c_int = 0;
+        // SAFETY: both ioctls take a pointer to the int of inode flags, and
+        // `fd` is open for their duration
+        unsafe {
+            if libc::ioctl(fd.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) != 0 {
+                return Err(Error::last_os_error());
+            }
+            let was = flags & IMMUTABLE_FLAG != 0;
+            if was != immutable {
+                flags ^= IMMUTABLE_FLAG;
+                if libc::ioctl(fd.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) != 0 {
+                    return Err(Error::last_os_error());
+                }
+            }
+            Ok(was)
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (path, immutable);
+        Ok(false)
+    }
+}
+
+/// Describes the options of `config` that won't work as configured on the
+/// filesystems of `dirs`.
+pub(crate) fn warnings(config: &RotationConfig, dirs: &[&Path]) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for dir in dirs {
+        let fs = match filesystem_type(dir) {
+            Some(fs) => fs,
+            None => continue,
+        };
+        let mut warn = |what: &str| {
+            warnings.push(format!("{} is on {:?}, which {}", dir.display(), fs, what));
+        };
+        if config.latest_link && !fs.symlinks() {
+            warn("does not support the symlinks of latest_link");
+        }
+        if config.sync_archives && !fs.durable() {
+            warn("keeps nothing across a reboot, so sync_archives has no effect");
+        } else if config.sync_archives && !fs.dir_sync() {
+            warn("may ignore directory syncs, so sync_archives can't make renames durable");
+        }
+        if config.fadvise && !fs.page_cache() {
+            warn("has no page cache for fadvise to drop");
+        }
+        if config.immutable_archives && !fs.immutable() {
+            warn("may not support the immutable flag of immutable_archives");
+        }
+    }
+    warnings
+}
@@ -1,258 +1,3656 @@
+mod archive;
+mod budget;
+mod builder;
+mod compress;
+mod filesystem;
+mod lines;
+mod memory;
+mod multi;
+mod non_blocking;
+mod notify;
+mod rate;
+mod replay;
+mod retention;
+mod shared;
+
+pub use archive::{list_archives_in, ArchiveInfo, Collision};
+pub use budget::DiskBudget;
+pub use builder::{
+    CompressFilter, CompressionProgress, ConfigError, RotatingFileBuilder, RotationConfig,
+    RotationTrigger, RotationVeto, SwapHook, TimestampSource,
+};
+pub use compress::{
+    compress_existing, compress_file, verify_checksum_footer, ArchiveMtime, ArchivePermissions,
+    Codec, CompressionExecutor, CompressionPool, Compressor, Job, TokioBlocking,
+};
+pub use filesystem::{filesystem_type, Filesystem};
+pub use lines::{LineCounting, LineEnding};
+pub use memory::RotatingBuffer;
+pub use multi::{MultiRotatingFile, OnFailure};
+pub use non_blocking::{NonBlocking, Overflow};
+pub use notify::NotifyEndpoint;
+pub use replay::{Gap, Gaps, Replay, RotatingReader};
+pub use retention::{prune, Combine, PruneReport, RetentionPolicy, RetentionRule};
+pub use shared::SharedRotatingFile;
+
 use chrono::prelude::*;
-use flate2::{write::GzEncoder, Compression};
+use compress::ActiveStream;
 use futures::{
     future::Future,
+    ready,
     task::{Context, Poll},
     FutureExt,
 };
+use lines::{LineBuffer, NewlineCounter};
 use std::{
     cell::Cell,
-    io::{Error, Write},
+    io::{Error, ErrorKind},
     path::{Path, PathBuf},
     pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::{
     fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncWrite},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    task::JoinHandle,
 };
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub enum RotationMode {
     Lines(usize),
     Bytes(usize),
+    /// Rotates after this many characters, counted as UTF-8 scalar values,
+    /// for records of a fixed width in characters. A character split
+    /// between writes counts once its last byte is written, and the file
+    /// never rotates in the middle of one. Stray continuation bytes don't
+    /// count. Counts that start from [`RotatingFile::new_with_counts`] or a
+    /// checkpoint take each of their bytes as a character.
+    Chars(usize),
+    /// Rotates after this many completed writes, however many newlines
+    /// they contain. Each `poll_write` counts once, so a buffered writer in
+    /// front of the file changes what a "write" is. Empty writes don't
+    /// count.
+    Writes(usize),
+    /// Rotates by bytes, retuning the threshold after every rotation so that
+    /// roughly `files` files are written per `window` at the throughput seen
+    /// so far. Starts at `initial_bytes`.
+    ///
+    /// This is best-effort: the threshold follows the average rate of the
+    /// last file, so bursty writes make file counts vary.
+    Adaptive {
+        files: usize,
+        window: std::time::Duration,
+        initial_bytes: usize,
+    },
+    /// Rotates once every one of the modes would, e.g. both a byte and a
+    /// line threshold, so neither long nor short lines make tiny files.
+    /// Can't hold [`Adaptive`](Self::Adaptive) modes.
+    All(Vec<RotationMode>),
+}
+
+/// What has gone into the active file, as seen by a
+/// [`RotatingFileBuilder::rotation_veto`] hook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RotationStats {
+    pub lines: usize,
+    pub bytes: usize,
+    pub writes: usize,
+    /// How long the active file has been open.
+    pub age: std::time::Duration,
+}
+
+/// When the active file is flushed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FlushStrategy {
+    /// Only when `flush` is called.
+    #[default]
+    Manual,
+    /// After every write, which completes only once its data has been
+    /// handed to the OS.
+    EveryWrite,
+    /// After a write that comes at least this long after the last flush.
+    /// Like `max_file_age`, this is only checked on writes.
+    Interval(std::time::Duration),
+    /// Only when the file is rotated or shut down, which always flushes.
+    /// Calls to `flush` still check `rotate_on_flush` but leave the file
+    /// alone.
+    BeforeRotation,
+}
+
+/// What [`RotatingFile::repair`] found and fixed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Half-written `.tmp` outputs of interrupted compressions, now deleted.
+    pub removed_temp: Vec<PathBuf>,
+    /// Archives that were left uncompressed and have now been compressed.
+    pub compressed: Vec<PathBuf>,
+    /// Half-written outputs kept for compression to resume from, with
+    /// [`RotatingFileBuilder::resumable_compression`].
+    pub resumed: Vec<PathBuf>,
+    /// Sequence numbers missing between the oldest and newest archive.
+    pub missing_sequences: Vec<u64>,
+}
+
+/// How the active file is moved out of the way on rotation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RotationStrategy {
+    /// Renames the active file to its archive name and opens a new file at
+    /// the original path. Readers following the file by name see a new
+    /// inode; readers holding the old descriptor keep reading the archive.
+    #[default]
+    RenameReopen,
+    /// Copies the active file to its archive name, then truncates it in
+    /// place. The inode never changes, so readers following the descriptor
+    /// keep reading, at the cost of copying the whole file.
+    CopyTruncate,
+}
+
+/// Why a file was rotated.
+///
+/// When several triggers call for a rotation at once, the file rotates
+/// once, for the first of them in this order: a forced rotation (by a JSON
+/// key, pattern, snapshot or shutdown), the thresholds of the
+/// [`RotationMode`], in the order of [`RotationMode::All`], then age, then
+/// the day boundary. The predicate of
+/// [`RotatingFileBuilder::rotate_when`] is only asked when none of those
+/// fire. [`RotatingFile::last_rotation_reasons`] lists every one that fired.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RotationReason {
+    Lines,
+    Bytes,
+    Chars,
+    Writes,
+    /// The file reached its [`RotatingFileBuilder::max_file_age`].
+    Age,
+    /// A new value of the key watched by `rotate_on_json_change`.
+    JsonKey,
+    /// A line matching the pattern of `rotate_after_match`.
+    Pattern,
+    /// The first write of a new local day, with
+    /// [`RotatingFileBuilder::daily_boundary`].
+    Day,
+    /// A call to [`RotatingFile::snapshot_rotate`].
+    Snapshot,
+    /// Shutting down with [`RotatingFileBuilder::rotate_on_shutdown`].
+    Shutdown,
+    /// The predicate of [`RotatingFileBuilder::rotate_when`].
+    Custom,
+}
+
+impl RotationReason {
+    /// The token written into archive names by
+    /// [`RotatingFileBuilder::trigger_in_name`].
+    pub fn token(&self) -> &'static str {
+        match self {
+            RotationReason::Lines => "lines",
+            RotationReason::Bytes => "bytes",
+            RotationReason::Chars => "chars",
+            RotationReason::Writes => "writes",
+            RotationReason::Age => "age",
+            RotationReason::JsonKey => "json",
+            RotationReason::Pattern => "pattern",
+            RotationReason::Day => "day",
+            RotationReason::Snapshot => "snapshot",
+            RotationReason::Shutdown => "shutdown",
+            RotationReason::Custom => "custom",
+        }
+    }
+
+    pub(crate) const ALL: [RotationReason; 11] = [
+        RotationReason::Lines,
+        RotationReason::Bytes,
+        RotationReason::Chars,
+        RotationReason::Writes,
+        RotationReason::Age,
+        RotationReason::JsonKey,
+        RotationReason::Pattern,
+        RotationReason::Day,
+        RotationReason::Snapshot,
+        RotationReason::Shutdown,
+        RotationReason::Custom,
+    ];
+}
+
+impl RotationMode {
+    /// Rounds the byte thresholds for [`RotatingFileBuilder::block_alignment`].
+    fn aligned(self, block: usize) -> Self {
+        match self {
+            RotationMode::Bytes(b) => RotationMode::Bytes(align(b, block)),
+            RotationMode::Adaptive {
+                files,
+                window,
+                initial_bytes,
+            } => RotationMode::Adaptive {
+                files,
+                window,
+                initial_bytes: align(initial_bytes, block),
+            },
+            RotationMode::All(modes) => {
+                RotationMode::All(modes.into_iter().map(|m| m.aligned(block)).collect())
+            }
+            mode => mode,
+        }
+    }
+
+    fn exceeded(&self, counters: &Counters) -> bool {
+        !self.exceeded_reasons(counters).is_empty()
+    }
+
+    /// The thresholds reached, each once and in the order of the modes.
+    fn exceeded_reasons(&self, counters: &Counters) -> Vec<RotationReason> {
+        let (exceeded, reason) = match *self {
+            RotationMode::Lines(l) => (counters.lines >= l, RotationReason::Lines),
+            RotationMode::Bytes(b) => (counters.bytes >= b, RotationReason::Bytes),
+            // Never between the bytes of a character
+            RotationMode::Chars(c) => (
+                counters.chars >= c && counters.pending == 0,
+                RotationReason::Chars,
+            ),
+            RotationMode::Writes(w) => (counters.writes >= w, RotationReason::Writes),
+            RotationMode::Adaptive { initial_bytes, .. } => {
+                (counters.bytes >= initial_bytes, RotationReason::Bytes)
+            }
+            RotationMode::All(ref modes) => {
+                let mut reasons = Vec::new();
+                for mode in modes {
+                    let reached = mode.exceeded_reasons(counters);
+                    if reached.is_empty() {
+                        return Vec::new();
+                    }
+                    for reason in reached {
+                        if !reasons.contains(&reason) {
+                            reasons.push(reason);
+                        }
+                    }
+                }
+                return reasons;
+            }
+        };
+        exceeded.then_some(reason).into_iter().collect()
+    }
+}
+
+/// Rounds `bytes` to the nearest multiple of `block`, at least one block.
+fn align(bytes: usize, block: usize) -> usize {
+    (bytes.saturating_add(block / 2) / block).max(1) * block
+}
+
+/// The filesystem block size of a file with `metadata`.
+fn block_size(metadata: &std::fs::Metadata) -> usize {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.blksize() as usize
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        4096
+    }
+}
+
+/// Pads `file` with zeros to a multiple of `block` bytes.
+async fn pad_to_block(file: &mut Pin<Box<File>>, block: usize) -> Result<(), Error> {
+    let block = block as u64;
+    let short = (block - file.metadata().await?.len() % block) % block;
+    file.write_all(&vec![0; short as usize]).await?;
+    file.flush().await
+}
+
+/// Retunes an adaptive byte threshold after a file of `bytes` took
+/// `elapsed` to fill, aiming for files that take `target` each.
+///
+/// Moves halfway towards the ideal threshold per file to damp the effect of
+/// a single unusual file.
+fn adapt_threshold(
+    current: usize,
+    bytes: usize,
+    elapsed: std::time::Duration,
+    target: std::time::Duration,
+) -> usize {
+    if elapsed.is_zero() {
+        return current.saturating_mul(2);
+    }
+    let ideal = bytes as f64 * target.as_secs_f64() / elapsed.as_secs_f64();
+    ((current as f64 + ideal) / 2.0).clamp(1.0, usize::MAX as f64) as usize
+}
+
+type RotateFuture = Pin<Box<dyn Future<Output = Result<Rotated, Error>> + Send>>;
+
+/// A finished rotation: the fresh active file and, with background
+/// compression, the task still finishing the archive.
+struct Rotated {
+    file: Pin<Box<File>>,
+    background: Option<JoinHandle<Result<(), Error>>>,
+    /// The counts of a file reopened after a failed rotation, which carries
+    /// on rather than starting over.
+    resumed: Option<Counters>,
 }
 
 enum StateFuture {
-    FileReady(usize, usize),
-    Rotating(Pin<Box<dyn Future<Output = Result<Pin<Box<File>>, Error>> + Send>>),
+    FileReady(Counters),
+    Rotating(RotateFuture),
 }
 
 pub struct RotatingFile {
     path: PathBuf,
     rotation: RotationMode,
+    /// The current threshold of [`RotationMode::Adaptive`].
+    adaptive_bytes: usize,
+    config: Arc<RotationConfig>,
+    sequence: u64,
+    rotated: bool,
+    last_write_rotated: bool,
+    opened_at: Instant,
+    /// The local day the active file was started on.
+    opened_on: NaiveDate,
+    force_rotate: Option<RotationReason>,
+    lines: LineBuffer,
+    background: Vec<JoinHandle<Result<(), Error>>>,
+    background_error: Option<Error>,
+    /// Bytes of a write that has landed but is still being flushed.
+    unflushed: Option<usize>,
+    last_flush: Instant,
+    /// Counters after each recent write, for estimating the write rate.
+    recent: std::collections::VecDeque<(Instant, Counters)>,
+    /// Set once shut down, after which writes fail.
+    shut_down: bool,
+    rate_limit: Option<rate::TokenBucket>,
+    /// Set during a write that [`RotatingFileBuilder::rotation_barrier`]
+    /// keeps from being split by a rotation.
+    holding: bool,
+    /// When recent rotations happened, for [`RotatingFileBuilder::max_rotations`].
+    rotation_times: std::collections::VecDeque<Instant>,
+    /// Whether rotation is currently held back by `max_rotations`.
+    throttled: bool,
+    times_throttled: u64,
+    /// Rotations failed in a row, and until when threshold rotation is held
+    /// back by [`RotatingFileBuilder::rotation_cooldown`].
+    failed_rotations: u32,
+    cooldown_until: Option<Instant>,
+    /// When [`RotatingFileBuilder::rotate_when`] was last consulted.
+    trigger_checked: Option<Instant>,
+    /// Every trigger that fired with the reason `should_rotate` last gave,
+    /// and with that of the last rotation.
+    fired: Vec<RotationReason>,
+    last_reasons: Vec<RotationReason>,
+    /// When [`RotatingFileBuilder::recreate_missing_dir`] last looked for
+    /// the active file.
+    path_checked: Instant,
+    newlines: NewlineCounter,
+    /// Writes held for [`RotatingFileBuilder::compress_active`].
+    stream: Option<ActiveStream>,
+    #[cfg(test)]
+    flushes: usize,
     state: Cell<StateFuture>,
     file: Option<Pin<Box<File>>>,
 }
 
+/// What has been written to the active file so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Counters {
+    lines: usize,
+    bytes: usize,
+    chars: usize,
+    /// Continuation bytes still to come for the last character, which only
+    /// counts once they have.
+    pending: usize,
+    writes: usize,
+}
+
+impl Counters {
+    fn record(&mut self, buf: &[u8]) {
+        self.record_counted(buf, countlines(buf));
+    }
+
+    fn record_counted(&mut self, buf: &[u8], lines: usize) {
+        self.lines += lines;
+        self.bytes += buf.len();
+        self.chars += countchars(buf, &mut self.pending);
+        self.writes += 1;
+    }
+}
+
+/// How many recent writes the write rate is estimated from.
+const RATE_WINDOW: usize = 16;
+
+/// How often [`RotatingFileBuilder::recreate_missing_dir`] looks for the
+/// active file.
+const MISSING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+fn join_result(result: Result<Result<(), Error>, tokio::task::JoinError>) -> Result<(), Error> {
+    result.map_err(Error::other)?
+}
+
+/// Opens the active file for appending, retrying transient failures as
+/// configured by [`RotatingFileBuilder::open_retry`].
+async fn open_active(path: &Path, config: &RotationConfig) -> Result<File, Error> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).append(true);
+    retry_transient(config.open_retry, || options.open(path)).await
+}
+
+/// Where the next active file of `path` is created before it takes over.
+fn next_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_owned();
+    name.push(".next");
+    path.with_file_name(name)
+}
+
+async fn create_next(path: &Path, config: &RotationConfig) -> Result<File, Error> {
+    let next = next_path(path);
+    // Left behind by a rotation that failed after creating it
+    match tokio::fs::remove_file(&next).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    open_active(&next, config)
+        .await
+        .map_err(|e| diagnose_create(e, &next))
+}
+
+/// Points out that a full device while creating `path` may mean the
+/// filesystem ran out of inodes rather than space.
+pub(crate) fn diagnose_create(e: Error, path: &Path) -> Error {
+    if e.kind() != ErrorKind::StorageFull {
+        return e;
+    }
+    Error::new(
+        ErrorKind::StorageFull,
+        format!(
+            "could not create {}: {}; if the device has free space, its filesystem is out of inodes",
+            path.display(),
+            e
+        ),
+    )
+}
+
+async fn retry_transient<T, F, Fut>(retry: Option<(usize, Duration)>, mut op: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let (mut attempts, mut backoff) = retry.unwrap_or_default();
+    loop {
+        match op().await {
+            Err(e) if attempts > 0 && is_transient(&e) => {
+                attempts -= 1;
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(e: &Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    #[cfg(windows)]
+    if matches!(e.raw_os_error(), Some(32) | Some(33)) {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    )
+}
+
+/// Creates `dir` and any missing parents, with the configured mode on Unix.
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn create_dir(dir: &Path, config: &RotationConfig) -> Result<(), Error> {
+    let mut builder = tokio::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(mode) = config.dir_mode {
+        builder.mode(mode);
+    }
+    builder.create(dir).await
+}
+
+/// Where the counts of `path` are checkpointed.
+fn checkpoint_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_owned();
+    name.push(".checkpoint");
+    path.with_file_name(name)
+}
+
+/// Reads the `(bytes, lines)` saved by [`RotatingFile::close`].
+async fn read_checkpoint(path: &Path) -> Option<(u64, usize)> {
+    let saved = tokio::fs::read_to_string(checkpoint_path(path))
+        .await
+        .ok()?;
+    let (bytes, lines) = saved.trim().split_once(' ')?;
+    Some((bytes.parse().ok()?, lines.parse().ok()?))
+}
+
+/// Counts the lines and bytes left to read in `file`.
+async fn count(file: &mut File, newlines: &mut NewlineCounter) -> (usize, usize, usize) {
+    let (mut lines, mut bytes, mut chars, mut pending) = (0, 0, 0, 0);
+    let mut buf = [0u8; 1024];
+    while let Ok(n) = file.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        lines += newlines.count(&buf[..n]);
+        bytes += n;
+        chars += countchars(&buf[..n], &mut pending);
+    }
+    (lines, bytes, chars)
+}
+
+/// Counts the lines, bytes and characters of the active file, decompressing it for
+/// [`RotatingFileBuilder::compress_active`].
+async fn count_active(
+    path: &Path,
+    config: &RotationConfig,
+    newlines: &mut NewlineCounter,
+) -> Result<(usize, usize, usize), Error> {
+    if !config.compress_active {
+        return Ok(count(&mut File::open(path).await?, newlines).await);
+    }
+    let (path, codec, mut detecting) = (path.to_path_buf(), config.codec.clone(), *newlines);
+    let resumable = config.resumable_compression;
+    let (counts, detected) = tokio::task::spawn_blocking(move || {
+        let counts = (|| {
+            if resumable {
+                // Cuts off a member torn by a crash, so new ones follow whole ones
+                let (end, _) = compress::valid_prefix(&path, &codec)?;
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(end)?;
+            }
+            compress::decoded_counts(&path, &codec, &mut detecting)
+        })();
+        (counts, detecting)
+    })
+    .await
+    .map_err(Error::other)?;
+    *newlines = detected;
+    counts
+}
+
 fn countlines(buf: &[u8]) -> usize {
     buf.iter().filter(|x| **x == b'\n').count()
 }
 
+/// Counts the UTF-8 characters ending in `buf`, carrying the continuation
+/// bytes still to come for one split between buffers in `pending`, so it
+/// counts in the buffer its last byte is in. A character cut short by
+/// another counts where it was cut, and stray continuation bytes not at all.
+pub(crate) fn countchars(buf: &[u8], pending: &mut usize) -> usize {
+    let mut chars = 0;
+    for &b in buf {
+        if *pending > 0 && b & 0xC0 == 0x80 {
+            *pending -= 1;
+            chars += usize::from(*pending == 0);
+            continue;
+        }
+        chars += usize::from(*pending > 0);
+        *pending = match b {
+            0x80..=0xBF => continue,
+            0xC0..=0xDF => 1,
+            0xE0..=0xEF => 2,
+            0xF0..=0xF7 => 3,
+            _ => 0,
+        };
+        chars += usize::from(*pending == 0);
+    }
+    chars
+}
+
 impl RotatingFile {
     pub async fn new<P: AsRef<Path>>(path: P, mode: RotationMode) -> Result<Self, Error> {
-        // Count existing details if file exists
+        Self::builder(path, mode).build().await
+    }
+
+    /// Opens `path` as if it already held `lines` lines and `bytes` bytes,
+    /// skipping the scan that would count them; see
+    /// [`RotatingFileBuilder::initial_counts`].
+    pub async fn new_with_counts<P: AsRef<Path>>(
+        path: P,
+        mode: RotationMode,
+        lines: usize,
+        bytes: usize,
+    ) -> Result<Self, Error> {
+        Self::builder(path, mode)
+            .initial_counts(lines, bytes)
+            .build()
+            .await
+    }
+
+    pub fn builder<P: AsRef<Path>>(path: P, mode: RotationMode) -> RotatingFileBuilder {
+        RotatingFileBuilder::new(path.as_ref().to_path_buf(), mode)
+    }
+
+    pub(crate) async fn open(
+        path: PathBuf,
+        mut mode: RotationMode,
+        mut config: RotationConfig,
+    ) -> Result<Self, Error> {
+        config.naming.custom_extensions = std::iter::once(&config.codec)
+            .chain(&config.extra_codecs)
+            .chain(config.retention.iter().filter_map(|r| r.cold.as_ref()))
+            .filter_map(|codec| Some(codec.custom_extension()?.to_string()))
+            .collect();
+
+        // Count existing details if file exists, skipping what a checkpoint
+        // already counted unless the file has shrunk below it
         let mut lines_at: usize = 0;
         let mut bytes_at: usize = 0;
-        if let Ok(mut existing) = File::open(path.as_ref()).await {
-            let mut buf = [0u8; 1024];
-            while let Ok(n) = existing.read(&mut buf).await {
-                if n == 0 {
-                    break;
+        let mut chars_at: usize = 0;
+        let opened_on = match tokio::fs::metadata(&path).await {
+            Ok(m) if m.len() > 0 && !config.truncate_on_open => {
+                DateTime::<Local>::from(m.modified()?).date_naive()
+            }
+            _ => Local::now().date_naive(),
+        };
+        let initial_counts = match config.truncate_on_open {
+            true => Some((0, 0)),
+            false => config.initial_counts,
+        };
+        let mut newlines = NewlineCounter::new(config.line_counting);
+        if let Some((lines, bytes)) = initial_counts {
+            lines_at = lines;
+            bytes_at = bytes;
+            chars_at = bytes;
+        } else if let Ok(mut existing) = File::open(&path).await {
+            let len = existing.metadata().await?.len();
+            if config.checkpoint {
+                if let Some((bytes, lines)) = read_checkpoint(&path).await {
+                    if bytes <= len {
+                        existing.seek(std::io::SeekFrom::Start(bytes)).await?;
+                        bytes_at = bytes as usize;
+                        chars_at = bytes as usize;
+                        lines_at = lines;
+                    }
                 }
-                lines_at += countlines(&buf[..n]);
-                bytes_at += n;
             }
+            let (lines, bytes, chars) = match config.compress_active {
+                true => count_active(&path, &config, &mut newlines).await?,
+                false => count(&mut existing, &mut newlines).await,
+            };
+            lines_at += lines;
+            bytes_at += bytes;
+            chars_at += chars;
         }
 
-        if let Some(dir) = path.as_ref().parent() {
-            tokio::fs::create_dir_all(dir).await?;
+        if let Some(dir) = path.parent() {
+            create_dir(dir, &config).await?;
+        }
+        for dir in [
+            config.naming.archive_dir.as_ref(),
+            config.staging_dir.as_ref(),
+        ]
+        .iter()
+        .flatten()
+        {
+            create_dir(dir, &config).await?;
         }
 
-        let file = Box::pin(
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(path.as_ref())
-                .await?,
-        );
+        let stream = config.compress_active.then(ActiveStream::default);
+        let rate_limit = config.write_rate_limit.map(rate::TokenBucket::new);
+        let file = Box::pin(open_active(&path, &config).await?);
+        if config.truncate_on_open {
+            file.set_len(0).await?;
+        }
+        if let Some((block, pad)) = config.block_alignment {
+            let block = match block {
+                0 => block_size(&file.metadata().await?),
+                block => block,
+            };
+            config.block_alignment = Some((block, pad));
+            mode = mode.aligned(block);
+        }
+
+        if let Some(budget) = &config.disk_budget {
+            budget.register(path.clone(), config.naming.clone(), config.pins.clone());
+        }
+
+        let sequence = match config.naming.sequence_width {
+            Some(_) => archive::list(&path, &config.naming)
+                .await?
+                .iter()
+                .filter_map(|a| a.sequence)
+                .max()
+                .map_or(1, |max| max + 1),
+            None => 0,
+        };
 
         Ok(Self {
-            path: path.as_ref().to_path_buf(),
+            path,
+            adaptive_bytes: match mode {
+                RotationMode::Adaptive { initial_bytes, .. } => initial_bytes,
+                _ => 0,
+            },
             rotation: mode,
-            state: Cell::new(StateFuture::FileReady(lines_at, bytes_at)),
+            config: Arc::new(config),
+            sequence,
+            rotated: false,
+            last_write_rotated: false,
+            opened_at: Instant::now(),
+            opened_on,
+            force_rotate: None,
+            lines: LineBuffer::default(),
+            background: Vec::new(),
+            background_error: None,
+            unflushed: None,
+            last_flush: Instant::now(),
+            recent: Default::default(),
+            shut_down: false,
+            rate_limit,
+            holding: false,
+            rotation_times: std::collections::VecDeque::new(),
+            throttled: false,
+            times_throttled: 0,
+            failed_rotations: 0,
+            cooldown_until: None,
+            trigger_checked: None,
+            fired: Vec::new(),
+            last_reasons: Vec::new(),
+            path_checked: Instant::now(),
+            newlines,
+            stream,
+            #[cfg(test)]
+            flushes: 0,
+            state: Cell::new(StateFuture::FileReady(Counters {
+                lines: lines_at,
+                bytes: bytes_at,
+                chars: chars_at,
+                pending: 0,
+                writes: 0,
+            })),
             file: Some(file),
         })
     }
 
-    fn get_rotate_dest(path: &Path) -> PathBuf {
-        let mut path = path.to_path_buf();
-        let extension = path.extension().unwrap().to_owned();
-        path.set_extension("");
-        let mut filename = path.file_name().unwrap().to_owned(); // Must be file in constructor
-        filename.push(Local::now().format("-%Y-%m-%d_%H-%M-%S").to_string());
-        path.set_file_name(filename);
-        path.set_extension(extension);
-        println!("{:?}", path);
-        path
+    /// The directory holding the active file, where archives are written.
+    pub fn directory(&self) -> &Path {
+        archive::directory(&self.path)
     }
 
-    async fn rotate_fut(path: PathBuf, file: Pin<Box<File>>) -> Result<Pin<Box<File>>, Error> {
-        file.sync_all().await?;
+    /// Whether the most recently completed write went through a rotation
+    /// before landing in the file.
+    pub fn last_write_rotated(&self) -> bool {
+        self.last_write_rotated
+    }
 
-        let target_path = Self::get_rotate_dest(&path);
-        tokio::fs::rename(&path, &target_path).await?;
-        Self::compress(&target_path).await?;
-        Ok(Box::pin(
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .append(true)
-                .open(&path)
-                .await?,
-        ))
+    /// Every trigger that called for the last rotation, first the one it
+    /// was named after; see [`RotationReason`] for their order. Empty until
+    /// the first rotation.
+    pub fn last_rotation_reasons(&self) -> &[RotationReason] {
+        &self.last_reasons
     }
 
-    async fn compress(path: &Path) -> Result<(), Error> {
-        let mut outputfile = path.to_path_buf();
-        let mut extension = outputfile.extension().unwrap().to_owned();
-        extension.push(".gz");
-        outputfile.set_extension(extension);
+    /// The most memory the blocks of
+    /// [`RotatingFileBuilder::parallel_compression`] have held at once, in
+    /// bytes, across all of this file's compressions so far.
+    pub fn compression_peak_memory(&self) -> usize {
+        self.config.compression_memory.peak()
+    }
 
-        let mut inputfile = File::open(&path).await?;
-        let mut gz = GzEncoder::new(
-            std::fs::File::create(outputfile.clone())?,
-            Compression::fast(),
-        );
-        loop {
-            let mut buffer = [0_u8; 4096];
-            let n = inputfile.read(&mut buffer[..]).await?;
-            if n == 0 {
-                gz.finish()?;
-                tokio::fs::remove_file(&path).await?;
-                break;
-            } else {
-                gz.write_all(&buffer[..n])?;
+    /// How many times [`RotatingFileBuilder::max_rotations`] has started
+    /// holding back rotation.
+    pub fn times_throttled(&self) -> u64 {
+        self.times_throttled
+    }
+
+    /// Estimates how long until the file is rotated, from the rate of the
+    /// last few writes and the remaining threshold or
+    /// [`max_file_age`](RotatingFileBuilder::max_file_age).
+    ///
+    /// Returns `None` until there have been two writes since the last
+    /// rotation, or if nothing has been written in between.
+    pub fn estimated_time_to_rotation(&self) -> Option<std::time::Duration> {
+        let by_threshold = self.time_to_threshold(&self.rotation);
+        let by_age = self
+            .config
+            .max_file_age
+            .map(|age| age.saturating_sub(self.opened_at.elapsed()));
+        match (by_threshold, by_age) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b).filter(|_| self.recent.len() >= 2),
+        }
+    }
+
+    /// Estimates how long until `mode` is reached, from the rate of the last
+    /// few writes.
+    fn time_to_threshold(&self, mode: &RotationMode) -> Option<std::time::Duration> {
+        let (first_at, first) = self.recent.front()?;
+        let (last_at, last) = self.recent.back()?;
+        let span = last_at.duration_since(*first_at).as_secs_f64();
+        let (threshold, done, start) = match *mode {
+            RotationMode::Lines(l) => (l, last.lines, first.lines),
+            RotationMode::Bytes(b) => (b, last.bytes, first.bytes),
+            RotationMode::Chars(c) => (c, last.chars, first.chars),
+            RotationMode::Writes(w) => (w, last.writes, first.writes),
+            RotationMode::Adaptive { .. } => (self.adaptive_bytes, last.bytes, first.bytes),
+            RotationMode::All(ref modes) => {
+                let each = modes.iter().map(|mode| self.time_to_threshold(mode));
+                return each.collect::<Option<Vec<_>>>()?.into_iter().max();
             }
+        };
+        match done - start {
+            0 => None,
+            _ if span == 0.0 => None,
+            progress => {
+                let rate = progress as f64 / span;
+                let remaining = threshold.saturating_sub(done) as f64 / rate;
+                Some(
+                    std::time::Duration::from_secs_f64(remaining).saturating_sub(last_at.elapsed()),
+                )
+            }
+        }
+    }
+
+    /// Lists this file's archives, oldest first.
+    pub async fn archives(&self) -> Result<Vec<ArchiveInfo>, Error> {
+        archive::list(&self.path, &self.config.naming).await
+    }
+
+    /// Returns the most recent archive by sequence and timestamp, if any.
+    pub async fn latest_archive(&self) -> Result<Option<PathBuf>, Error> {
+        Ok(self.archives().await?.pop().map(|a| a.path))
+    }
+
+    /// Sums the size on disk of the active file and all of its archives.
+    pub async fn total_footprint(&self) -> Result<u64, Error> {
+        let active = match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        let archived: u64 = self.archives().await?.iter().map(|a| a.size).sum();
+        Ok(active + archived)
+    }
+
+    /// Compresses every archive left plain, e.g. by
+    /// [`RotatingFileBuilder::defer_compression`], returning how many were
+    /// compressed.
+    pub async fn compress_pending(&self) -> Result<usize, Error> {
+        if self.config.codec == Codec::None {
+            return Ok(0);
+        }
+        let mut compressed = 0;
+        let archives = self.archives().await?;
+        for archive in archives.iter().filter(|a| self.should_compress(a)) {
+            compress::compress_on_executor(&self.config, &self.path, &archive.path).await?;
+            compressed += 1;
+        }
+        Ok(compressed)
+    }
+
+    /// Whether `archive` is plain and [`RotatingFileBuilder::compress_if`]
+    /// allows compressing it.
+    fn should_compress(&self, archive: &ArchiveInfo) -> bool {
+        !archive.compressed && self.config.compress_filter(&archive.path)
+    }
+
+    /// Reads the contents of every archive, decompressed, oldest first.
+    ///
+    /// Gaps in the sequence numbering are recorded in [`Replay::gaps`] as
+    /// they're reached and, with [`Gaps::Fail`], also fail the read at that
+    /// point. Archives without sequence numbers can't be checked.
+    pub async fn replay(&self, gaps: Gaps) -> Result<Replay, Error> {
+        Ok(Replay::new(self.archives().await?, gaps))
+    }
+
+    /// Recounts the lines and bytes of the active file from disk, e.g. after
+    /// something else has written to or truncated it. Finishes a pending
+    /// write or rotation first.
+    pub async fn resync_counts(&mut self) -> Result<(), Error> {
+        self.flush().await?;
+        let mut newlines = NewlineCounter::new(self.config.line_counting);
+        let (lines, bytes, chars) = count_active(&self.path, &self.config, &mut newlines).await?;
+        if let StateFuture::FileReady(counters) = self.state.get_mut() {
+            counters.lines = lines;
+            counters.bytes = bytes;
+            counters.chars = chars;
         }
         Ok(())
     }
 
-    fn rotate(&mut self) {
-        self.state.replace(StateFuture::Rotating(
-            Self::rotate_fut(self.path.clone(), self.file.take().unwrap()).boxed(),
-        ));
+    /// Reads every archive and then the active file as one stream; see
+    /// [`RotatingReader`].
+    pub async fn reader(&self) -> Result<RotatingReader, Error> {
+        RotatingReader::with_naming(&self.path, &self.config.naming).await
     }
-}
 
-impl AsyncWrite for RotatingFile {
-    fn poll_write(
-        self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-        buf: &[u8],
-    ) -> Poll<Result<usize, Error>> {
-        let me = self.get_mut();
+    /// Opens the active file read-only, up to its current end, to inspect
+    /// what's been written without disturbing the writer.
+    ///
+    /// This is a snapshot: later writes aren't read, nor are bytes not yet
+    /// flushed to the file. After a rotation it still reads the file as it
+    /// was when opened, which may since have been archived.
+    pub async fn read_active_snapshot(&self) -> Result<impl AsyncRead + Unpin, Error> {
+        let file = File::open(&self.path).await?;
+        let len = file.metadata().await?.len();
+        Ok(file.take(len))
+    }
 
-        loop {
-            match me.state.get_mut() {
-                StateFuture::FileReady(ref mut lines, ref mut bytes) => {
-                    let shouldrotate = match me.rotation {
-                        RotationMode::Lines(l) => *lines >= l,
-                        RotationMode::Bytes(b) => *bytes >= b,
-                    };
-                    if shouldrotate {
-                        me.rotate();
+    /// Cleans up the archive directory after a crash or manual tampering.
+    ///
+    /// Removes stale temporary files, compresses archives that were left
+    /// plain (unless the codec is [`Codec::None`]) and reports gaps in the
+    /// sequence numbering. Meant to be run at startup, before writing.
+    ///
+    /// With [`RotatingFileBuilder::resumable_compression`], the partial
+    /// output of a plain archive is kept and its compression resumed.
+    pub async fn repair(&self) -> Result<RepairReport, Error> {
+        let mut report = RepairReport::default();
+        for temp in archive::stale_temps(&self.path, &self.config.naming).await? {
+            if self.config.resumable_compression {
+                if let Some(source) = compress::partial_source(&temp) {
+                    if tokio::fs::try_exists(&source).await? {
+                        report.resumed.push(temp);
                         continue;
-                    } else {
-                        let ret = Pin::as_mut(me.file.as_mut().unwrap()).poll_write(cx, buf);
-                        if let Poll::Ready(Ok(n)) = ret {
-                            *bytes += n;
-                            *lines += countlines(&buf[..n]);
-                        }
-                        return ret;
                     }
                 }
-                StateFuture::Rotating(ref mut fut) => match Pin::as_mut(fut).poll(cx) {
-                    Poll::Ready(Ok(file)) => {
-                        me.file = Some(file);
-                        me.state.replace(StateFuture::FileReady(0, 0));
-                        continue;
-                    }
-                    Poll::Pending => return Poll::Pending,
-                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                },
             }
+            tokio::fs::remove_file(&temp).await?;
+            report.removed_temp.push(temp);
+        }
+
+        let archives = self.archives().await?;
+        if self.config.codec != Codec::None {
+            for archive in archives.iter().filter(|a| self.should_compress(a)) {
+                let compressed =
+                    compress::compress_on_executor(&self.config, &self.path, &archive.path).await?;
+                report.compressed.push(compressed);
+            }
+        }
+
+        let sequences: Vec<u64> = archives.iter().filter_map(|a| a.sequence).collect();
+        for pair in sequences.windows(2) {
+            report.missing_sequences.extend(pair[0] + 1..pair[1]);
         }
+        Ok(report)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        let me = self.get_mut();
-        match me.state.get_mut() {
-            StateFuture::FileReady(_, _) => Pin::as_mut(me.file.as_mut().unwrap()).poll_flush(cx),
-            StateFuture::Rotating(ref mut fut) => match Pin::as_mut(fut).poll(cx) {
-                Poll::Ready(Ok(file)) => {
-                    me.file = Some(file);
-                    me.state.replace(StateFuture::FileReady(0, 0));
-                    Poll::Ready(Ok(()))
-                }
-                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-                Poll::Pending => Poll::Pending,
-            },
+    async fn rotate_fut(
+        path: PathBuf,
+        mut file: Pin<Box<File>>,
+        config: Arc<RotationConfig>,
+        sequence: u64,
+        reason: RotationReason,
+    ) -> Result<Rotated, Error> {
+        #[cfg(feature = "tracing")]
+        let started = Instant::now();
+        if let Some((block, true)) = config.block_alignment {
+            pad_to_block(&mut file, block).await?;
         }
+        file.sync_all().await?;
+
+        let now = config.archive_time(&path);
+        let name = |counter| {
+            let archive = config
+                .naming
+                .archive_path(&path, &now, sequence, reason, counter);
+            match config.compress_active {
+                true => config.naming.output_path(&config.codec, &archive),
+                false => archive,
+            }
+        };
+        let directory = config.naming.directory_at(&path, &now);
+        if config.naming.date_dirs.is_some() {
+            create_dir(&directory, &config).await?;
+        }
+        let mut dirs = vec![directory.as_path()];
+        dirs.extend(config.staging_dir.as_deref());
+        let target_path = archive::claim(name, &dirs, &config.naming, config.collision).await?;
+        let target_path = match &config.staging_dir {
+            Some(dir) => dir.join(target_path.file_name().unwrap()),
+            None => target_path,
+        };
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("archive", tracing::field::display(target_path.display()));
+        let compress = config.compress_filter(&path);
+        let direct = config.direct_compression && config.codec != Codec::None && compress;
+        let rename = config.strategy == RotationStrategy::RenameReopen;
+        let (kept, file) = match rename {
+            true => (None, Some(file)),
+            false => (Some(file), None),
+        };
+        // Creating the next file before anything moves means running out of
+        // inodes fails the rotation while the active file is still in place
+        let next = match rename {
+            true => Some(Box::pin(create_next(&path, &config).await?)),
+            false => None,
+        };
+        let encoded = match direct {
+            true => {
+                drop(file);
+                Some(
+                    compress::encode_on_executor(&config, &path, &path, &target_path, rename)
+                        .await?,
+                )
+            }
+            false => None,
+        };
+        // The archive appears and retention makes room for it in one step
+        let lock = config.archive_lock.lock().await;
+        let archive = match encoded {
+            Some(encoded) => encoded
+                .commit(&config)
+                .await?
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "active file disappeared"))?,
+            None if rename => {
+                archive::move_file(&path, &target_path).await?;
+                target_path
+            }
+            None => {
+                tokio::fs::copy(&path, &target_path)
+                    .await
+                    .map_err(|e| diagnose_create(e, &target_path))?;
+                target_path
+            }
+        };
+        if next.is_some() {
+            tokio::fs::rename(next_path(&path), &path).await?;
+        }
+        if let Some(file) = &kept {
+            file.set_len(0).await?;
+        }
+        if let Some(on_swap) = &config.on_swap {
+            on_swap(&path, &archive);
+        }
+        if reason == RotationReason::Snapshot {
+            config.pins.pin(archive.clone());
+        }
+        if let (Some(policy), None) = (&config.retention, &config.staging_dir) {
+            retention::apply_on_create(policy, &path, &config.naming, &config.pins).await?;
+        }
+        drop(lock);
+        if config.checkpoint {
+            // The checkpoint described the file that was just archived
+            match tokio::fs::remove_file(checkpoint_path(&path)).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        let compressed = direct || config.compress_active || !compress;
+        let finish = Self::finish_archive(path.clone(), archive, config.clone(), compressed);
+        #[cfg(feature = "tracing")]
+        let finish = tracing::Instrument::instrument(
+            finish,
+            tracing::info_span!("compression", codec = ?config.codec),
+        );
+        let background = if config.background_compression {
+            Some(tokio::spawn(finish))
+        } else {
+            finish.await?;
+            None
+        };
+        let file = kept.or(next).unwrap();
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("duration_ms", started.elapsed().as_millis() as u64);
+        Ok(Rotated {
+            file,
+            background,
+            resumed: None,
+        })
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
-        let me = self.get_mut();
-        match me.state.get_mut() {
-            StateFuture::FileReady(_, _) => {
-                Pin::as_mut(me.file.as_mut().unwrap()).poll_shutdown(cx)
-            }
-            StateFuture::Rotating(ref mut fut) => match Pin::as_mut(fut).poll(cx) {
-                Poll::Ready(Ok(file)) => {
-                    me.file = Some(file);
-                    me.state.replace(StateFuture::FileReady(0, 0));
-                    Poll::Ready(Ok(()))
-                }
-                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
-                Poll::Pending => Poll::Pending,
+    /// Compresses a freshly rotated archive unless it already is,
+    /// publishes it from the staging directory and applies retention.
+    async fn finish_archive(
+        path: PathBuf,
+        archive: PathBuf,
+        config: Arc<RotationConfig>,
+        compressed: bool,
+    ) -> Result<(), Error> {
+        let encoded = match config.codec {
+            _ if compressed => None,
+            Codec::None => None,
+            _ if config.defer_compression => None,
+            _ => match compress::encode_on_executor(&config, &path, &archive, &archive, true).await
+            {
+                Ok(encoded) => Some(encoded),
+                // Retention removed the archive before it was compressed
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            },
+        };
+        let _lock = config.archive_lock.lock().await;
+        let archive = match encoded {
+            Some(encoded) => match encoded.commit(&config).await? {
+                Some(archive) => archive,
+                // Or while it was compressed
+                None => return Ok(()),
             },
+            None => archive,
+        };
+        let archive = match &config.staging_dir {
+            // Content-addressed archives are already named in the final place
+            Some(staging) if archive.parent() == Some(staging.as_path()) => {
+                let directory = config.naming.directory(&path);
+                let compressed = config.naming.codec_extension(&config.codec);
+                if compressed.is_some()
+                    && archive.extension() == compressed.as_deref().map(std::ffi::OsStr::new)
+                {
+                    let plain = archive.with_extension("");
+                    for extra in &config.extra_codecs {
+                        let extra = config.naming.output_path(extra, &plain);
+                        if let Some(name) = extra.file_name() {
+                            archive::publish(&extra, &directory.join(name)).await?;
+                        }
+                    }
+                }
+                let destination = directory.join(archive.file_name().unwrap());
+                let index = archive::index_path(&archive);
+                if config.line_index.is_some() && tokio::fs::try_exists(&index).await? {
+                    archive::publish(&index, &archive::index_path(&destination)).await?;
+                }
+                archive::publish(&archive, &destination).await?;
+                config.pins.moved(&archive, &destination);
+                destination
+            }
+            _ => archive,
+        };
+        if config.fadvise {
+            match tokio::fs::File::open(&archive).await {
+                Ok(file) => {
+                    let file = file.into_std().await;
+                    compress::advise(&config, &file, compress::Advice::DontNeed);
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if config.immutable_archives {
+            let flagged = archive.clone();
+            match tokio::task::spawn_blocking(move || filesystem::set_immutable(&flagged, true))
+                .await
+                .map_err(Error::other)?
+            {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if config.done_markers {
+            tokio::fs::File::create(archive::done_marker(&archive)).await?;
+        }
+        if let Some(policy) = &config.retention {
+            retention::apply(policy, &path, &config.naming, &config.pins).await?;
         }
+        #[cfg(unix)]
+        if config.latest_link {
+            archive::point_latest(&path, &config.naming, &config.codec, &archive).await?;
+        }
+        if let Some(budget) = &config.disk_budget {
+            budget.enforce().await?;
+        }
+        drop(_lock);
+        if let Some(endpoint) = &config.notify {
+            match tokio::fs::metadata(&archive).await {
+                Ok(metadata) => endpoint.send(&archive, metadata.len()).await,
+                // Retention or the budget already removed it
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tokio::io::AsyncWriteExt;
+    /// Writes all of `buf`, and with [`RotatingFileBuilder::rotation_barrier`]
+    /// holds off any rotation until it's all in the same file.
+    pub(crate) async fn write_unsplit(&mut self, buf: &[u8]) -> Result<(), Error> {
+        struct Hold<'a>(&'a mut RotatingFile);
 
-    #[tokio::test]
-    async fn enforce_file_path() {
-        let file = RotatingFile::new(".", RotationMode::Lines(1)).await;
-        assert!(file.is_err());
+        impl Drop for Hold<'_> {
+            fn drop(&mut self) {
+                self.0.holding = false;
+            }
+        }
+
+        // A rotation that's already due still happens before the first piece
+        let n = match buf.is_empty() {
+            true => 0,
+            false => self.write(buf).await?,
+        };
+        let hold = Hold(self);
+        hold.0.holding = hold.0.config.rotation_barrier;
+        hold.0.write_all(&buf[n..]).await
     }
 
-    #[tokio::test]
-    async fn basic_lines() {
-        let mut file = RotatingFile::new("./out/testfile1.log", RotationMode::Lines(1))
-            .await
-            .unwrap();
-        file.write_all(b"1\n2\n3\n").await.unwrap();
-        file.write_all(b"1\n2\n3\n").await.unwrap();
-        file.flush().await.unwrap();
+    /// How many background compressions are still running.
+    pub fn pending_compressions(&self) -> usize {
+        self.background.iter().filter(|h| !h.is_finished()).count()
     }
 
-    #[tokio::test]
-    async fn basic_bytes() {
-        let mut file = RotatingFile::new("./out1/testfile.log", RotationMode::Bytes(2))
-            .await
-            .unwrap();
-        file.write_all(b"112233").await.unwrap();
-        file.write_all(b"112233").await.unwrap();
-        file.flush().await.unwrap();
+    /// Waits for every background compression started so far, returning the
+    /// first error any of them hit since the last wait.
+    pub async fn await_compressions(&mut self) -> Result<(), Error> {
+        futures::future::poll_fn(|cx| self.poll_background(cx)).await
     }
 
-    #[tokio::test]
-    async fn send() {
-        tokio::spawn(async {
-            tokio::fs::create_dir_all("./out1").await.unwrap();
-            let file = RotatingFile::new("./out1/testfile.log", RotationMode::Bytes(2))
-                .await
-                .unwrap();
-            let mut buf = tokio::io::BufWriter::with_capacity(500, file);
-            buf.write_all(b"112233").await.unwrap();
-            buf.write_all(b"112233").await.unwrap();
-            let mut file = buf.into_inner();
-            file.flush().await.unwrap();
-        });
+    /// Rotates now and returns the archive holding everything written
+    /// before the call, once it's compressed and in its final place.
+    ///
+    /// The archive is pinned: retention and the disk budget leave it alone
+    /// until [`release_snapshot`](Self::release_snapshot). An unterminated
+    /// line held back by line buffering goes to the next file instead.
+    pub async fn snapshot_rotate(&mut self) -> Result<PathBuf, Error> {
+        self.flush().await?;
+        futures::future::poll_fn(|cx| self.poll_seal(cx)).await?;
+        let pinned = self.config.pins.len();
+        self.rotate(RotationReason::Snapshot);
+        let mut snapshot = self.flush().await;
+        if snapshot.is_ok() {
+            snapshot = self.await_compressions().await;
+        }
+        match (snapshot, self.config.pins.len() > pinned) {
+            (Ok(()), true) => Ok(self.config.pins.last().unwrap()),
+            (snapshot, _) => {
+                self.config.pins.truncate(pinned);
+                snapshot?;
+                Err(Error::other("snapshot rotation left no archive"))
+            }
+        }
+    }
+
+    /// When the active file reaches its
+    /// [`max_file_age`](RotatingFileBuilder::max_file_age), if it has one.
+    pub fn age_deadline(&self) -> Option<Instant> {
+        Some(self.opened_at + self.config.max_file_age?)
+    }
+
+    /// Rotates the file if it's due, e.g. by age, without a write, returning
+    /// whether it did. [`SharedRotatingFile`] and [`NonBlocking`] call this
+    /// at the [`age_deadline`](Self::age_deadline) so that an idle file
+    /// still rotates by age; the owner of a bare `RotatingFile` calls it
+    /// from a timer of their own.
+    pub async fn rotate_if_due(&mut self) -> Result<bool, Error> {
+        self.flush().await?;
+        let counters = match self.state.get_mut() {
+            StateFuture::FileReady(counters) => *counters,
+            StateFuture::Rotating(_) => return Ok(false),
+        };
+        let reason = match self.should_rotate(&counters) {
+            Some(reason) => reason,
+            None => return Ok(false),
+        };
+        futures::future::poll_fn(|cx| self.poll_seal(cx)).await?;
+        self.rotate(reason);
+        self.flush().await?;
+        Ok(true)
+    }
+
+    /// Hands a [`snapshot_rotate`](Self::snapshot_rotate) archive back to
+    /// retention. Returns whether it was pinned.
+    pub fn release_snapshot(&self, archive: &Path) -> bool {
+        self.config.pins.unpin(archive)
+    }
+
+    /// Shuts the file down, waiting for any background compressions. Like
+    /// after `shutdown`, writes then fail with [`ErrorKind::BrokenPipe`].
+    ///
+    /// With [`RotatingFileBuilder::checkpoint`], saves the active file's
+    /// counts afterwards.
+    pub async fn close(&mut self) -> Result<(), Error> {
+        self.shutdown().await?;
+        if self.config.checkpoint {
+            if let StateFuture::FileReady(counters) = self.state.get_mut() {
+                let saved = format!("{} {}\n", counters.bytes, counters.lines);
+                tokio::fs::write(checkpoint_path(&self.path), saved).await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn counters(&mut self) -> Counters {
+        match self.state.get_mut() {
+            StateFuture::FileReady(counters) => *counters,
+            StateFuture::Rotating(_) => panic!("rotating"),
+        }
+    }
+
+    /// Returns why the file is due for rotation, if it is.
+    fn should_rotate(&mut self, counters: &Counters) -> Option<RotationReason> {
+        if self.holding
+            || self
+                .cooldown_until
+                .is_some_and(|until| Instant::now() < until)
+        {
+            return None;
+        }
+        let too_old = match self.config.max_file_age {
+            Some(age) => counters.bytes > 0 && self.opened_at.elapsed() >= age,
+            None => false,
+        };
+        let adaptive = RotationMode::Bytes(self.adaptive_bytes);
+        let rotation = match self.rotation {
+            RotationMode::Adaptive { .. } => &adaptive,
+            ref mode => mode,
+        };
+        let new_day = self.config.daily_boundary
+            && counters.bytes > 0
+            && Local::now().date_naive() != self.opened_on;
+        let mut reasons = rotation.exceeded_reasons(counters);
+        reasons.extend(too_old.then_some(RotationReason::Age));
+        reasons.extend(new_day.then_some(RotationReason::Day));
+        if reasons.is_empty() {
+            reasons.extend(self.triggered(counters));
+        }
+        let reason = *reasons.first()?;
+        let allowed = match &self.config.veto {
+            Some(allow) => allow(&self.stats(counters)),
+            None => true,
+        };
+        if !allowed {
+            return None;
+        }
+        if let Some((count, window)) = self.config.max_rotations {
+            while self
+                .rotation_times
+                .front()
+                .is_some_and(|at| at.elapsed() >= window)
+            {
+                self.rotation_times.pop_front();
+            }
+            if self.rotation_times.len() >= count {
+                if !self.throttled {
+                    self.throttled = true;
+                    self.times_throttled += 1;
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        file = %self.path.display(),
+                        "rotated {} times within {:?}, deferring rotation",
+                        count,
+                        window,
+                    );
+                }
+                return None;
+            }
+        }
+        self.fired = reasons;
+        Some(reason)
+    }
+
+    fn stats(&self, counters: &Counters) -> RotationStats {
+        RotationStats {
+            lines: counters.lines,
+            bytes: counters.bytes,
+            writes: counters.writes,
+            age: self.opened_at.elapsed(),
+        }
+    }
+
+    /// Consults [`RotatingFileBuilder::rotate_when`], unless it was within
+    /// its interval.
+    fn triggered(&mut self, counters: &Counters) -> Option<RotationReason> {
+        let (rotate, interval) = self.config.trigger.as_ref()?;
+        if self
+            .trigger_checked
+            .is_some_and(|at| at.elapsed() < *interval)
+        {
+            return None;
+        }
+        self.trigger_checked = Some(Instant::now());
+        rotate(&self.stats(counters)).then_some(RotationReason::Custom)
+    }
+
+    fn reopened(&mut self, rotated: Rotated) {
+        if let Some(counters) = rotated.resumed {
+            self.file = Some(rotated.file);
+            self.state.replace(StateFuture::FileReady(counters));
+            return;
+        }
+        self.failed_rotations = 0;
+        self.cooldown_until = None;
+        // Reap finished compressions, keeping the first error for close()
+        let mut i = 0;
+        while i < self.background.len() {
+            if !self.background[i].is_finished() {
+                i += 1;
+                continue;
+            }
+            let handle = self.background.swap_remove(i);
+            if let Some(Err(e)) = handle.now_or_never().map(join_result) {
+                self.background_error.get_or_insert(e);
+            }
+        }
+        self.background.extend(rotated.background);
+        self.file = Some(rotated.file);
+        self.state
+            .replace(StateFuture::FileReady(Counters::default()));
+        self.opened_at = Instant::now();
+        self.opened_on = Local::now().date_naive();
+        self.recent.clear();
+    }
+
+    /// Whether [`RotatingFileBuilder::recreate_missing_dir`] finds the active
+    /// file gone, looking at most once per [`MISSING_CHECK_INTERVAL`].
+    fn path_missing(&mut self) -> bool {
+        if !self.config.recreate_dir || self.path_checked.elapsed() < MISSING_CHECK_INTERVAL {
+            return false;
+        }
+        self.path_checked = Instant::now();
+        matches!(std::fs::metadata(&self.path), Err(e) if e.kind() == ErrorKind::NotFound)
+    }
+
+    /// Creates the active file anew, and its directory, after they were
+    /// deleted. The counts start over.
+    async fn recreate(path: PathBuf, config: Arc<RotationConfig>) -> Result<Rotated, Error> {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(file = %path.display(), "active file was deleted, recreating it");
+        create_dir(archive::directory(&path), &config).await?;
+        Ok(Rotated {
+            file: Box::pin(open_active(&path, &config).await?),
+            background: None,
+            resumed: Some(Counters::default()),
+        })
+    }
+
+    /// Reopens the active file after a failed rotation so writes can go on,
+    /// and starts the cooldown if one is configured.
+    fn rotation_failed(&mut self, e: Error) -> Error {
+        if let Some(backoff) = self.config.rotation_cooldown {
+            let wait = backoff.saturating_mul(1 << self.failed_rotations.min(16));
+            self.cooldown_until = Some(Instant::now() + wait);
+        }
+        self.failed_rotations += 1;
+        let resume = Self::resume(self.path.clone(), self.config.clone());
+        self.state.replace(StateFuture::Rotating(resume.boxed()));
+        e
+    }
+
+    /// Opens the active file again, as left by a failed rotation. Its
+    /// contents are recounted, and the write count starts over.
+    async fn resume(path: PathBuf, config: Arc<RotationConfig>) -> Result<Rotated, Error> {
+        if config.recreate_dir {
+            create_dir(archive::directory(&path), &config).await?;
+        }
+        let file = Box::pin(open_active(&path, &config).await?);
+        let mut newlines = NewlineCounter::new(config.line_counting);
+        let (lines, bytes, chars) = count_active(&path, &config, &mut newlines).await?;
+        Ok(Rotated {
+            file,
+            background: None,
+            resumed: Some(Counters {
+                lines,
+                bytes,
+                chars,
+                pending: 0,
+                writes: 0,
+            }),
+        })
+    }
+
+    fn rotate(&mut self, reason: RotationReason) {
+        // What should_rotate found, unless this rotation has another cause
+        let fired = std::mem::take(&mut self.fired);
+        self.last_reasons = match fired.first() == Some(&reason) {
+            true => fired,
+            false => vec![reason],
+        };
+        if let (RotationMode::Adaptive { files, window, .. }, StateFuture::FileReady(counters)) =
+            (&self.rotation, self.state.get_mut())
+        {
+            self.adaptive_bytes = adapt_threshold(
+                self.adaptive_bytes,
+                counters.bytes,
+                self.opened_at.elapsed(),
+                window.div_f64(*files as f64),
+            );
+            if let Some((block, _)) = self.config.block_alignment {
+                self.adaptive_bytes = align(self.adaptive_bytes, block);
+            }
+        }
+        let rotation = Self::rotate_fut(
+            self.path.clone(),
+            self.file.take().unwrap(),
+            self.config.clone(),
+            self.sequence,
+            reason,
+        );
+        #[cfg(feature = "tracing")]
+        let rotation = {
+            let counters = match self.state.get_mut() {
+                StateFuture::FileReady(counters) => *counters,
+                StateFuture::Rotating(_) => Counters::default(),
+            };
+            let span = tracing::info_span!(
+                "rotation",
+                file = %self.path.display(),
+                archive = tracing::field::Empty,
+                reason = ?reason,
+                bytes = counters.bytes,
+                lines = counters.lines,
+                duration_ms = tracing::field::Empty,
+            );
+            tracing::Instrument::instrument(rotation, span)
+        };
+        self.state.replace(StateFuture::Rotating(rotation.boxed()));
+        self.sequence += 1;
+        if self.config.max_rotations.is_some() {
+            self.rotation_times.push_back(Instant::now());
+            self.throttled = false;
+        }
+    }
+}
+
+impl RotatingFile {
+    /// Writes `buf` straight to the active file, rotating first if due.
+    fn poll_write_raw(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        if let Some(n) = self.unflushed {
+            let flushed = ready!(self.poll_flush_file(cx));
+            self.unflushed = None;
+            return Poll::Ready(flushed.map(|()| n));
+        }
+        ready!(self.poll_drain_stream(cx))?;
+        loop {
+            match self.state.get_mut() {
+                StateFuture::FileReady(counters) => {
+                    let mut counters = *counters;
+                    if self.path_missing() {
+                        let recreate = Self::recreate(self.path.clone(), self.config.clone());
+                        self.state.replace(StateFuture::Rotating(recreate.boxed()));
+                        continue;
+                    }
+                    let forced = match self.holding {
+                        true => None,
+                        false => self.force_rotate.take().filter(|_| counters.bytes > 0),
+                    };
+                    let due = match forced {
+                        Some(reason) => Some(reason),
+                        None if self.config.rotate_on_write => self.should_rotate(&counters),
+                        None => None,
+                    };
+                    if let Some(reason) = due {
+                        match self.poll_seal(cx) {
+                            Poll::Ready(Ok(())) => {}
+                            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                            Poll::Pending => {
+                                self.force_rotate = forced;
+                                return Poll::Pending;
+                            }
+                        }
+                        self.rotate(reason);
+                        continue;
+                    } else {
+                        let ret = match &mut self.stream {
+                            Some(stream) => {
+                                stream.push(buf);
+                                Poll::Ready(Ok(buf.len()))
+                            }
+                            None => Pin::as_mut(self.file.as_mut().unwrap()).poll_write(cx, buf),
+                        };
+                        if let Poll::Ready(Ok(n)) = ret {
+                            let lines = self.newlines.count(&buf[..n]);
+                            counters.record_counted(&buf[..n], lines);
+                            self.state.replace(StateFuture::FileReady(counters));
+                            if self.recent.len() == RATE_WINDOW {
+                                self.recent.pop_front();
+                            }
+                            self.recent.push_back((Instant::now(), counters));
+                            self.last_write_rotated = std::mem::take(&mut self.rotated);
+                            if let Some(stream) = self.stream.as_mut().filter(|s| s.full()) {
+                                stream.seal(&self.config.codec)?;
+                                // The rest waits for the next write or flush
+                                if let Poll::Ready(Err(e)) = self.poll_drain_stream(cx) {
+                                    return Poll::Ready(Err(e));
+                                }
+                            }
+                            if self.flush_due() {
+                                // Reported once the flush completes
+                                self.unflushed = Some(n);
+                                return self.poll_write_raw(cx, buf);
+                            }
+                        }
+                        return ret;
+                    }
+                }
+                StateFuture::Rotating(ref mut fut) => match Pin::as_mut(fut).poll(cx) {
+                    Poll::Ready(Ok(rotated)) => {
+                        self.rotated = rotated.resumed.is_none();
+                        self.reopened(rotated);
+                        continue;
+                    }
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(self.rotation_failed(e))),
+                },
+            }
+        }
+    }
+
+    fn flush_due(&self) -> bool {
+        match self.config.flush {
+            FlushStrategy::EveryWrite => true,
+            FlushStrategy::Interval(every) => self.last_flush.elapsed() >= every,
+            FlushStrategy::Manual | FlushStrategy::BeforeRotation => false,
+        }
+    }
+
+    /// Compresses what [`RotatingFileBuilder::compress_active`] holds and
+    /// appends it to the active file.
+    fn poll_seal(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let Some(stream) = &mut self.stream {
+            stream.seal(&self.config.codec)?;
+        }
+        self.poll_drain_stream(cx)
+    }
+
+    fn poll_drain_stream(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let Some(stream) = &mut self.stream {
+            while !stream.outgoing.is_empty() {
+                let file = Pin::as_mut(self.file.as_mut().unwrap());
+                match ready!(file.poll_write(cx, &stream.outgoing))? {
+                    0 => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                    n => drop(stream.outgoing.drain(..n)),
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush_file(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        ready!(self.poll_seal(cx))?;
+        ready!(Pin::as_mut(self.file.as_mut().unwrap()).poll_flush(cx))?;
+        self.last_flush = Instant::now();
+        #[cfg(test)]
+        {
+            self.flushes += 1;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Waits for all background compressions, then reports the first error
+    /// any of them hit.
+    fn poll_background(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let mut i = 0;
+        while i < self.background.len() {
+            match Pin::new(&mut self.background[i]).poll(cx) {
+                Poll::Ready(result) => {
+                    self.background.swap_remove(i);
+                    if let Err(e) = join_result(result) {
+                        self.background_error.get_or_insert(e);
+                    }
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        if !self.background.is_empty() {
+            return Poll::Pending;
+        }
+        match self.background_error.take() {
+            Some(e) => Poll::Ready(Err(e)),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Writes out complete lines held by the line buffer.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        while !self.lines.outgoing.is_empty() {
+            let outgoing = std::mem::take(&mut self.lines.outgoing);
+            let ret = self.poll_write_raw(cx, &outgoing);
+            self.lines.outgoing = outgoing;
+            match ret {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => {
+                    self.lines.outgoing.drain(..n);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        if std::mem::take(&mut self.lines.rotate_after) {
+            self.force_rotate = Some(RotationReason::Pattern);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes `buf`, through the line buffer if anything needs one.
+    fn poll_write_lines(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        if !LineBuffer::needed(&self.config) {
+            return self.poll_write_raw(cx, buf);
+        }
+
+        // Lines are accepted one at a time, once the previous one is written
+        ready!(self.poll_drain(cx))?;
+        let accepted = self.lines.accept(buf, &self.config);
+        if accepted.rotate_before {
+            self.force_rotate = Some(RotationReason::JsonKey);
+        }
+        if let Poll::Ready(Err(e)) = self.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(accepted.consumed))
+    }
+}
+
+impl AsyncWrite for RotatingFile {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let me = self.get_mut();
+        if me.shut_down {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "write after shutdown",
+            )));
+        }
+        // Neither counted nor a reason to rotate
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if let Some(bucket) = &mut me.rate_limit {
+            let allowed = ready!(bucket.poll_acquire(cx, buf.len()));
+            let written = ready!(me.poll_write_lines(cx, &buf[..allowed]));
+            if let (Some(bucket), Ok(n)) = (&mut me.rate_limit, &written) {
+                bucket.consume(*n);
+            }
+            return Poll::Ready(written);
+        }
+        me.poll_write_lines(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        ready!(me.poll_drain(cx))?;
+        loop {
+            match me.state.get_mut() {
+                StateFuture::FileReady(counters) => {
+                    let counters = *counters;
+                    if me.config.rotate_on_flush {
+                        if let Some(reason) = me.should_rotate(&counters) {
+                            ready!(me.poll_seal(cx))?;
+                            me.rotate(reason);
+                            continue;
+                        }
+                    }
+                    if me.config.flush == FlushStrategy::BeforeRotation {
+                        return Poll::Ready(Ok(()));
+                    }
+                    return me.poll_flush_file(cx);
+                }
+                StateFuture::Rotating(ref mut fut) => {
+                    return match Pin::as_mut(fut).poll(cx) {
+                        Poll::Ready(Ok(rotated)) => {
+                            me.reopened(rotated);
+                            Poll::Ready(Ok(()))
+                        }
+                        Poll::Ready(Err(e)) => Poll::Ready(Err(me.rotation_failed(e))),
+                        Poll::Pending => Poll::Pending,
+                    }
+                }
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        ready!(me.poll_drain(cx))?;
+        // An unterminated last line is written as is rather than lost
+        me.lines.take_partial();
+        ready!(me.poll_drain(cx))?;
+        loop {
+            match me.state.get_mut() {
+                StateFuture::FileReady(counters) => {
+                    let written = counters.bytes > 0;
+                    ready!(me.poll_seal(cx))?;
+                    if me.config.rotate_on_shutdown && written {
+                        me.rotate(RotationReason::Shutdown);
+                        continue;
+                    }
+                    ready!(Pin::as_mut(me.file.as_mut().unwrap()).poll_shutdown(cx))?;
+                    me.shut_down = true;
+                    return me.poll_background(cx);
+                }
+                StateFuture::Rotating(ref mut fut) => match Pin::as_mut(fut).poll(cx) {
+                    Poll::Ready(Ok(rotated)) => {
+                        me.reopened(rotated);
+                        continue;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(me.rotation_failed(e))),
+                    Poll::Pending => return Poll::Pending,
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) async fn fresh_dir(name: &str) -> PathBuf {
+        let dir = PathBuf::from("./out").join(name);
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        dir
+    }
+
+    pub(crate) fn gunzip(path: &Path) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        std::io::Read::read_to_end(
+            &mut flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap()),
+            &mut decoded,
+        )
+        .unwrap();
+        decoded
+    }
+
+    #[tokio::test]
+    async fn enforce_file_path() {
+        let file = RotatingFile::new(".", RotationMode::Lines(1)).await;
+        assert!(file.is_err());
+    }
+
+    #[tokio::test]
+    async fn basic_lines() {
+        let mut file = RotatingFile::new("./out/testfile1.log", RotationMode::Lines(1))
+            .await
+            .unwrap();
+        file.write_all(b"1\n2\n3\n").await.unwrap();
+        file.write_all(b"1\n2\n3\n").await.unwrap();
+        file.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn basic_bytes() {
+        let mut file = RotatingFile::new("./out1/testfile.log", RotationMode::Bytes(2))
+            .await
+            .unwrap();
+        file.write_all(b"112233").await.unwrap();
+        file.write_all(b"112233").await.unwrap();
+        file.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send() {
+        tokio::spawn(async {
+            tokio::fs::create_dir_all("./out1").await.unwrap();
+            let file = RotatingFile::new("./out1/testfile.log", RotationMode::Bytes(2))
+                .await
+                .unwrap();
+            let mut buf = tokio::io::BufWriter::with_capacity(500, file);
+            buf.write_all(b"112233").await.unwrap();
+            buf.write_all(b"112233").await.unwrap();
+            let mut file = buf.into_inner();
+            file.flush().await.unwrap();
+        });
+    }
+
+    #[tokio::test]
+    async fn dedicated_compression_pool() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        };
+
+        #[derive(Default)]
+        struct Stats {
+            active: AtomicUsize,
+            peak: AtomicUsize,
+            threads: Mutex<Vec<Option<String>>>,
+        }
+
+        struct Recording {
+            pool: CompressionPool,
+            stats: Arc<Stats>,
+        }
+
+        impl CompressionExecutor for Recording {
+            fn execute(&self, job: Job) {
+                let stats = self.stats.clone();
+                self.pool.execute(Box::new(move || {
+                    let n = stats.active.fetch_add(1, Ordering::SeqCst) + 1;
+                    stats.peak.fetch_max(n, Ordering::SeqCst);
+                    let name = std::thread::current().name().map(String::from);
+                    stats.threads.lock().unwrap().push(name);
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    job();
+                    stats.active.fetch_sub(1, Ordering::SeqCst);
+                }));
+            }
+        }
+
+        let dir = fresh_dir("dedicated_compression_pool").await;
+        let stats = Arc::new(Stats::default());
+        let executor: Arc<dyn CompressionExecutor> = Arc::new(Recording {
+            pool: CompressionPool::new(1).unwrap(),
+            stats: stats.clone(),
+        });
+
+        let mut files = Vec::new();
+        for i in 0..3 {
+            let file =
+                RotatingFile::builder(dir.join(format!("{}/app.log", i)), RotationMode::Bytes(2))
+                    .compression_executor(executor.clone())
+                    .build()
+                    .await
+                    .unwrap();
+            files.push(file);
+        }
+        futures::future::join_all(files.iter_mut().map(|file| async move {
+            file.write_all(b"11").await.unwrap();
+            file.write_all(b"22").await.unwrap();
+            file.flush().await.unwrap();
+        }))
+        .await;
+
+        let threads = stats.threads.lock().unwrap();
+        assert_eq!(threads.len(), 3);
+        assert_eq!(stats.peak.load(Ordering::SeqCst), 1);
+        assert!(threads
+            .iter()
+            .all(|name| name.as_deref() == Some("rotating-file-compress-0")));
+    }
+
+    #[tokio::test]
+    async fn sequence_naming() {
+        let dir = fresh_dir("sequence_naming").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(2))
+            .sequence(6)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+        file.write_all(b"33").await.unwrap();
+        file.flush().await.unwrap();
+        drop(file);
+
+        // Sequence resumes after the highest existing archive
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(2))
+            .sequence(6)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"44").await.unwrap();
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        let sequences: Vec<_> = archives.iter().map(|a| a.sequence).collect();
+        assert_eq!(sequences, vec![Some(1), Some(2), Some(3)]);
+        for (archive, seq) in archives.iter().zip(1..) {
+            let name = archive.path.file_name().unwrap().to_str().unwrap();
+            assert!(name.starts_with(&format!("app-{:06}-", seq)), "{}", name);
+            assert!(archive.timestamp.is_some());
+            assert!(archive.compressed);
+        }
+    }
+
+    #[tokio::test]
+    async fn total_footprint() {
+        let dir = fresh_dir("total_footprint").await;
+        let path = dir.join("app.log");
+        tokio::fs::write(dir.join("app-2024-01-01_00-00-00.log.gz"), b"12345")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("app-2024-01-02_00-00-00.log"), b"123")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("other-2024-01-02_00-00-00.log"), b"ignored")
+            .await
+            .unwrap();
+
+        let mut file = RotatingFile::new(&path, RotationMode::Bytes(1024))
+            .await
+            .unwrap();
+        file.write_all(b"0123456789").await.unwrap();
+        file.flush().await.unwrap();
+
+        assert_eq!(file.total_footprint().await.unwrap(), 5 + 3 + 10);
+    }
+
+    #[tokio::test]
+    async fn rotate_on_flush() {
+        let dir = fresh_dir("rotate_on_flush").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(4))
+            .rotate_on_write(false)
+            .rotate_on_flush(true)
+            .build()
+            .await
+            .unwrap();
+
+        // The whole batch lands in one file even though it crosses the threshold
+        file.write_all(b"begin\n").await.unwrap();
+        file.write_all(b"commit\n").await.unwrap();
+        assert!(file.archives().await.unwrap().is_empty());
+
+        file.flush().await.unwrap();
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(tokio::fs::metadata(&path).await.unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn latest_archive() {
+        let dir = fresh_dir("latest_archive").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(3)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(file.latest_archive().await.unwrap(), None);
+
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+        file.write_all(b"33").await.unwrap();
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 2);
+        let latest = file.latest_archive().await.unwrap().unwrap();
+        assert_eq!(latest, archives[1].path);
+        let name = latest.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("app-002-"), "{}", name);
+    }
+
+    #[tokio::test]
+    async fn retention_after_rotation() {
+        let dir = fresh_dir("retention_after_rotation").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(3)
+            .retention(RetentionPolicy::any(vec![RetentionRule::KeepCount(2)]))
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            file.write_all(b"11").await.unwrap();
+        }
+        file.flush().await.unwrap();
+
+        let sequences: Vec<_> = file
+            .archives()
+            .await
+            .unwrap()
+            .iter()
+            .map(|a| a.sequence)
+            .collect();
+        assert_eq!(sequences, vec![Some(3), Some(4)]);
+    }
+
+    #[tokio::test]
+    async fn last_write_rotated() {
+        let dir = fresh_dir("last_write_rotated").await;
+        let mut file = RotatingFile::new(dir.join("app.log"), RotationMode::Bytes(4))
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        assert!(!file.last_write_rotated());
+        file.write_all(b"22").await.unwrap();
+        assert!(!file.last_write_rotated());
+        file.write_all(b"33").await.unwrap();
+        assert!(file.last_write_rotated());
+        file.write_all(b"44").await.unwrap();
+        assert!(!file.last_write_rotated());
+    }
+
+    #[tokio::test]
+    async fn max_file_age() {
+        let dir = fresh_dir("max_file_age").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1024))
+            .sequence(1)
+            .max_file_age(std::time::Duration::from_millis(50))
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"a").await.unwrap();
+        file.write_all(b"b").await.unwrap();
+        assert!(!file.last_write_rotated());
+
+        tokio::time::sleep(std::time::Duration::from_millis(80)).await;
+        file.write_all(b"c").await.unwrap();
+        assert!(file.last_write_rotated());
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+
+        // An idle file is rotated by the timer of a shared one
+        let shared = SharedRotatingFile::new(file);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        shared.close().await.unwrap();
+        let archives = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name() != "app.log")
+            .count();
+        assert_eq!(archives, 2);
+        assert_eq!(std::fs::read(dir.join("app.log")).unwrap(), b"");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn rotation_strategies() {
+        use std::os::unix::fs::MetadataExt;
+
+        for (strategy, same_inode) in [
+            (RotationStrategy::RenameReopen, false),
+            (RotationStrategy::CopyTruncate, true),
+        ] {
+            let dir = fresh_dir(&format!("rotation_strategies_{:?}", strategy)).await;
+            let path = dir.join("app.log");
+            let mut file = RotatingFile::builder(&path, RotationMode::Bytes(2))
+                .strategy(strategy)
+                .build()
+                .await
+                .unwrap();
+            file.write_all(b"11").await.unwrap();
+            let before = std::fs::metadata(&path).unwrap().ino();
+            file.write_all(b"22").await.unwrap();
+            file.flush().await.unwrap();
+            let after = std::fs::metadata(&path).unwrap().ino();
+
+            assert_eq!(before == after, same_inode, "{:?}", strategy);
+            assert_eq!(std::fs::read(&path).unwrap(), b"22");
+            assert_eq!(file.archives().await.unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn repair() {
+        let dir = fresh_dir("repair").await;
+        let plain = dir.join("app-001-2024-01-01_00-00-00.log");
+        let temp = dir.join("app-002-2024-01-02_00-00-00.log.gz.tmp");
+        tokio::fs::write(&plain, b"left behind").await.unwrap();
+        tokio::fs::write(&temp, b"half written").await.unwrap();
+        tokio::fs::write(dir.join("app-004-2024-01-04_00-00-00.log.gz"), b"")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("unrelated.tmp"), b"")
+            .await
+            .unwrap();
+
+        let file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1024))
+            .sequence(3)
+            .build()
+            .await
+            .unwrap();
+        let report = file.repair().await.unwrap();
+
+        assert_eq!(report.removed_temp, vec![temp.clone()]);
+        assert_eq!(
+            report.compressed,
+            vec![archive::Naming::default().output_path(&Codec::default(), &plain)]
+        );
+        assert_eq!(report.missing_sequences, vec![2, 3]);
+        assert!(!temp.exists() && !plain.exists());
+        assert!(dir.join("unrelated.tmp").exists());
+        assert!(file.archives().await.unwrap().iter().all(|a| a.compressed));
+    }
+
+    #[tokio::test]
+    async fn rotate_by_writes() {
+        let dir = fresh_dir("rotate_by_writes").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Writes(2))
+            .sequence(1)
+            .build()
+            .await
+            .unwrap();
+        let message = b"first line\nsecond line\nthird line\n";
+        for _ in 0..5 {
+            file.write_all(message).await.unwrap();
+        }
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 2);
+        assert_eq!(gunzip(&archives[0].path), message.repeat(2));
+    }
+
+    #[tokio::test]
+    async fn directory() {
+        let dir = fresh_dir("directory").await;
+        let file = RotatingFile::new(dir.join("app.log"), RotationMode::Bytes(2))
+            .await
+            .unwrap();
+        assert_eq!(file.directory(), dir);
+
+        let absolute = std::fs::canonicalize(&dir).unwrap();
+        let file = RotatingFile::new(absolute.join("abs.log"), RotationMode::Bytes(2))
+            .await
+            .unwrap();
+        assert_eq!(file.directory(), absolute);
+
+        assert_eq!(archive::directory(Path::new("app.log")), Path::new("."));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn rotate_on_json_change() {
+        let dir = fresh_dir("rotate_on_json_change").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1 << 20))
+            .sequence(1)
+            .rotate_on_json_change("/session")
+            .build()
+            .await
+            .unwrap();
+        let a1 = b"{\"session\":\"a\",\"n\":1}\n";
+        let a2 = b"{\"session\":\"a\",\"n\":2}\n";
+        let b1 = b"{\"session\":\"b\",\"n\":3}\n";
+        file.write_all(a1).await.unwrap();
+        // A line split across writes is judged once complete
+        file.write_all(&a2[..5]).await.unwrap();
+        file.write_all(&[&a2[5..], &b1[..]].concat()).await.unwrap();
+        file.write_all(b"not json\n").await.unwrap();
+        file.write_all(a1).await.unwrap();
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 2);
+        assert_eq!(gunzip(&archives[0].path), [&a1[..], &a2[..]].concat());
+        assert_eq!(gunzip(&archives[1].path), [&b1[..], b"not json\n"].concat());
+        assert_eq!(std::fs::read(dir.join("app.log")).unwrap(), a1);
+    }
+
+    #[cfg(feature = "regex")]
+    #[tokio::test]
+    async fn rotate_after_match() {
+        let dir = fresh_dir("rotate_after_match").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1 << 20))
+            .rotate_after_match(regex::Regex::new("^=== RESTART ===$").unwrap())
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"a\n=== RES").await.unwrap();
+        file.write_all(b"TART ===\r\nb\n").await.unwrap();
+        file.write_all(b"c\n").await.unwrap();
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(gunzip(&archives[0].path), b"a\n=== RESTART ===\r\n");
+        assert_eq!(std::fs::read(dir.join("app.log")).unwrap(), b"b\nc\n");
+    }
+
+    /// Runs each job on its own thread after a delay.
+    pub(crate) struct Slow(pub(crate) std::time::Duration);
+
+    impl CompressionExecutor for Slow {
+        fn execute(&self, job: Job) {
+            let delay = self.0;
+            std::thread::spawn(move || {
+                std::thread::sleep(delay);
+                job()
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn background_compression() {
+        let dir = fresh_dir("background_compression").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .background_compression(true)
+            .compression_executor(Arc::new(Slow(std::time::Duration::from_millis(200))))
+            .build()
+            .await
+            .unwrap();
+        let started = Instant::now();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_millis(200));
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert!(!archives[0].compressed);
+
+        file.close().await.unwrap();
+        assert!(started.elapsed() >= std::time::Duration::from_millis(200));
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(gunzip(&archives[0].path), b"11");
+    }
+
+    #[tokio::test]
+    async fn archive_dir() {
+        let dir = fresh_dir("archive_dir").await;
+        let archives = dir.join("archives");
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .archive_dir(&archives)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+        file.flush().await.unwrap();
+
+        let listed = file.archives().await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].path.parent().unwrap(), archives);
+        assert_eq!(gunzip(&listed[0].path), b"11");
+    }
+
+    #[tokio::test]
+    async fn rotation_veto() {
+        let dir = fresh_dir("rotation_veto").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .rotation_veto(|stats| stats.writes >= 3)
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            file.write_all(b"11").await.unwrap();
+            assert!(!file.last_write_rotated());
+        }
+        file.write_all(b"22").await.unwrap();
+        assert!(file.last_write_rotated());
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(gunzip(&archives[0].path), b"111111");
+    }
+
+    #[tokio::test]
+    async fn new_with_counts() {
+        let dir = fresh_dir("new_with_counts").await;
+        let path = dir.join("app.log");
+        std::fs::write(&path, b"ab").unwrap();
+        let mut file = RotatingFile::new_with_counts(&path, RotationMode::Lines(3), 2, 100)
+            .await
+            .unwrap();
+        file.write_all(b"x\n").await.unwrap();
+        file.write_all(b"y\n").await.unwrap();
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(gunzip(&archives[0].path), b"abx\n");
+    }
+
+    #[tokio::test]
+    async fn line_ending() {
+        let dir = fresh_dir("line_ending").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Lines(100))
+            .line_ending(LineEnding::Lf)
+            .build()
+            .await
+            .unwrap();
+        for chunk in [&b"a\r\nb\n"[..], b"c\r", b"\nd\r\n"].iter() {
+            file.write_all(chunk).await.unwrap();
+        }
+        file.flush().await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"a\nb\nc\nd\n");
+        let counters = file.counters();
+        assert_eq!((counters.lines, counters.bytes), (4, 8));
+    }
+
+    #[tokio::test]
+    async fn naming_template() {
+        let dir = fresh_dir("naming_template").await;
+        let day = Local::now().format("%Y-%m-%d").to_string();
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .naming_template("{stem}.{date}.{ext}.{CODEC}")
+            .timestamp_format("%Y-%m-%d")
+            .build()
+            .await
+            .unwrap();
+        for chunk in [b"11", b"22", b"33"].iter() {
+            file.write_all(*chunk).await.unwrap();
+        }
+
+        let archives = file.archives().await.unwrap();
+        let names: Vec<_> = archives
+            .iter()
+            .map(|a| a.path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                format!("app.{}.log.GZ", day),
+                format!("app.{}.1.log.GZ", day)
+            ]
+        );
+        assert_eq!(gunzip(&archives[1].path), b"22");
+
+        let err = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .naming_template("{stem}.{ext}.{seq}")
+            .build()
+            .await
+            .err()
+            .unwrap();
+        let config = err
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<ConfigError>()
+            .unwrap();
+        // No {codec}, no {date}, and {seq} without a sequence
+        assert_eq!(config.problems.len(), 3, "{:?}", config.problems);
+    }
+
+    #[tokio::test]
+    async fn rotation_cooldown() {
+        let dir = fresh_dir("rotation_cooldown").await;
+        let day = Local::now().format("%Y-%m-%d").to_string();
+        std::fs::write(dir.join(format!("app-{}.log.gz", day)), b"old").unwrap();
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(2))
+            .on_collision(Collision::Fail)
+            .rotation_cooldown(Duration::from_millis(100))
+            .timestamp_format("%Y-%m-%d")
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+
+        // Each failure holds rotation back while the file keeps growing
+        let wait = Duration::from_millis(120);
+        assert!(file.write_all(b"22").await.is_err());
+        file.write_all(b"22").await.unwrap();
+        tokio::time::sleep(wait).await;
+        assert!(file.write_all(b"33").await.is_err());
+        file.write_all(b"33").await.unwrap();
+        tokio::time::sleep(wait).await;
+        file.write_all(b"44").await.unwrap();
+        tokio::time::sleep(wait).await;
+        assert!(file.write_all(b"55").await.is_err());
+
+        file.flush().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"11223344");
+        assert_eq!(file.counters().bytes, 8);
+    }
+
+    #[tokio::test]
+    async fn read_active_snapshot() {
+        let dir = fresh_dir("read_active_snapshot").await;
+        let mut file = RotatingFile::new(dir.join("app.log"), RotationMode::Bytes(100))
+            .await
+            .unwrap();
+        file.write_all(b"before\n").await.unwrap();
+        file.flush().await.unwrap();
+        let mut snapshot = file.read_active_snapshot().await.unwrap();
+        file.write_all(b"after\n").await.unwrap();
+        file.flush().await.unwrap();
+
+        let mut read = Vec::new();
+        snapshot.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"before\n");
+    }
+
+    #[tokio::test]
+    async fn open_retry() {
+        let mut failures = 1u32;
+        let retry = Some((2, Duration::from_millis(1)));
+        let opened = retry_transient(retry, || {
+            let result = match failures {
+                0 => Ok("opened"),
+                _ => Err(Error::from(ErrorKind::WouldBlock)),
+            };
+            failures = failures.saturating_sub(1);
+            futures::future::ready(result)
+        })
+        .await;
+        assert_eq!(opened.unwrap(), "opened");
+
+        // Permanent errors and exhausted attempts are returned as is
+        let missing = retry_transient(retry, || {
+            futures::future::ready(Err::<(), _>(Error::from(ErrorKind::NotFound)))
+        });
+        assert_eq!(missing.await.unwrap_err().kind(), ErrorKind::NotFound);
+        let locked = retry_transient(retry, || {
+            futures::future::ready(Err::<(), _>(Error::from(ErrorKind::WouldBlock)))
+        });
+        assert_eq!(locked.await.unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[tokio::test]
+    async fn snapshot_rotate() {
+        let dir = fresh_dir("snapshot_rotate").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Lines(1))
+            .sequence(1)
+            .background_compression(true)
+            .retention(RetentionPolicy::any(vec![RetentionRule::KeepCount(1)]))
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"first").await.unwrap();
+        let snapshot = file.snapshot_rotate().await.unwrap();
+        assert_eq!(gunzip(&snapshot), b"first");
+
+        // Retention keeps one archive besides the pinned snapshot
+        for line in [b"a\n", b"b\n", b"c\n"].iter() {
+            file.write_all(*line).await.unwrap();
+        }
+        file.await_compressions().await.unwrap();
+        assert_eq!(gunzip(&snapshot), b"first");
+        assert_eq!(file.archives().await.unwrap().len(), 2);
+
+        assert!(file.release_snapshot(&snapshot));
+        file.write_all(b"d\n").await.unwrap();
+        file.await_compressions().await.unwrap();
+        assert!(!snapshot.exists());
+    }
+
+    #[tokio::test]
+    async fn compress_if() {
+        let dir = fresh_dir("compress_if").await;
+        let mut file = RotatingFile::builder(dir.join("capture.bin"), RotationMode::Bytes(4))
+            .sequence(1)
+            .compress_if(|path| {
+                let mut magic = [0; 2];
+                let read = std::fs::File::open(path)
+                    .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut magic));
+                read.is_err() || magic != [0x1f, 0x8b]
+            })
+            .build()
+            .await
+            .unwrap();
+        let gzipped = [0x1f, 0x8b, 0x08, 0x00];
+        for chunk in [&gzipped[..], b"text", b"next"].iter() {
+            file.write_all(chunk).await.unwrap();
+        }
+        assert_eq!(file.compress_pending().await.unwrap(), 0);
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 2);
+        assert!(!archives[0].compressed);
+        assert_eq!(std::fs::read(&archives[0].path).unwrap(), gzipped);
+        assert!(archives[1].compressed);
+        assert_eq!(gunzip(&archives[1].path), b"text");
+    }
+
+    #[tokio::test]
+    async fn compress_active() {
+        let dir = fresh_dir("compress_active").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(10))
+            .compress_active(true)
+            .build()
+            .await
+            .unwrap();
+        let decoded = |path: &Path| {
+            let mut decoded = Vec::new();
+            let input = std::fs::File::open(path).unwrap();
+            std::io::Read::read_to_end(&mut flate2::read::MultiGzDecoder::new(input), &mut decoded)
+                .unwrap();
+            decoded
+        };
+        file.write_all(b"hello\n").await.unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(decoded(&path), b"hello\n");
+        file.write_all(b"world\n").await.unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(decoded(&path), b"hello\nworld\n");
+        assert_eq!(file.counters().bytes, 12);
+
+        file.write_all(b"next\n").await.unwrap();
+        file.flush().await.unwrap();
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert!(archives[0].compressed);
+        assert_eq!(decoded(&archives[0].path), b"hello\nworld\n");
+        assert_eq!(decoded(&path), b"next\n");
+
+        // Reopening counts what the file decodes to
+        file.close().await.unwrap();
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(10))
+            .compress_active(true)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(file.counters().bytes, 5);
+    }
+
+    #[tokio::test]
+    async fn auto_line_counting() {
+        let dir = fresh_dir("auto_line_counting").await;
+        let path = dir.join("app.log");
+        std::fs::write(&path, b"one\r\ntwo\r\n").unwrap();
+        let mut file = RotatingFile::builder(&path, RotationMode::Lines(100))
+            .line_counting(LineCounting::Auto)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(file.counters().lines, 2);
+        // Neither the bare \n nor the bare \r ends a record, and a \r\n split
+        // across writes still does
+        for chunk in [&b"multi\nline\r"[..], b"\n", b"x\ry\r\n"].iter() {
+            file.write_all(chunk).await.unwrap();
+        }
+        assert_eq!(file.counters().lines, 4);
+    }
+
+    #[tokio::test]
+    async fn line_timestamps() {
+        let dir = fresh_dir("line_timestamps").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(100))
+            .line_timestamps("%H:%M:%S")
+            .build()
+            .await
+            .unwrap();
+        for chunk in [&b"one\ntw"[..], b"o\n", b"three\n"].iter() {
+            file.write_all(chunk).await.unwrap();
+        }
+        file.flush().await.unwrap();
+
+        let written = String::from_utf8(std::fs::read(&path).unwrap()).unwrap();
+        let lines: Vec<_> = written.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for (line, text) in lines.iter().zip(["one", "two", "three"].iter()) {
+            let (stamp, rest) = line.split_once(' ').unwrap();
+            assert!(NaiveTime::parse_from_str(stamp, "%H:%M:%S").is_ok());
+            assert_eq!(rest, *text);
+        }
+        // Each line grew by the 9 byte prefix
+        assert_eq!(file.counters().bytes, 14 + 3 * 9);
+    }
+
+    #[tokio::test]
+    async fn max_line_bytes() {
+        let dir = fresh_dir("max_line_bytes").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(100))
+            .line_ending(LineEnding::Lf)
+            .max_line_bytes(8, false)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(&[b'x'; 20]).await.unwrap();
+        file.flush().await.unwrap();
+
+        // Whole chunks of the cap went out, the rest waits for its newline
+        assert_eq!(std::fs::read(&path).unwrap(), [b'x'; 16]);
+        assert_eq!(file.lines.buffered(), 4);
+        file.write_all(b"\n").await.unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap().len(), 21);
+    }
+
+    #[tokio::test]
+    async fn chars_mode() {
+        let dir = fresh_dir("chars_mode").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Chars(4))
+            .sequence(1)
+            .codec(Codec::None)
+            .build()
+            .await
+            .unwrap();
+        // Four characters in twelve bytes, the last one split between writes
+        let record = "€€€€".as_bytes();
+        file.write_all(&record[..7]).await.unwrap();
+        file.write_all(&record[7..]).await.unwrap();
+        assert!(file.archives().await.unwrap().is_empty());
+        file.write_all("ß".as_bytes()).await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(std::fs::read(&archives[0].path).unwrap(), record);
+        assert_eq!(file.counters().chars, 1);
+
+        // The threshold is reached on the third character's first byte, but
+        // the file only rotates once the character is whole
+        let dir = fresh_dir("chars_mode_split").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Chars(3))
+            .sequence(1)
+            .codec(Codec::None)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(&record[..7]).await.unwrap();
+        file.write_all(&record[7..]).await.unwrap();
+        file.write_all(b"x").await.unwrap();
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(std::fs::read(&archives[0].path).unwrap(), record);
+        assert_eq!(std::fs::read(dir.join("app.log")).unwrap(), b"x");
+    }
+
+    #[tokio::test]
+    async fn archive_timestamp() {
+        let dir = fresh_dir("archive_timestamp").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Lines(2))
+            .archive_timestamp(|path| {
+                let text = std::fs::read_to_string(path).ok()?;
+                let stamp = text.lines().last()?.split(' ').next()?;
+                let time = NaiveDateTime::parse_from_str(stamp, "%Y-%m-%dT%H:%M:%S").ok()?;
+                Local.from_local_datetime(&time).earliest()
+            })
+            .build()
+            .await
+            .unwrap();
+        for line in [
+            "2020-03-04T05:06:07 first",
+            "2020-03-04T05:07:08 second",
+            "now",
+        ]
+        .iter()
+        {
+            file.write_all(format!("{}\n", line).as_bytes())
+                .await
+                .unwrap();
+        }
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        let event = NaiveDate::from_ymd_opt(2020, 3, 4)
+            .and_then(|d| d.and_hms_opt(5, 7, 8))
+            .unwrap();
+        assert_eq!(archives[0].timestamp, Some(event));
+    }
+
+    #[tokio::test]
+    async fn write_rate_limit() {
+        let dir = fresh_dir("write_rate_limit").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1 << 20))
+            .write_rate_limit(100_000)
+            .build()
+            .await
+            .unwrap();
+        let started = Instant::now();
+        file.write_all(&[b'x'; 50_000]).await.unwrap();
+        file.flush().await.unwrap();
+
+        // A burst of 10 kB goes right away, the other 40 kB take 0.4 s
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(380), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+        assert_eq!(file.counters().bytes, 50_000);
+    }
+
+    #[tokio::test]
+    async fn simultaneous_triggers() {
+        let dir = fresh_dir("simultaneous_triggers").await;
+        let mode = RotationMode::All(vec![RotationMode::Bytes(4), RotationMode::Lines(2)]);
+        let mut file = RotatingFile::builder(dir.join("app.log"), mode)
+            .sequence(1)
+            .build()
+            .await
+            .unwrap();
+        // Both thresholds are crossed by the same write
+        file.write_all(b"a\nb\n").await.unwrap();
+        file.write_all(b"c\n").await.unwrap();
+        file.write_all(b"d\n").await.unwrap();
+
+        assert_eq!(file.archives().await.unwrap().len(), 1);
+        assert_eq!(
+            file.last_rotation_reasons(),
+            [RotationReason::Bytes, RotationReason::Lines]
+        );
+    }
+
+    #[tokio::test]
+    async fn block_alignment() {
+        let dir = fresh_dir("block_alignment").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1000))
+            .sequence(1)
+            .block_alignment(512, true)
+            .build()
+            .await
+            .unwrap();
+        // Rounded up to 1024 bytes
+        for _ in 0..10 {
+            file.write_all(&[b'x'; 100]).await.unwrap();
+        }
+        assert!(file.archives().await.unwrap().is_empty());
+        for _ in 0..13 {
+            file.write_all(&[b'x'; 100]).await.unwrap();
+        }
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 2);
+        for archive in &archives {
+            let data = gunzip(&archive.path);
+            assert_eq!(data.len() % 512, 0);
+            assert_eq!(data.iter().filter(|b| **b == b'x').count(), 1100);
+        }
+    }
+
+    #[tokio::test]
+    async fn recreate_missing_dir() {
+        let dir = fresh_dir("recreate_missing_dir").await.join("logs");
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(1024))
+            .recreate_missing_dir(true)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"before\n").await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        tokio::time::sleep(MISSING_CHECK_INTERVAL).await;
+        file.write_all(b"after\n").await.unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"after\n");
+    }
+
+    #[tokio::test]
+    async fn rotate_when() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let dir = fresh_dir("rotate_when").await;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1 << 20))
+            .sequence(1)
+            .rotate_when(Duration::from_secs(1), move |stats| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                stats.lines >= 2
+            })
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            file.write_all(b"line\n").await.unwrap();
+        }
+        // Only the first write asked, before there were two lines
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(file.archives().await.unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        file.write_all(b"line\n").await.unwrap();
+        file.write_all(b"line\n").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(file.archives().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rotate_on_shutdown() {
+        let dir = fresh_dir("rotate_on_shutdown").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Lines(10))
+            .line_ending(LineEnding::Lf)
+            .rotate_on_shutdown(true)
+            .build()
+            .await
+            .unwrap();
+        // The unterminated line is still held by the line buffer
+        file.write_all(b"one\ntwo").await.unwrap();
+        file.shutdown().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(gunzip(&archives[0].path), b"one\ntwo");
+        assert_eq!(std::fs::read(&path).unwrap(), b"");
+    }
+
+    #[tokio::test]
+    async fn all_thresholds() {
+        let dir = fresh_dir("all_thresholds").await;
+        let mode = RotationMode::All(vec![RotationMode::Bytes(10), RotationMode::Lines(2)]);
+        let mut file = RotatingFile::builder(dir.join("app.log"), mode)
+            .codec(Codec::None)
+            .build()
+            .await
+            .unwrap();
+        // Long lines pass the byte threshold first, then short ones the line
+        // threshold
+        file.write_all(b"0123456789ab\n").await.unwrap();
+        file.write_all(b"x").await.unwrap();
+        assert!(file.archives().await.unwrap().is_empty());
+        file.write_all(b"\n").await.unwrap();
+        file.write_all(b"y").await.unwrap();
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(
+            std::fs::read(&archives[0].path).unwrap(),
+            b"0123456789ab\nx\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn on_swap() {
+        use std::{io::Read, sync::Mutex};
+
+        let dir = fresh_dir("on_swap").await;
+        let path = dir.join("app.log");
+        std::fs::write(&path, b"").unwrap();
+        let tail = Arc::new(Mutex::new((
+            std::fs::File::open(&path).unwrap(),
+            Vec::new(),
+        )));
+        let follower = tail.clone();
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(2))
+            .sequence(1)
+            .on_swap(move |hot, _| {
+                // Finish the old file, then follow the hot path to the new one
+                let (reader, seen) = &mut *follower.lock().unwrap();
+                reader.read_to_end(seen).unwrap();
+                *reader = std::fs::File::open(hot).unwrap();
+            })
+            .build()
+            .await
+            .unwrap();
+        for chunk in [b"ab", b"cd", b"ef", b"gh"].iter() {
+            file.write_all(*chunk).await.unwrap();
+        }
+        file.flush().await.unwrap();
+        assert_eq!(file.archives().await.unwrap().len(), 3);
+
+        let (reader, seen) = &mut *tail.lock().unwrap();
+        reader.read_to_end(seen).unwrap();
+        assert_eq!(seen, b"abcdefgh");
+    }
+
+    #[tokio::test]
+    async fn daily_boundary() {
+        let dir = fresh_dir("daily_boundary").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(100))
+            .daily_boundary(true)
+            .trigger_in_name(true)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+        assert!(file.archives().await.unwrap().is_empty());
+
+        file.opened_on = file.opened_on.pred_opt().unwrap();
+        file.write_all(b"33").await.unwrap();
+        assert!(file.last_write_rotated());
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert!(archives[0].path.to_str().unwrap().contains("-day."));
+        assert_eq!(gunzip(&archives[0].path), b"1122");
+    }
+
+    #[tokio::test]
+    async fn date_directories() {
+        let dir = fresh_dir("date_directories").await;
+        let yesterday = (Local::now() - chrono::Duration::days(1)).format("%Y-%m-%d");
+        let old = dir.join(yesterday.to_string());
+        std::fs::create_dir(&old).unwrap();
+        let old = old.join(format!("app-{}_23-59-59.log.gz", yesterday));
+        std::fs::write(&old, b"old").unwrap();
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .date_directories("%Y-%m-%d")
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+
+        let today = dir.join(Local::now().format("%Y-%m-%d").to_string());
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 2);
+        assert_eq!(archives[0].path, old);
+        assert_eq!(archives[1].path.parent(), Some(today.as_path()));
+        assert_eq!(gunzip(&archives[1].path), b"11");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn latest_link() {
+        let dir = fresh_dir("latest_link").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(1)
+            .latest_link(true)
+            .build()
+            .await
+            .unwrap();
+        for chunk in [b"11", b"22", b"33", b"44"].iter() {
+            file.write_all(*chunk).await.unwrap();
+            file.await_compressions().await.unwrap();
+        }
+
+        let link = dir.join("app.latest.log.gz");
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 3);
+        let target = std::fs::read_link(&link).unwrap();
+        assert_eq!(Some(target.as_os_str()), archives[2].path.file_name());
+        assert_eq!(gunzip(&link), b"33");
+    }
+
+    #[test]
+    fn inode_exhaustion() {
+        let path = Path::new("app.log.next");
+        let err = diagnose_create(ErrorKind::StorageFull.into(), path);
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert!(err.to_string().contains("app.log.next"), "{}", err);
+        assert!(err.to_string().contains("out of inodes"), "{}", err);
+        let err = diagnose_create(ErrorKind::PermissionDenied.into(), path);
+        assert!(!err.to_string().contains("inodes"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn next_file_before_move() {
+        struct Failing;
+
+        impl Compressor for Failing {
+            fn encode(
+                &self,
+                _: &mut dyn std::io::Read,
+                _: &mut dyn std::io::Write,
+            ) -> Result<(), Error> {
+                Err(Error::other("encoder failed"))
+            }
+
+            fn extension(&self) -> &str {
+                "fail"
+            }
+        }
+
+        let dir = fresh_dir("next_file_before_move").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(2))
+            .codec(Codec::Custom(Arc::new(Failing)))
+            .direct_compression(true)
+            .rotation_cooldown(Duration::from_secs(60))
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        let err = file.write_all(b"22").await.unwrap_err();
+        assert!(err.to_string().contains("encoder failed"), "{}", err);
+        // The next file was in place before the compression that failed
+        assert!(dir.join("app.log.next").exists());
+
+        // The active file never moved, and takes writes until the cooldown ends
+        file.write_all(b"33").await.unwrap();
+        file.flush().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"1133");
+        assert!(file.archives().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn name_collision() {
+        let dir = fresh_dir("name_collision").await;
+        let day = Local::now().format("%Y-%m-%d").to_string();
+        let taken = dir.join(format!("app-{}.log.gz", day));
+        std::fs::write(&taken, b"old").unwrap();
+        let builder = || {
+            RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+                .timestamp_format("%Y-%m-%d")
+        };
+        let mut file = builder().build().await.unwrap();
+        for chunk in [b"11", b"22", b"33"].iter() {
+            file.write_all(*chunk).await.unwrap();
+        }
+
+        assert_eq!(std::fs::read(&taken).unwrap(), b"old");
+        let archives = file.archives().await.unwrap();
+        let names: Vec<_> = archives
+            .iter()
+            .map(|a| a.path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                format!("app-{}.log.gz", day),
+                format!("app-{}.1.log.gz", day),
+                format!("app-{}.2.log.gz", day),
+            ]
+        );
+        assert_eq!(gunzip(&archives[2].path), b"22");
+
+        file.close().await.unwrap();
+        let mut file = builder()
+            .on_collision(Collision::Fail)
+            .build()
+            .await
+            .unwrap();
+        let err = file.write_all(b"44").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn resync_counts() {
+        let dir = fresh_dir("resync_counts").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::new(&path, RotationMode::Lines(100))
+            .await
+            .unwrap();
+        file.write_all(b"a\n").await.unwrap();
+        file.flush().await.unwrap();
+        std::io::Write::write_all(
+            &mut std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap(),
+            b"bb\ncc\n",
+        )
+        .unwrap();
+        assert_eq!(file.counters().lines, 1);
+
+        file.resync_counts().await.unwrap();
+        let counters = file.counters();
+        assert_eq!((counters.lines, counters.bytes), (3, 8));
+    }
+
+    #[tokio::test]
+    async fn zero_byte_write() {
+        let dir = fresh_dir("zero_byte_write").await;
+        let mut file = RotatingFile::new(dir.join("app.log"), RotationMode::Writes(1))
+            .await
+            .unwrap();
+        file.write_all(b"1").await.unwrap();
+        let before = file.counters();
+        assert_eq!(file.write(b"").await.unwrap(), 0);
+        file.write_all(b"").await.unwrap();
+        assert_eq!(file.counters(), before);
+        assert!(!file.last_write_rotated());
+        assert!(file.archives().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn skip_startup_scan() {
+        let dir = fresh_dir("skip_startup_scan").await;
+        let path = dir.join("app.log");
+        std::fs::write(&path, vec![b'\n'; 1 << 20]).unwrap();
+
+        let mut file = RotatingFile::builder(&path, RotationMode::Lines(10))
+            .skip_startup_scan()
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(file.counters(), Counters::default());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 1 << 20);
+        drop(file);
+
+        let mut file = RotatingFile::builder(&path, RotationMode::Lines(10))
+            .skip_startup_scan()
+            .truncate_on_open(true)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(file.counters(), Counters::default());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn max_rotations() {
+        let dir = fresh_dir("max_rotations").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(1)
+            .max_rotations(3, std::time::Duration::from_secs(3600))
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            file.write_all(b"11").await.unwrap();
+        }
+        file.flush().await.unwrap();
+
+        assert_eq!(file.archives().await.unwrap().len(), 3);
+        assert_eq!(file.times_throttled(), 1);
+        let active = std::fs::read(dir.join("app.log")).unwrap();
+        assert_eq!(active.len(), 2 * 47);
+    }
+
+    #[tokio::test]
+    async fn trigger_in_name() {
+        let dir = fresh_dir("trigger_in_name").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(1)
+            .trigger_in_name(true)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+        file.flush().await.unwrap();
+
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        let name = archives[0].path.file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with("-bytes.log.gz"), "{}", name);
+        assert!(archives[0].timestamp.is_some());
+    }
+
+    #[tokio::test]
+    async fn flush_strategies() {
+        use std::time::Duration;
+
+        for (strategy, after_writes, after_flush) in [
+            (FlushStrategy::Manual, 0, 1),
+            (FlushStrategy::EveryWrite, 3, 4),
+            (FlushStrategy::BeforeRotation, 0, 0),
+        ] {
+            let dir = fresh_dir(&format!("flush_strategies_{:?}", strategy)).await;
+            let path = dir.join("app.log");
+            let mut file = RotatingFile::builder(&path, RotationMode::Bytes(1024))
+                .flush_strategy(strategy)
+                .build()
+                .await
+                .unwrap();
+            for _ in 0..3 {
+                file.write_all(b"11").await.unwrap();
+            }
+            assert_eq!(file.flushes, after_writes, "{:?}", strategy);
+            if strategy == FlushStrategy::EveryWrite {
+                assert_eq!(std::fs::read(&path).unwrap(), b"111111");
+            }
+            file.flush().await.unwrap();
+            assert_eq!(file.flushes, after_flush, "{:?}", strategy);
+        }
+
+        let dir = fresh_dir("flush_strategies_interval").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(1024))
+            .flush_strategy(FlushStrategy::Interval(Duration::from_millis(50)))
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+        assert_eq!(file.flushes, 0);
+        std::thread::sleep(Duration::from_millis(80));
+        file.write_all(b"33").await.unwrap();
+        file.write_all(b"44").await.unwrap();
+        assert_eq!(file.flushes, 1);
+    }
+
+    #[tokio::test]
+    async fn staging_dir() {
+        let dir = fresh_dir("staging_dir").await;
+        let staging = dir.join("staging");
+        let published = dir.join("published");
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .archive_dir(&published)
+            .staging_dir(&staging)
+            .background_compression(true)
+            .compression_executor(Arc::new(Slow(std::time::Duration::from_millis(100))))
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+        assert!(file.archives().await.unwrap().is_empty());
+        assert_eq!(std::fs::read_dir(&staging).unwrap().count(), 1);
+
+        file.close().await.unwrap();
+        assert_eq!(std::fs::read_dir(&staging).unwrap().count(), 0);
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(std::fs::read_dir(&published).unwrap().count(), 1);
+        assert_eq!(gunzip(&archives[0].path), b"11");
+    }
+
+    #[tokio::test]
+    async fn done_markers() {
+        let dir = fresh_dir("done_markers").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(1)
+            .done_markers(true)
+            .retention(RetentionPolicy::any(vec![RetentionRule::KeepCount(2)]))
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            file.write_all(b"11").await.unwrap();
+        }
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 2);
+        for archive in &archives {
+            assert!(archive::done_marker(&archive.path).exists());
+        }
+        let markers = std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().unwrap() == "done")
+            .count();
+        assert_eq!(markers, 2);
+    }
+
+    #[tokio::test]
+    async fn retention_holds_during_compression() {
+        let dir = fresh_dir("retention_holds_during_compression").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(1)
+            .retention(RetentionPolicy::any(vec![RetentionRule::KeepCount(2)]))
+            .background_compression(true)
+            .compression_executor(Arc::new(Slow(std::time::Duration::from_millis(20))))
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..20 {
+            file.write_all(b"11").await.unwrap();
+            // An archive briefly exists both plain and compressed
+            let mut archives: Vec<_> = file
+                .archives()
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|a| a.sequence)
+                .collect();
+            archives.dedup();
+            assert!(archives.len() <= 2, "{:?}", archives);
+        }
+        file.await_compressions().await.unwrap();
+        assert_eq!(file.archives().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn await_compressions() {
+        let dir = fresh_dir("await_compressions").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(1)
+            .background_compression(true)
+            .compression_executor(Arc::new(Slow(std::time::Duration::from_millis(100))))
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            file.write_all(b"11").await.unwrap();
+        }
+        assert_eq!(file.pending_compressions(), 3);
+
+        file.await_compressions().await.unwrap();
+        assert_eq!(file.pending_compressions(), 0);
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 3);
+        assert!(archives.iter().all(|a| a.compressed));
+    }
+
+    #[test]
+    fn adaptive_threshold_converges() {
+        use std::time::Duration;
+
+        // 1000 bytes/s in 10 byte writes, aiming for 6 files a minute
+        let target = Duration::from_secs(60) / 6;
+        let (mut threshold, mut bytes, mut opened) = (100, 0, 0u64);
+        let mut rotations = Vec::new();
+        for ms in (10..3_600_000u64).step_by(10) {
+            if bytes >= threshold {
+                let elapsed = Duration::from_millis(ms - opened);
+                threshold = adapt_threshold(threshold, bytes, elapsed, target);
+                rotations.push(ms);
+                bytes = 0;
+                opened = ms;
+            }
+            bytes += 10;
+        }
+        let last_window = rotations.iter().filter(|ms| **ms > 3_540_000).count();
+        assert!((5..=7).contains(&last_window), "{}", last_window);
+        assert!((9_000..=11_000).contains(&threshold), "{}", threshold);
+    }
+
+    #[tokio::test]
+    async fn compress_pending() {
+        let dir = fresh_dir("compress_pending").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .sequence(1)
+            .defer_compression(true)
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..4 {
+            file.write_all(b"11").await.unwrap();
+        }
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 3);
+        assert!(archives.iter().all(|a| !a.compressed));
+
+        assert_eq!(file.compress_pending().await.unwrap(), 3);
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 3);
+        assert!(archives.iter().all(|a| a.compressed));
+        assert_eq!(file.compress_pending().await.unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dir_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = fresh_dir("dir_mode").await;
+        let logs = dir.join("logs");
+        RotatingFile::builder(logs.join("app.log"), RotationMode::Bytes(2))
+            .archive_dir(dir.join("archives"))
+            .dir_mode(0o700)
+            .build()
+            .await
+            .unwrap();
+        for created in [logs, dir.join("archives")] {
+            let mode = std::fs::metadata(&created).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700, "{}", created.display());
+        }
+    }
+
+    #[tokio::test]
+    async fn checkpoint_truncated() {
+        let dir = fresh_dir("checkpoint_truncated").await;
+        let path = dir.join("app.log");
+        let open = || {
+            RotatingFile::builder(&path, RotationMode::Bytes(1024))
+                .checkpoint(true)
+                .build()
+        };
+        let mut file = open().await.unwrap();
+        file.write_all(b"one\ntwo\nthree\n").await.unwrap();
+        file.close().await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("app.log.checkpoint")).unwrap(),
+            "14 3\n"
+        );
+
+        // Appended to while closed, so only the tail is counted
+        let mut appended = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut appended, b"four\n").unwrap();
+        let mut file = open().await.unwrap();
+        assert_eq!((file.counters().bytes, file.counters().lines), (19, 4));
+        file.close().await.unwrap();
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(4)
+            .unwrap();
+        let mut file = open().await.unwrap();
+        assert_eq!((file.counters().bytes, file.counters().lines), (4, 1));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn rotation_span() {
+        use std::sync::Mutex;
+        use tracing::{
+            field::{Field, Visit},
+            span, Event, Metadata, Subscriber,
+        };
+
+        struct Recorded {
+            metadata: &'static Metadata<'static>,
+            name: &'static str,
+            parent: Option<u64>,
+            fields: Vec<String>,
+        }
+
+        /// Records every span with its parent and `field=value` strings.
+        #[derive(Default)]
+        struct Spans {
+            spans: Mutex<Vec<Recorded>>,
+            entered: Mutex<Vec<u64>>,
+        }
+
+        struct Fields<'a>(&'a mut Vec<String>);
+
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                self.0.push(format!("{}={:?}", field.name(), value));
+            }
+        }
+
+        impl Subscriber for &'static Spans {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+                let mut spans = self.spans.lock().unwrap();
+                let mut fields = Vec::new();
+                attrs.record(&mut Fields(&mut fields));
+                let parent = match attrs.parent() {
+                    Some(id) => Some(id.into_u64()),
+                    None => self.entered.lock().unwrap().last().copied(),
+                };
+                spans.push(Recorded {
+                    metadata: attrs.metadata(),
+                    name: attrs.metadata().name(),
+                    parent,
+                    fields,
+                });
+                span::Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, id: &span::Id, values: &span::Record<'_>) {
+                let mut spans = self.spans.lock().unwrap();
+                let fields = &mut spans[id.into_u64() as usize - 1].fields;
+                values.record(&mut Fields(fields));
+            }
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, _: &Event<'_>) {}
+            fn enter(&self, id: &span::Id) {
+                self.entered.lock().unwrap().push(id.into_u64());
+            }
+            fn exit(&self, _: &span::Id) {
+                self.entered.lock().unwrap().pop();
+            }
+            fn current_span(&self) -> tracing_core::span::Current {
+                match self.entered.lock().unwrap().last() {
+                    Some(&id) => {
+                        let metadata = self.spans.lock().unwrap()[id as usize - 1].metadata;
+                        tracing_core::span::Current::new(span::Id::from_u64(id), metadata)
+                    }
+                    None => tracing_core::span::Current::none(),
+                }
+            }
+        }
+
+        static SPANS: std::sync::OnceLock<Spans> = std::sync::OnceLock::new();
+        let recorder = SPANS.get_or_init(Spans::default);
+        let _guard = tracing::subscriber::set_default(recorder);
+
+        let dir = fresh_dir("rotation_span").await;
+        let mut file = RotatingFile::new(dir.join("app.log"), RotationMode::Bytes(2))
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+
+        let spans = recorder.spans.lock().unwrap();
+        let rotation = &spans[0];
+        assert_eq!(rotation.name, "rotation");
+        let fields = &rotation.fields;
+        assert!(fields.contains(&"bytes=2".to_string()), "{:?}", fields);
+        assert!(fields.contains(&"reason=Bytes".to_string()), "{:?}", fields);
+        assert!(
+            fields.iter().any(|f| f.starts_with("archive=")),
+            "{:?}",
+            fields
+        );
+        assert!(
+            fields.iter().any(|f| f.starts_with("duration_ms=")),
+            "{:?}",
+            fields
+        );
+        assert_eq!(spans[1].name, "compression");
+        assert_eq!(spans[1].parent, Some(1));
+    }
+
+    #[tokio::test]
+    async fn subsecond_timestamps() {
+        let dir = fresh_dir("subsecond_timestamps").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .subsecond_timestamps(true)
+            .build()
+            .await
+            .unwrap();
+        for data in [b"11", b"22", b"33", b"44"] {
+            file.write_all(data).await.unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 3);
+        let contents: Vec<_> = archives.iter().map(|a| gunzip(&a.path)).collect();
+        assert_eq!(contents, [b"11", b"22", b"33"]);
+    }
+
+    #[tokio::test]
+    async fn estimated_time_to_rotation() {
+        let dir = fresh_dir("estimated_time_to_rotation").await;
+        let mut file = RotatingFile::new(dir.join("app.log"), RotationMode::Bytes(200))
+            .await
+            .unwrap();
+        file.write_all(b"0123456789").await.unwrap();
+        assert_eq!(file.estimated_time_to_rotation(), None);
+
+        let mut previous = None;
+        for _ in 0..5 {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            file.write_all(b"0123456789").await.unwrap();
+            let estimate = file.estimated_time_to_rotation().unwrap();
+            // At most 19 more writes 50ms apart, allowing for a busy machine
+            assert!(
+                estimate < std::time::Duration::from_secs(3),
+                "{:?}",
+                estimate
+            );
+            if let Some(previous) = previous {
+                assert!(estimate < previous, "{:?} after {:?}", estimate, previous);
+            }
+            previous = Some(estimate);
+        }
+    }
+
+    #[tokio::test]
+    async fn direct_compression() {
+        use std::sync::Mutex;
+
+        /// Lists the directory around each job.
+        struct Watching {
+            dir: PathBuf,
+            seen: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl CompressionExecutor for Watching {
+            fn execute(&self, job: Job) {
+                let (dir, seen) = (self.dir.clone(), self.seen.clone());
+                let list = move || {
+                    let names = std::fs::read_dir(&dir)
+                        .unwrap()
+                        .map(|e| e.unwrap().file_name().into_string().unwrap());
+                    seen.lock().unwrap().extend(names);
+                };
+                std::thread::spawn(move || {
+                    list();
+                    job();
+                    list();
+                });
+            }
+        }
+
+        let dir = fresh_dir("direct_compression").await;
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .direct_compression(true)
+            .compression_executor(Arc::new(Watching {
+                dir: dir.clone(),
+                seen: seen.clone(),
+            }))
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+
+        let seen = seen.lock().unwrap().clone();
+        assert!(!seen.is_empty());
+        // The compressed temporary is renamed into place after the job, and
+        // the next active file waits beside the current one
+        assert!(
+            seen.iter().all(|name| name == "app.log"
+                || name == "app.log.next"
+                || name.ends_with(".gz")
+                || name.ends_with(".gz.tmp")),
+            "{:?}",
+            seen
+        );
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert_eq!(gunzip(&archives[0].path), b"11");
+        assert_eq!(std::fs::read(dir.join("app.log")).unwrap(), b"22");
+    }
+
+    #[tokio::test]
+    async fn write_after_shutdown() {
+        let dir = fresh_dir("write_after_shutdown").await;
+        let mut file = RotatingFile::new(dir.join("app.log"), RotationMode::Bytes(2))
+            .await
+            .unwrap();
+        file.write_all(b"1").await.unwrap();
+        file.shutdown().await.unwrap();
+        let err = file.write_all(b"2").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(std::fs::read(dir.join("app.log")).unwrap(), b"1");
     }
 }
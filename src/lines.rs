@@ -0,0 +1,215 @@
+use crate::builder::RotationConfig;
+
+/// The line ending that [`RotatingFileBuilder::line_ending`] normalizes
+/// written lines to.
+///
+/// [`RotatingFileBuilder::line_ending`]: crate::RotatingFileBuilder::line_ending
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    /// Ends lines with `\n`, dropping a `\r` before it.
+    Lf,
+    /// Ends lines with `\r\n`, adding a `\r` before a lone `\n`.
+    CrLf,
+}
+
+impl LineEnding {
+    /// Rewrites the ending of `line`, which ends with `\n`.
+    fn normalize(self, line: &mut Vec<u8>) {
+        let crlf = line.ends_with(b"\r\n");
+        match self {
+            LineEnding::Lf if crlf => {
+                line.remove(line.len() - 2);
+            }
+            LineEnding::CrLf if !crlf => line.insert(line.len() - 1, b'\r'),
+            _ => {}
+        }
+    }
+}
+
+/// How lines are counted towards [`RotationMode::Lines`], set by
+/// [`RotatingFileBuilder::line_counting`].
+///
+/// [`RotationMode::Lines`]: crate::RotationMode::Lines
+/// [`RotatingFileBuilder::line_counting`]: crate::RotatingFileBuilder::line_counting
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineCounting {
+    /// Counts every `\n`.
+    #[default]
+    Lf,
+    /// Detects the convention from the first write that ends a line. If it
+    /// only ends lines with `\r\n`, only `\r\n` counts from then on, so a
+    /// bare `\n` or `\r` inside a record doesn't. If it mixes both, every
+    /// `\n` counts, as with [`Lf`](Self::Lf).
+    Auto,
+}
+
+/// Counts lines per a [`LineCounting`], remembering the convention once
+/// detected.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct NewlineCounter {
+    mode: LineCounting,
+    /// Whether lines end with `\r\n`, once detected.
+    crlf: Option<bool>,
+    /// Whether the last byte counted was a `\r`.
+    after_cr: bool,
+}
+
+impl NewlineCounter {
+    pub(crate) fn new(mode: LineCounting) -> Self {
+        Self {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn count(&mut self, buf: &[u8]) -> usize {
+        if self.mode == LineCounting::Lf {
+            return crate::countlines(buf);
+        }
+        let (mut lf, mut crlf) = (0, 0);
+        for &b in buf {
+            if b == b'\n' {
+                match self.after_cr {
+                    true => crlf += 1,
+                    false => lf += 1,
+                }
+            }
+            self.after_cr = b == b'\r';
+        }
+        if self.crlf.is_none() && lf + crlf > 0 {
+            self.crlf = Some(lf == 0);
+        }
+        match self.crlf {
+            Some(true) => crlf,
+            _ => lf + crlf,
+        }
+    }
+}
+
+/// Splits writes into complete lines for features that look at, or act
+/// between, whole lines.
+///
+/// Bytes after the last newline are held back in `partial` until their line
+/// completes, so a line is never split between two files. Complete lines
+/// wait in `outgoing` until they've reached the file.
+#[derive(Default)]
+pub(crate) struct LineBuffer {
+    partial: Vec<u8>,
+    pub(crate) outgoing: Vec<u8>,
+    /// Whether the file must rotate once `outgoing` is written.
+    pub(crate) rotate_after: bool,
+    /// Whether the start of the current line was already written, cut off
+    /// by `max_line_bytes`.
+    continued: bool,
+    #[cfg(feature = "json")]
+    last_key: Option<serde_json::Value>,
+}
+
+/// The result of [`LineBuffer::accept`].
+pub(crate) struct Accepted {
+    /// How many bytes of the caller's buffer were taken.
+    pub(crate) consumed: usize,
+    /// Whether the file must rotate before `outgoing` is written.
+    pub(crate) rotate_before: bool,
+}
+
+impl LineBuffer {
+    /// Whether `config` enables anything that needs line buffering.
+    pub(crate) fn needed(config: &RotationConfig) -> bool {
+        #[cfg(feature = "json")]
+        if config.json_key.is_some() {
+            return true;
+        }
+        #[cfg(feature = "regex")]
+        if config.rotate_after_match.is_some() {
+            return true;
+        }
+        config.line_ending.is_some() || config.line_timestamps.is_some()
+    }
+
+    /// Takes `buf` up to and including its first newline. A complete line is
+    /// moved to `outgoing`, which must be empty, as is one cut off at
+    /// `max_line_bytes`.
+    pub(crate) fn accept(&mut self, buf: &[u8], config: &RotationConfig) -> Accepted {
+        debug_assert!(self.outgoing.is_empty());
+        let room = match config.max_line_bytes {
+            Some((max, _)) => max.saturating_sub(self.partial.len()),
+            None => usize::MAX,
+        };
+        let window = &buf[..buf.len().min(room)];
+        let end = match window.iter().position(|b| *b == b'\n') {
+            Some(i) => i + 1,
+            None => {
+                self.partial.extend_from_slice(window);
+                if window.len() == room {
+                    let mut line = std::mem::take(&mut self.partial);
+                    if !self.continued {
+                        Self::stamp(&mut line, config);
+                    }
+                    self.continued = true;
+                    self.outgoing = line;
+                    self.rotate_after = config.max_line_bytes.is_some_and(|(_, rotate)| rotate);
+                }
+                return Accepted {
+                    consumed: window.len(),
+                    rotate_before: false,
+                };
+            }
+        };
+        let mut line = std::mem::take(&mut self.partial);
+        line.extend_from_slice(&buf[..end]);
+        let continued = std::mem::take(&mut self.continued);
+        let rotate_before = !continued && self.starts_new_file(&line, config);
+        #[cfg(feature = "regex")]
+        if let Some(pattern) = config.rotate_after_match.as_ref().filter(|_| !continued) {
+            let text = String::from_utf8_lossy(&line);
+            self.rotate_after = pattern.is_match(text.trim_end_matches(&['\r', '\n'][..]));
+        }
+        if let Some(ending) = config.line_ending {
+            ending.normalize(&mut line);
+        }
+        if !continued {
+            Self::stamp(&mut line, config);
+        }
+        self.outgoing = line;
+        Accepted {
+            consumed: end,
+            rotate_before,
+        }
+    }
+
+    /// Prefixes the start of a line with the `line_timestamps` format.
+    fn stamp(line: &mut Vec<u8>, config: &RotationConfig) {
+        if let Some(format) = &config.line_timestamps {
+            let mut stamped = format!("{} ", chrono::Local::now().format(format)).into_bytes();
+            stamped.append(line);
+            *line = stamped;
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn buffered(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Moves an unterminated trailing line to `outgoing`.
+    pub(crate) fn take_partial(&mut self) {
+        debug_assert!(self.outgoing.is_empty());
+        self.outgoing = std::mem::take(&mut self.partial);
+    }
+
+    #[cfg_attr(not(feature = "json"), allow(unused_variables))]
+    fn starts_new_file(&mut self, line: &[u8], config: &RotationConfig) -> bool {
+        #[cfg(feature = "json")]
+        if let Some(pointer) = &config.json_key {
+            let key = serde_json::from_slice::<serde_json::Value>(line)
+                .ok()
+                .and_then(|record| record.pointer(pointer).cloned());
+            if let Some(key) = key {
+                let previous = self.last_key.replace(key);
+                return previous.is_some() && previous != self.last_key;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{Counters, RotationMode};
+use futures::task::{Context, Poll};
+use std::{collections::VecDeque, io::Error, pin::Pin};
+use tokio::io::AsyncWrite;
+
+/// An in-memory log with the rotation semantics of a
+/// [`RotatingFile`](crate::RotatingFile), where rotating discards the oldest
+/// segment.
+///
+/// Keeps the active segment plus up to `archived` full ones, so memory is
+/// capped at roughly `archived + 1` times the mode's threshold. Useful for
+/// keeping the last stretch of logs around to dump on a crash.
+pub struct RotatingBuffer {
+    rotation: RotationMode,
+    archived: usize,
+    segments: VecDeque<Vec<u8>>,
+    counters: Counters,
+}
+
+impl RotatingBuffer {
+    pub fn new(mode: RotationMode, archived: usize) -> Self {
+        Self {
+            rotation: mode,
+            archived,
+            segments: VecDeque::from([Vec::new()]),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Everything still held, oldest first.
+    pub fn contents(&self) -> Vec<u8> {
+        self.segments.iter().flatten().copied().collect()
+    }
+
+    /// Iterates over the held segments, oldest first. The last is the active
+    /// one.
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.segments.iter().map(|s| s.as_slice())
+    }
+
+    fn rotate(&mut self) {
+        self.segments.push_back(Vec::new());
+        while self.segments.len() > self.archived + 1 {
+            self.segments.pop_front();
+        }
+        self.counters = Counters::default();
+    }
+}
+
+impl std::io::Write for RotatingBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if self.rotation.exceeded(&self.counters) {
+            self.rotate();
+        }
+        self.segments.back_mut().unwrap().extend_from_slice(buf);
+        self.counters.record(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for RotatingBuffer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        Poll::Ready(std::io::Write::write(self.get_mut(), buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn evicts_oldest() {
+        let mut buffer = RotatingBuffer::new(RotationMode::Lines(2), 1);
+        for i in 0..7 {
+            buffer
+                .write_all(format!("{}\n", i).as_bytes())
+                .await
+                .unwrap();
+        }
+        assert_eq!(buffer.contents(), b"4\n5\n6\n");
+        let segments: Vec<_> = buffer.segments().collect();
+        assert_eq!(segments, vec![&b"4\n5\n"[..], b"6\n"]);
+    }
+}
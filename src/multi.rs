@@ -0,0 +1,175 @@
+use crate::RotatingFile;
+use futures::future::join_all;
+use std::io::Error;
+use tokio::io::AsyncWriteExt;
+
+/// What a [`MultiRotatingFile`] does when one of its files fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnFailure {
+    /// Returns the first error. The other files still got the write, and
+    /// the next write goes to all of them again.
+    #[default]
+    FailFast,
+    /// Stops writing to a file once it fails and carries on with the rest,
+    /// only failing once none are left. See [`MultiRotatingFile::error`].
+    BestEffort,
+}
+
+/// Writes the same stream to several [`RotatingFile`]s, e.g. a local disk
+/// and a network mount, each rotating and retaining by its own settings.
+///
+/// Each write goes to all files concurrently and returns once every one of
+/// them has it.
+pub struct MultiRotatingFile {
+    files: Vec<RotatingFile>,
+    errors: Vec<Option<Error>>,
+    on_failure: OnFailure,
+}
+
+impl MultiRotatingFile {
+    pub fn new(files: Vec<RotatingFile>, on_failure: OnFailure) -> Self {
+        let errors = files.iter().map(|_| None).collect();
+        Self {
+            files,
+            errors,
+            on_failure,
+        }
+    }
+
+    /// Writes all of `buf` to every file.
+    pub async fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.each(Op::Write(buf)).await
+    }
+
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.each(Op::Flush).await
+    }
+
+    /// Shuts every file down; see [`RotatingFile::close`].
+    pub async fn close(&mut self) -> Result<(), Error> {
+        self.each(Op::Close).await
+    }
+
+    /// The files, in the order they were given.
+    pub fn files(&self) -> &[RotatingFile] {
+        &self.files
+    }
+
+    /// The error that made [`OnFailure::BestEffort`] stop writing to the
+    /// file at `index`, if any.
+    pub fn error(&self, index: usize) -> Option<&Error> {
+        self.errors.get(index)?.as_ref()
+    }
+
+    async fn each(&mut self, op: Op<'_>) -> Result<(), Error> {
+        let best_effort = self.on_failure == OnFailure::BestEffort;
+        let (live, results): (Vec<_>, Vec<_>) = self
+            .files
+            .iter_mut()
+            .zip(self.errors.iter())
+            .enumerate()
+            .filter(|(_, (_, error))| !best_effort || error.is_none())
+            .map(|(i, (file, _))| (i, op.run(file)))
+            .unzip();
+        let results = join_all(results).await;
+
+        let mut first = None;
+        for (i, result) in live.into_iter().zip(results) {
+            if let Err(e) = result {
+                match best_effort {
+                    true => self.errors[i] = Some(e),
+                    false => {
+                        first.get_or_insert(e);
+                    }
+                }
+            }
+        }
+        if let Some(e) = first {
+            return Err(e);
+        }
+        if best_effort && self.errors.iter().all(Option::is_some) {
+            return Err(Error::other("every file has failed"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Op<'a> {
+    Write(&'a [u8]),
+    Flush,
+    Close,
+}
+
+impl Op<'_> {
+    async fn run(self, file: &mut RotatingFile) -> Result<(), Error> {
+        match self {
+            Op::Write(buf) => file.write_unsplit(buf).await,
+            Op::Flush => file.flush().await,
+            Op::Close => file.close().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::fresh_dir, Codec, Collision, RotationMode};
+    use chrono::Local;
+
+    #[tokio::test]
+    async fn independent_rotation() {
+        let dir = fresh_dir("multi_independent_rotation").await;
+        let open = |name: &str, lines| {
+            RotatingFile::builder(dir.join(name), RotationMode::Lines(lines))
+                .sequence(1)
+                .codec(Codec::None)
+                .build()
+        };
+        let files = vec![
+            open("a.log", 2).await.unwrap(),
+            open("b.log", 3).await.unwrap(),
+        ];
+        let mut multi = MultiRotatingFile::new(files, OnFailure::FailFast);
+        for i in 0..6 {
+            multi.write(format!("{}\n", i).as_bytes()).await.unwrap();
+        }
+        multi.flush().await.unwrap();
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(multi.files()[0].archives().await.unwrap().len(), 2);
+        assert_eq!(read("a.log"), "4\n5\n");
+        assert_eq!(multi.files()[1].archives().await.unwrap().len(), 1);
+        assert_eq!(read("b.log"), "3\n4\n5\n");
+    }
+
+    #[tokio::test]
+    async fn best_effort() {
+        let dir = fresh_dir("multi_best_effort").await;
+        let day = Local::now().format("%Y-%m-%d").to_string();
+        std::fs::write(dir.join(format!("b-{}.log.gz", day)), b"old").unwrap();
+        let open = |name: &str, collision, format: &str| {
+            RotatingFile::builder(dir.join(name), RotationMode::Bytes(2))
+                .on_collision(collision)
+                .timestamp_format(format)
+                .build()
+        };
+        let files = vec![
+            open("a.log", Collision::Disambiguate, "%Y-%m-%d_%H-%M-%S")
+                .await
+                .unwrap(),
+            open("b.log", Collision::Fail, "%Y-%m-%d").await.unwrap(),
+        ];
+        let mut multi = MultiRotatingFile::new(files, OnFailure::BestEffort);
+        for chunk in [b"11", b"22", b"33"].iter() {
+            multi.write(*chunk).await.unwrap();
+        }
+        multi.flush().await.unwrap();
+
+        // b failed to rotate and was left behind, a carried on
+        assert!(multi.error(0).is_none());
+        assert!(multi.error(1).is_some());
+        assert_eq!(std::fs::read(dir.join("a.log")).unwrap(), b"33");
+        assert_eq!(std::fs::read(dir.join("b.log")).unwrap(), b"11");
+    }
+}
@@ -0,0 +1,268 @@
+use crate::RotatingFile;
+use futures::future::{self, Either};
+use std::{
+    io::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::AsyncWriteExt,
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore, TryAcquireError},
+    task::JoinHandle,
+};
+
+/// What a [`NonBlocking`] writer does when its byte budget is used up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Waits until the worker has written enough to make room.
+    #[default]
+    Block,
+    /// Discards the write and counts it in [`NonBlocking::dropped`].
+    Drop,
+}
+
+struct Shared {
+    budget: Arc<Semaphore>,
+    limit: usize,
+    overflow: Overflow,
+    dropped: AtomicU64,
+}
+
+/// A cloneable handle that queues writes for a background task owning the
+/// [`RotatingFile`].
+///
+/// Queued bytes are capped by a budget, so producers outpacing the disk
+/// either wait or lose writes (per [`Overflow`]) instead of growing memory
+/// without bound. A single write larger than the whole budget takes all of
+/// it and is queued alone.
+///
+/// With the `tracing-subscriber` feature it is a `MakeWriter`, standing in
+/// for `tracing_appender`'s rolling appenders with compression and
+/// retention on top. Where a subscriber was given
+/// `tracing_appender::rolling::daily(dir, "app.log")`, build the file with
+/// [`RotatingFileBuilder::build_non_blocking`] instead, using
+/// [`RotatingFileBuilder::daily_boundary`] for daily files, pass the
+/// writer to `with_writer`, and keep the worker handle where the
+/// `WorkerGuard` of `tracing_appender::non_blocking` was kept, awaiting it
+/// at exit. Through `std::io::Write` a write that finds the budget used up
+/// is dropped, as `tracing_appender::non_blocking` does by default, since
+/// waiting for room could block the very runtime the worker runs on.
+///
+/// ```ignore
+/// let (writer, worker) = RotatingFile::builder("logs/app.log", RotationMode::Bytes(1 << 20))
+///     .daily_boundary(true)
+///     .build_non_blocking()
+///     .await?;
+/// tracing_subscriber::fmt().with_writer(writer).init();
+/// ```
+///
+/// [`RotatingFileBuilder::build_non_blocking`]: crate::RotatingFileBuilder::build_non_blocking
+/// [`RotatingFileBuilder::daily_boundary`]: crate::RotatingFileBuilder::daily_boundary
+#[derive(Clone)]
+pub struct NonBlocking {
+    sender: mpsc::UnboundedSender<(Vec<u8>, OwnedSemaphorePermit)>,
+    shared: Arc<Shared>,
+}
+
+impl NonBlocking {
+    pub(crate) fn spawn(
+        mut file: RotatingFile,
+        limit: usize,
+        overflow: Overflow,
+    ) -> (Self, JoinHandle<Result<(), Error>>) {
+        let limit = limit.min(Semaphore::MAX_PERMITS);
+        let (sender, mut receiver) = mpsc::unbounded_channel::<(Vec<u8>, OwnedSemaphorePermit)>();
+        let worker = tokio::spawn(async move {
+            // Whether the age deadline is still to be checked, which a file
+            // that wasn't due then only is again after the next write
+            let mut aging = true;
+            loop {
+                let deadline = file.age_deadline().filter(|_| aging);
+                let next = match deadline {
+                    Some(deadline) => {
+                        let aged = tokio::time::sleep_until(deadline.into());
+                        match future::select(Box::pin(receiver.recv()), Box::pin(aged)).await {
+                            Either::Left((next, _)) => next,
+                            Either::Right(_) => {
+                                aging = file.rotate_if_due().await?;
+                                continue;
+                            }
+                        }
+                    }
+                    None => receiver.recv().await,
+                };
+                let Some((buf, permit)) = next else { break };
+                file.write_all(&buf).await?;
+                drop(permit);
+                aging = true;
+                if receiver.is_empty() {
+                    file.flush().await?;
+                }
+            }
+            file.flush().await
+        });
+        let shared = Arc::new(Shared {
+            budget: Arc::new(Semaphore::new(limit)),
+            limit,
+            overflow,
+            dropped: AtomicU64::new(0),
+        });
+        (Self { sender, shared }, worker)
+    }
+
+    /// Queues `buf` for writing.
+    ///
+    /// Fails only if the worker has stopped, e.g. after a write error.
+    pub async fn write(&self, buf: &[u8]) -> Result<(), Error> {
+        let cost = buf.len().min(self.shared.limit).min(u32::MAX as usize) as u32;
+        let budget = self.shared.budget.clone();
+        let permit = match self.shared.overflow {
+            Overflow::Block => budget.acquire_many_owned(cost).await.ok(),
+            Overflow::Drop => match budget.try_acquire_many_owned(cost) {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+            },
+        };
+        let permit = permit.ok_or_else(|| Error::other("non-blocking worker stopped"))?;
+        self.sender
+            .send((buf.to_vec(), permit))
+            .map_err(|_| Error::other("non-blocking worker stopped"))
+    }
+
+    /// Queues `buf` for writing if the budget has room for it right away,
+    /// dropping it otherwise.
+    fn try_write(&self, buf: &[u8]) -> Result<(), Error> {
+        let cost = buf.len().min(self.shared.limit).min(u32::MAX as usize) as u32;
+        match self.shared.budget.clone().try_acquire_many_owned(cost) {
+            Ok(permit) => self
+                .sender
+                .send((buf.to_vec(), permit))
+                .map_err(|_| Error::other("non-blocking worker stopped")),
+            Err(TryAcquireError::NoPermits) => {
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TryAcquireError::Closed) => Err(Error::other("non-blocking worker stopped")),
+        }
+    }
+
+    /// Bytes queued but not yet written.
+    pub fn buffered(&self) -> usize {
+        self.shared.limit - self.shared.budget.available_permits()
+    }
+
+    /// Writes discarded by [`Overflow::Drop`], or through `std::io::Write`
+    /// for want of room.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl std::io::Write for NonBlocking {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.try_write(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tracing-subscriber")]
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for NonBlocking {
+    type Writer = NonBlocking;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::fresh_dir, RotationMode};
+
+    #[tokio::test]
+    async fn drop_over_budget() {
+        let dir = fresh_dir("non_blocking_drop").await;
+        let path = dir.join("app.log");
+        let (writer, worker) = RotatingFile::builder(&path, RotationMode::Bytes(1024))
+            .buffer_limit(10)
+            .overflow(Overflow::Drop)
+            .build_non_blocking()
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            writer.write(b"abcd").await.unwrap();
+        }
+        assert_eq!(writer.buffered(), 8);
+        assert_eq!(writer.dropped(), 3);
+        drop(writer);
+        worker.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcdabcd");
+    }
+
+    #[tokio::test]
+    async fn block_over_budget() {
+        let dir = fresh_dir("non_blocking_block").await;
+        let path = dir.join("app.log");
+        let (writer, worker) = RotatingFile::builder(&path, RotationMode::Bytes(1024))
+            .buffer_limit(10)
+            .overflow(Overflow::Block)
+            .build_non_blocking()
+            .await
+            .unwrap();
+        for _ in 0..5 {
+            writer.write(b"abcd").await.unwrap();
+            assert!(writer.buffered() <= 10);
+        }
+        assert_eq!(writer.dropped(), 0);
+        drop(writer);
+        worker.await.unwrap().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"abcd".repeat(5));
+    }
+
+    #[cfg(feature = "tracing-subscriber")]
+    #[tokio::test]
+    async fn make_writer() {
+        let dir = fresh_dir("non_blocking_make_writer").await;
+        let path = dir.join("app.log");
+        let (writer, worker) = RotatingFile::builder(&path, RotationMode::Lines(2))
+            .sequence(1)
+            .build_non_blocking()
+            .await
+            .unwrap();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(writer)
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            for i in 0..5 {
+                tracing::info!("event {}", i);
+            }
+        });
+        worker.await.unwrap().unwrap();
+
+        let mut archives: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "gz"))
+            .collect();
+        archives.sort();
+        assert_eq!(archives.len(), 2);
+        let logged: Vec<_> = archives
+            .iter()
+            .map(|archive| String::from_utf8(crate::tests::gunzip(archive)).unwrap())
+            .chain(std::iter::once(std::fs::read_to_string(&path).unwrap()))
+            .collect();
+        for (i, line) in logged.concat().lines().enumerate() {
+            assert!(line.ends_with(&format!("event {}", i)), "{}", line);
+        }
+    }
+}
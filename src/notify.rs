@@ -0,0 +1,82 @@
+use std::{
+    io::Error,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// How long a notification may take before it's given up on.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where [`RotatingFileBuilder::notify`] announces finished archives.
+///
+/// [`RotatingFileBuilder::notify`]: crate::RotatingFileBuilder::notify
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NotifyEndpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl NotifyEndpoint {
+    /// Sends `archive` and its size in a connection of its own. Failures
+    /// are logged, if at all, and otherwise ignored, since the archive is
+    /// there either way.
+    pub(crate) async fn send(&self, archive: &Path, size: u64) {
+        let message = format!("{}\t{}\n", archive.display(), size);
+        let sent = tokio::time::timeout(NOTIFY_TIMEOUT, self.deliver(message.as_bytes())).await;
+        #[cfg(feature = "tracing")]
+        if let Err(e) = sent.map_err(Error::from).and_then(|sent| sent) {
+            tracing::warn!(endpoint = ?self, "could not announce {}: {}", archive.display(), e);
+        }
+        #[cfg(not(feature = "tracing"))]
+        drop(sent);
+    }
+
+    async fn deliver(&self, message: &[u8]) -> Result<(), Error> {
+        match self {
+            NotifyEndpoint::Tcp(addr) => {
+                write(tokio::net::TcpStream::connect(addr).await?, message).await
+            }
+            #[cfg(unix)]
+            NotifyEndpoint::Unix(path) => {
+                write(tokio::net::UnixStream::connect(path).await?, message).await
+            }
+        }
+    }
+}
+
+async fn write<S: AsyncWrite + Unpin>(mut stream: S, message: &[u8]) -> Result<(), Error> {
+    stream.write_all(message).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::fresh_dir, RotatingFile, RotationMode};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn announces_archives() {
+        let dir = fresh_dir("notify_announces_archives").await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = NotifyEndpoint::Tcp(listener.local_addr().unwrap());
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(2))
+            .notify(endpoint)
+            .build()
+            .await
+            .unwrap();
+        file.write_all(b"11").await.unwrap();
+        file.write_all(b"22").await.unwrap();
+        file.await_compressions().await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut message = String::new();
+        stream.read_to_string(&mut message).await.unwrap();
+        let archive = file.latest_archive().await.unwrap().unwrap();
+        let size = std::fs::metadata(&archive).unwrap().len();
+        assert_eq!(message, format!("{}\t{}\n", archive.display(), size));
+    }
+}
@@ -0,0 +1,59 @@
+use futures::task::{Context, Poll};
+use std::{future::Future, pin::Pin};
+use tokio::time::{Duration, Instant, Sleep};
+
+/// Paces writes to a number of bytes per second, set by
+/// [`RotatingFileBuilder::write_rate_limit`].
+///
+/// Tokens for a tenth of a second's worth of bytes can build up, so writes
+/// are let through in bursts of at most that size.
+///
+/// [`RotatingFileBuilder::write_rate_limit`]: crate::RotatingFileBuilder::write_rate_limit
+pub(crate) struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second as f64;
+        let capacity = (rate / 10.0).max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled: Instant::now(),
+            sleep: None,
+        }
+    }
+
+    /// Waits until some of `want` bytes may be written, and returns how
+    /// many. Take them with [`consume`](Self::consume) once written.
+    pub(crate) fn poll_acquire(&mut self, cx: &mut Context<'_>, want: usize) -> Poll<usize> {
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                futures::ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+            self.refilled = now;
+
+            // Waits for enough of a burst that writes don't trickle out
+            let needed = (want as f64).min(self.capacity);
+            if self.tokens >= needed {
+                return Poll::Ready((self.tokens as usize).clamp(1, want));
+            }
+            let wait = (needed - self.tokens) / self.rate;
+            self.sleep = Some(Box::pin(tokio::time::sleep(Duration::from_secs_f64(wait))));
+        }
+    }
+
+    pub(crate) fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
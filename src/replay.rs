@@ -0,0 +1,282 @@
+use crate::{
+    archive::{self, ArchiveInfo, Naming},
+    compress,
+};
+use futures::{
+    ready,
+    task::{Context, Poll},
+    Future,
+};
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind},
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+};
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, ReadBuf, Take},
+    task::JoinHandle,
+};
+
+/// What a [`Replay`] does on reaching a gap in the archive timeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gaps {
+    /// Records the gap in [`Replay::gaps`] and keeps reading.
+    Report,
+    /// Records the gap and fails the read with [`ErrorKind::InvalidData`].
+    /// Reading again continues after the gap.
+    Fail,
+}
+
+/// Archives missing between two consecutive archives of a [`Replay`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gap {
+    /// The last archive before the gap.
+    pub after: PathBuf,
+    /// The first archive after the gap.
+    pub before: PathBuf,
+    /// The sequence numbers that have no archive.
+    pub missing: Range<u64>,
+}
+
+/// The decompressed contents of a file's archives as one [`AsyncRead`],
+/// oldest first.
+///
+/// Each archive is decompressed whole on Tokio's blocking pool before it is
+/// read, so memory use is bounded by the largest archive.
+pub struct Replay {
+    pending: VecDeque<ArchiveInfo>,
+    gaps: Gaps,
+    found: Vec<Gap>,
+    previous: Option<ArchiveInfo>,
+    loading: Option<JoinHandle<Result<Vec<u8>, Error>>>,
+    current: Vec<u8>,
+    position: usize,
+}
+
+impl Replay {
+    pub(crate) fn new(archives: Vec<ArchiveInfo>, gaps: Gaps) -> Self {
+        Self {
+            pending: archives.into(),
+            gaps,
+            found: Vec::new(),
+            previous: None,
+            loading: None,
+            current: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// The gaps reached so far.
+    pub fn gaps(&self) -> &[Gap] {
+        &self.found
+    }
+
+    /// Starts loading the next archive, returning the gap before it if any.
+    fn advance(&mut self, next: ArchiveInfo) -> Option<Gap> {
+        let gap = match (
+            self.previous.as_ref().and_then(|p| p.sequence),
+            next.sequence,
+        ) {
+            (Some(last), Some(seq)) if seq > last + 1 => Some(Gap {
+                after: self.previous.as_ref().unwrap().path.clone(),
+                before: next.path.clone(),
+                missing: last + 1..seq,
+            }),
+            _ => None,
+        };
+        let path = next.path.clone();
+        self.loading = Some(tokio::task::spawn_blocking(move || {
+            compress::decode_file(&path)
+        }));
+        self.previous = Some(next);
+        gap
+    }
+}
+
+impl AsyncRead for Replay {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        loop {
+            if me.position < me.current.len() {
+                let n = buf.remaining().min(me.current.len() - me.position);
+                buf.put_slice(&me.current[me.position..me.position + n]);
+                me.position += n;
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(loading) = me.loading.as_mut() {
+                let loaded = ready!(Pin::new(loading).poll(cx)).map_err(Error::other);
+                me.loading = None;
+                me.current = loaded??;
+                me.position = 0;
+                continue;
+            }
+            let Some(next) = me.pending.pop_front() else {
+                return Poll::Ready(Ok(()));
+            };
+            if let Some(gap) = me.advance(next) {
+                let message = format!(
+                    "archives {}-{} are missing between {} and {}",
+                    gap.missing.start,
+                    gap.missing.end - 1,
+                    gap.after.display(),
+                    gap.before.display()
+                );
+                me.found.push(gap);
+                if me.gaps == Gaps::Fail {
+                    return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, message)));
+                }
+            }
+        }
+    }
+}
+
+/// Every archive of a file followed by the active file, read as one
+/// [`AsyncRead`] in the order it was written.
+///
+/// The active file is read up to its length when the reader was opened, so
+/// writes made while reading aren't picked up. Archives are read as by
+/// [`Replay`], reporting gaps in [`RotatingReader::gaps`].
+pub struct RotatingReader {
+    archives: Replay,
+    active: Option<Take<File>>,
+}
+
+impl RotatingReader {
+    /// Reads the archives of `path`, named by the default timestamp with or
+    /// without a sequence number, then `path` itself. For other naming
+    /// schemes use [`RotatingFile::reader`].
+    ///
+    /// [`RotatingFile::reader`]: crate::RotatingFile::reader
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut archives = Vec::new();
+        for sequence_width in [None, Some(1)] {
+            let naming = Naming {
+                sequence_width,
+                ..Naming::default()
+            };
+            archives.extend(archive::list(path, &naming).await?);
+        }
+        archives.sort_by_key(|a| (a.timestamp, a.sequence));
+        Self::with_archives(path, archives).await
+    }
+
+    pub(crate) async fn with_naming(path: &Path, naming: &Naming) -> Result<Self, Error> {
+        Self::with_archives(path, archive::list(path, naming).await?).await
+    }
+
+    async fn with_archives(path: &Path, archives: Vec<ArchiveInfo>) -> Result<Self, Error> {
+        let archives = Replay::new(archives, Gaps::Report);
+        let active = match File::open(path).await {
+            Ok(file) => {
+                let len = file.metadata().await?.len();
+                Some(file.take(len))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Self { archives, active })
+    }
+
+    /// The gaps between archives reached so far.
+    pub fn gaps(&self) -> &[Gap] {
+        self.archives.gaps()
+    }
+}
+
+impl AsyncRead for RotatingReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<(), Error>> {
+        let me = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut me.archives).poll_read(cx, buf))?;
+        if buf.filled().len() > before {
+            return Poll::Ready(Ok(()));
+        }
+        match me.active.as_mut() {
+            Some(active) => Pin::new(active).poll_read(cx, buf),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::fresh_dir, RotatingFile, RotationMode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn deleted_middle_archive() {
+        let dir = fresh_dir("replay_deleted_middle_archive").await;
+        let mut file = RotatingFile::builder(dir.join("app.log"), RotationMode::Bytes(3))
+            .sequence(3)
+            .build()
+            .await
+            .unwrap();
+        for line in [b"aa\n", b"bb\n", b"cc\n", b"dd\n"] {
+            file.write_all(line).await.unwrap();
+        }
+        file.flush().await.unwrap();
+        let archives = file.archives().await.unwrap();
+        assert_eq!(archives.len(), 3);
+
+        let mut all = Vec::new();
+        file.replay(Gaps::Fail)
+            .await
+            .unwrap()
+            .read_to_end(&mut all)
+            .await
+            .unwrap();
+        assert_eq!(all, b"aa\nbb\ncc\n");
+
+        std::fs::remove_file(&archives[1].path).unwrap();
+        let mut replay = file.replay(Gaps::Fail).await.unwrap();
+        let mut read = Vec::new();
+        let err = replay.read_to_end(&mut read).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(read, b"aa\n");
+        assert_eq!(replay.gaps().len(), 1);
+        assert_eq!(replay.gaps()[0].missing, 2..3);
+        replay.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"aa\ncc\n");
+
+        let mut replay = file.replay(Gaps::Report).await.unwrap();
+        let mut read = Vec::new();
+        replay.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"aa\ncc\n");
+        assert_eq!(replay.gaps()[0].after, archives[0].path);
+    }
+
+    #[tokio::test]
+    async fn rotating_reader() {
+        let dir = fresh_dir("rotating_reader").await;
+        let path = dir.join("app.log");
+        let mut file = RotatingFile::builder(&path, RotationMode::Bytes(3))
+            .sequence(1)
+            .build()
+            .await
+            .unwrap();
+        for line in [&b"aa\n"[..], b"bb\n", b"cc\n", b"d"].iter() {
+            file.write_all(line).await.unwrap();
+        }
+        file.flush().await.unwrap();
+
+        let mut reader = RotatingReader::open(&path).await.unwrap();
+        file.write_all(b"d\n").await.unwrap();
+        file.flush().await.unwrap();
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).await.unwrap();
+        assert_eq!(all, b"aa\nbb\ncc\nd");
+    }
+}
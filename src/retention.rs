@@ -0,0 +1,564 @@
+use crate::{
+    archive::{self, ArchiveInfo, Naming},
+    Codec,
+};
+use chrono::prelude::*;
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// A reason to keep an archive around.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RetentionRule {
+    /// Keeps the newest `n` archives.
+    KeepCount(usize),
+    /// Keeps archives younger than the given age.
+    KeepAge(Duration),
+    /// Keeps the newest archives whose combined size fits in the given
+    /// number of bytes.
+    KeepSize(u64),
+}
+
+/// How the rules of a [`RetentionPolicy`] are combined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Combine {
+    /// An archive is kept if it satisfies any rule, and only deleted once it
+    /// violates all of them. Generous rules win: a count minimum protects
+    /// files even past a size limit.
+    Any,
+    /// An archive is kept only while it satisfies every rule. Restrictive
+    /// rules win: a size limit deletes files even below a count minimum.
+    All,
+}
+
+/// Decides which archives are deleted after each rotation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    rules: Vec<RetentionRule>,
+    combine: Combine,
+    pub(crate) consolidate: Option<Duration>,
+    pub(crate) cold: Option<Codec>,
+}
+
+impl RetentionPolicy {
+    /// Keeps an archive if any of `rules` wants to keep it.
+    pub fn any(rules: Vec<RetentionRule>) -> Self {
+        Self {
+            rules,
+            combine: Combine::Any,
+            consolidate: None,
+            cold: None,
+        }
+    }
+
+    /// Keeps an archive only if all of `rules` want to keep it.
+    pub fn all(rules: Vec<RetentionRule>) -> Self {
+        Self {
+            rules,
+            combine: Combine::All,
+            consolidate: None,
+            cold: None,
+        }
+    }
+
+    pub fn rules(&self) -> &[RetentionRule] {
+        &self.rules
+    }
+
+    pub fn combine(&self) -> Combine {
+        self.combine
+    }
+
+    /// Before applying the rules, merges archives older than `age` into one
+    /// archive per calendar day, named after the newest of them.
+    ///
+    /// Archives are merged by concatenation, which gzip and zstd readers
+    /// decode as a single stream, and only with others of the same
+    /// extension.
+    pub fn consolidate_older_than(mut self, age: Duration) -> Self {
+        self.consolidate = Some(age);
+        self
+    }
+
+    /// Recompresses consolidated archives with `codec` instead of
+    /// concatenating them, e.g. with a slow, strong level for cold data
+    /// while rotation keeps a fast one. Archives of a day then merge
+    /// regardless of extension, into one named for `codec`.
+    pub fn consolidate_codec(mut self, codec: Codec) -> Self {
+        self.cold = Some(codec);
+        self
+    }
+
+    /// Returns the archives to delete. `archives` must be sorted oldest first.
+    pub(crate) fn expired<'a>(
+        &self,
+        archives: &'a [ArchiveInfo],
+        now: SystemTime,
+    ) -> Vec<&'a ArchiveInfo> {
+        if self.rules.is_empty() {
+            return Vec::new();
+        }
+        let verdicts: Vec<Vec<bool>> = self
+            .rules
+            .iter()
+            .map(|rule| rule.keeps(archives, now))
+            .collect();
+        archives
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| {
+                let mut keeps = verdicts.iter().map(|v| v[*i]);
+                let keep = match self.combine {
+                    Combine::Any => keeps.any(|k| k),
+                    Combine::All => keeps.all(|k| k),
+                };
+                !keep
+            })
+            .map(|(_, archive)| archive)
+            .collect()
+    }
+}
+
+pub(crate) fn age(archive: &ArchiveInfo, now: SystemTime) -> Option<Duration> {
+    let created = archive
+        .timestamp
+        .and_then(|ts| Local.from_local_datetime(&ts).earliest())
+        .map(SystemTime::from)
+        .or(archive.modified)?;
+    Some(now.duration_since(created).unwrap_or_default())
+}
+
+impl RetentionRule {
+    /// Evaluates the rule for each archive, oldest first.
+    fn keeps(&self, archives: &[ArchiveInfo], now: SystemTime) -> Vec<bool> {
+        let len = archives.len();
+        match *self {
+            RetentionRule::KeepCount(n) => (0..len).map(|i| len - i <= n).collect(),
+            RetentionRule::KeepAge(max) => archives
+                .iter()
+                .map(|a| age(a, now).is_none_or(|age| age < max))
+                .collect(),
+            RetentionRule::KeepSize(budget) => {
+                let mut total = 0u64;
+                let mut keeps: Vec<bool> = archives
+                    .iter()
+                    .rev()
+                    .map(|a| {
+                        total = total.saturating_add(a.size);
+                        total <= budget
+                    })
+                    .collect();
+                keeps.reverse();
+                keeps
+            }
+        }
+    }
+}
+
+/// Archives handed out by [`RotatingFile::snapshot_rotate`], which
+/// retention and disk budgets leave alone until they're released.
+///
+/// [`RotatingFile::snapshot_rotate`]: crate::RotatingFile::snapshot_rotate
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Pins(Arc<Mutex<Vec<PathBuf>>>);
+
+impl Pins {
+    pub(crate) fn pin(&self, archive: PathBuf) {
+        self.0.lock().unwrap().push(archive);
+    }
+
+    /// Follows a pinned archive to its new name, e.g. once compressed.
+    pub(crate) fn moved(&self, from: &Path, to: &Path) {
+        for pinned in self.0.lock().unwrap().iter_mut() {
+            if pinned == from {
+                *pinned = to.to_path_buf();
+            }
+        }
+    }
+
+    pub(crate) fn unpin(&self, archive: &Path) -> bool {
+        let mut pins = self.0.lock().unwrap();
+        let before = pins.len();
+        pins.retain(|pinned| pinned != archive);
+        pins.len() != before
+    }
+
+    pub(crate) fn contains(&self, archive: &Path) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|pinned| pinned == archive)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub(crate) fn last(&self) -> Option<PathBuf> {
+        self.0.lock().unwrap().last().cloned()
+    }
+
+    pub(crate) fn truncate(&self, len: usize) {
+        self.0.lock().unwrap().truncate(len);
+    }
+}
+
+/// Removes the done marker and line index of `archive`, if it has them.
+pub(crate) async fn remove_marker(archive: &Path) -> Result<(), Error> {
+    for sidecar in [archive::done_marker(archive), archive::index_path(archive)] {
+        match tokio::fs::remove_file(sidecar).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Merges each day's archives older than `age` into the newest of them,
+/// recompressed with `cold` if given.
+async fn consolidate(
+    archives: &[ArchiveInfo],
+    age: Duration,
+    cold: Option<&Codec>,
+    now: SystemTime,
+) -> Result<(), Error> {
+    // Archives merge only with others of the same day and, unless they're
+    // recompressed, extension.
+    type Key<'a> = (NaiveDate, Option<&'a std::ffi::OsStr>);
+    let mut groups: Vec<(Key, Vec<&ArchiveInfo>)> = Vec::new();
+    for archive in archives {
+        if self::age(archive, now).is_none_or(|a| a < age) {
+            continue;
+        }
+        let day = match (archive.timestamp, archive.modified) {
+            (Some(ts), _) => ts.date(),
+            (None, Some(modified)) => DateTime::<Local>::from(modified).date_naive(),
+            (None, None) => continue,
+        };
+        let key = (day, archive.path.extension().filter(|_| cold.is_none()));
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, members)) => members.push(archive),
+            None => groups.push((key, vec![archive])),
+        }
+    }
+    for (_, members) in groups.iter().filter(|(_, members)| members.len() > 1) {
+        let newest = members.last().unwrap();
+        let output = match cold {
+            Some(codec) => cold_path(&newest.path, codec),
+            None => newest.path.clone(),
+        };
+        let temp = crate::compress::temp_path(&output);
+        match cold {
+            Some(codec) => {
+                let sources: Vec<_> = members.iter().map(|m| m.path.clone()).collect();
+                let (codec, temp) = (codec.clone(), temp.clone());
+                tokio::task::spawn_blocking(move || {
+                    crate::compress::recompress(&sources, &codec, &temp)
+                })
+                .await
+                .map_err(Error::other)??;
+            }
+            None => {
+                let mut merged = tokio::fs::File::create(&temp).await?;
+                for member in members {
+                    let mut input = tokio::fs::File::open(&member.path).await?;
+                    tokio::io::copy(&mut input, &mut merged).await?;
+                }
+                merged.sync_all().await?;
+            }
+        }
+        tokio::fs::rename(&temp, &output).await?;
+        // The index described only the newest member
+        match tokio::fs::remove_file(archive::index_path(&output)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for member in members.iter().filter(|m| m.path != output) {
+            tokio::fs::remove_file(&member.path).await?;
+            remove_marker(&member.path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The name of `archive` once recompressed with `codec`.
+fn cold_path(archive: &Path, codec: &Codec) -> PathBuf {
+    let name = archive.file_name().unwrap_or_default().to_string_lossy();
+    let plain = archive::strip_codec(&name).unwrap_or(&name);
+    let name = match codec.extension() {
+        Some(extension) => format!("{}.{}", plain, extension),
+        None => plain.to_string(),
+    };
+    archive.with_file_name(name)
+}
+
+/// What [`prune`] did, with paths oldest first.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub removed: Vec<PathBuf>,
+    pub kept: Vec<PathBuf>,
+}
+
+/// Applies `policy` to the archives of `file_name` (e.g. `app.log`) in
+/// `dir` the way a [`RotatingFile`] does after rotating, but without one,
+/// e.g. from a cleanup job. Archives are found as by
+/// [`list_archives_in`](crate::list_archives_in), whatever their codec.
+///
+/// [`RotatingFile`]: crate::RotatingFile
+pub async fn prune<P: AsRef<Path>>(
+    dir: P,
+    file_name: &str,
+    policy: &RetentionPolicy,
+) -> Result<PruneReport, Error> {
+    let base = archive::standalone_base(dir.as_ref(), file_name)?;
+    let mut archives = archive::list_standalone(&base, policy.cold.as_slice()).await?;
+    if let Some(age) = policy.consolidate {
+        consolidate(&archives, age, policy.cold.as_ref(), SystemTime::now()).await?;
+        archives = archive::list_standalone(&base, policy.cold.as_slice()).await?;
+    }
+    let removed = remove_expired(policy, &archives).await?;
+    let kept = archives
+        .into_iter()
+        .map(|archive| archive.path)
+        .filter(|path| !removed.contains(path))
+        .collect();
+    Ok(PruneReport { removed, kept })
+}
+
+/// Lists the archives of `base` that retention may touch, leaving out
+/// pinned ones.
+async fn unpinned(base: &Path, naming: &Naming, pins: &Pins) -> Result<Vec<ArchiveInfo>, Error> {
+    let mut archives = archive::list(base, naming).await?;
+    archives.retain(|archive| !pins.contains(&archive.path));
+    Ok(archives)
+}
+
+/// Deletes the archives of `base` that `policy` no longer keeps.
+pub(crate) async fn apply(
+    policy: &RetentionPolicy,
+    base: &Path,
+    naming: &Naming,
+    pins: &Pins,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut archives = unpinned(base, naming, pins).await?;
+    if let Some(age) = policy.consolidate {
+        consolidate(&archives, age, policy.cold.as_ref(), SystemTime::now()).await?;
+        archives = unpinned(base, naming, pins).await?;
+    }
+    remove_expired(policy, &archives).await
+}
+
+/// Applies `policy` as a new archive of `base` appears, before it's
+/// compressed, so a count limit holds at all times.
+///
+/// Sizes aren't final until compression, so size rules are left to the
+/// [`apply`] that follows it: they're dropped when all rules must keep an
+/// archive, and nothing is deleted when any of them may.
+pub(crate) async fn apply_on_create(
+    policy: &RetentionPolicy,
+    base: &Path,
+    naming: &Naming,
+    pins: &Pins,
+) -> Result<Vec<PathBuf>, Error> {
+    let sized = |rule: &RetentionRule| matches!(rule, RetentionRule::KeepSize(_));
+    if policy.combine == Combine::Any && policy.rules.iter().any(sized) {
+        return Ok(Vec::new());
+    }
+    let unsized_policy = RetentionPolicy {
+        rules: policy.rules.iter().filter(|r| !sized(r)).cloned().collect(),
+        combine: policy.combine,
+        consolidate: None,
+        cold: None,
+    };
+    let archives = unpinned(base, naming, pins).await?;
+    remove_expired(&unsized_policy, &archives).await
+}
+
+async fn remove_expired(
+    policy: &RetentionPolicy,
+    archives: &[ArchiveInfo],
+) -> Result<Vec<PathBuf>, Error> {
+    let mut removed = Vec::new();
+    for archive in policy.expired(archives, SystemTime::now()) {
+        match archive::remove(&archive.path).await {
+            Ok(()) => removed.push(archive.path.clone()),
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+        remove_marker(&archive.path).await?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(name: &str, size: u64) -> ArchiveInfo {
+        ArchiveInfo {
+            path: PathBuf::from(name),
+            sequence: None,
+            timestamp: None,
+            compressed: true,
+            size,
+            modified: Some(SystemTime::now()),
+        }
+    }
+
+    fn names(expired: Vec<&ArchiveInfo>) -> Vec<&str> {
+        expired.iter().map(|a| a.path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn conflicting_rules() {
+        let archives = vec![
+            archive("a", 100),
+            archive("b", 100),
+            archive("c", 100),
+            archive("d", 100),
+        ];
+        let rules = vec![RetentionRule::KeepCount(3), RetentionRule::KeepSize(150)];
+        let now = SystemTime::now();
+
+        // The count minimum protects b and c even though they blow the size budget
+        let any = RetentionPolicy::any(rules.clone());
+        assert_eq!(names(any.expired(&archives, now)), vec!["a"]);
+
+        // The size limit wins over the count minimum
+        let all = RetentionPolicy::all(rules);
+        assert_eq!(names(all.expired(&archives, now)), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn age_rule() {
+        let mut old = archive("old", 1);
+        old.modified = Some(SystemTime::now() - Duration::from_secs(3600));
+        let archives = vec![old, archive("new", 1)];
+        let policy = RetentionPolicy::any(vec![RetentionRule::KeepAge(Duration::from_secs(60))]);
+        assert_eq!(
+            names(policy.expired(&archives, SystemTime::now())),
+            vec!["old"]
+        );
+    }
+
+    #[tokio::test]
+    async fn consolidate_old_archives() {
+        use std::io::Write;
+
+        let dir = crate::tests::fresh_dir("consolidate_old_archives").await;
+        let base = dir.join("app.log");
+        let gz = |data: &[u8]| {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap()
+        };
+        for hour in 0..3 {
+            let name = format!("app-2024-01-01_{:02}-00-00.log.gz", hour);
+            std::fs::write(dir.join(name), gz(format!("hour {}\n", hour).as_bytes())).unwrap();
+        }
+        std::fs::write(
+            dir.join("app-2024-01-02_00-00-00.log.gz"),
+            gz(b"next day\n"),
+        )
+        .unwrap();
+
+        let policy = RetentionPolicy::any(vec![RetentionRule::KeepCount(10)])
+            .consolidate_older_than(Duration::from_secs(3600));
+        assert!(apply(&policy, &base, &Naming::default(), &Pins::default())
+            .await
+            .unwrap()
+            .is_empty());
+
+        let archives = archive::list(&base, &Naming::default()).await.unwrap();
+        let names: Vec<_> = archives
+            .iter()
+            .map(|a| a.path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "app-2024-01-01_02-00-00.log.gz",
+                "app-2024-01-02_00-00-00.log.gz"
+            ]
+        );
+        assert_eq!(
+            crate::compress::decode_file(&archives[0].path).unwrap(),
+            b"hour 0\nhour 1\nhour 2\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn consolidate_with_cold_codec() {
+        use std::io::Write;
+
+        let dir = crate::tests::fresh_dir("consolidate_with_cold_codec").await;
+        let base = dir.join("app.log");
+        let hour = |hour: u32| {
+            (0..200)
+                .map(|i| format!("{:02}:{:03} request served in {}ms\n", hour, i, i % 17))
+                .collect::<String>()
+        };
+        let mut hot = 0;
+        for h in 0..3 {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(hour(h).as_bytes()).unwrap();
+            let data = encoder.finish().unwrap();
+            hot += data.len() as u64;
+            let name = format!("app-2024-01-01_{:02}-00-00.log.gz", h);
+            std::fs::write(dir.join(name), data).unwrap();
+        }
+
+        let policy = RetentionPolicy::any(vec![RetentionRule::KeepCount(10)])
+            .consolidate_older_than(Duration::from_secs(3600))
+            .consolidate_codec(Codec::Gzip(9));
+        apply(&policy, &base, &Naming::default(), &Pins::default())
+            .await
+            .unwrap();
+
+        let archives = archive::list(&base, &Naming::default()).await.unwrap();
+        assert_eq!(archives.len(), 1);
+        assert!(archives[0].size < hot);
+        assert_eq!(
+            crate::compress::decode_file(&archives[0].path).unwrap(),
+            (0..3).map(hour).collect::<String>().as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn prune_without_writer() {
+        let dir = crate::tests::fresh_dir("prune_without_writer").await;
+        let names = [
+            "app-2024-01-01_00-00-00.log.gz",
+            "app-2024-01-02_00-00-00.log",
+            "app-1-2024-01-03_00-00-00.log.gz",
+            "app-2-2024-01-04_00-00-00.log.gz",
+            "other-2024-01-05_00-00-00.log.gz",
+        ];
+        for name in names.iter() {
+            std::fs::write(dir.join(name), b"x").unwrap();
+        }
+        let listed = archive::list_archives_in(&dir, "app.log", &[Codec::Gzip(1)])
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 3);
+
+        let policy = RetentionPolicy::any(vec![RetentionRule::KeepCount(2)]);
+        let report = prune(&dir, "app.log", &policy).await.unwrap();
+        let file_names = |paths: &[PathBuf]| -> Vec<String> {
+            paths
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(file_names(&report.removed), names[..2]);
+        assert_eq!(file_names(&report.kept), names[2..4]);
+        assert!(!dir.join(names[0]).exists());
+        assert!(dir.join(names[4]).exists());
+    }
+}
@@ -0,0 +1,177 @@
+use crate::RotatingFile;
+use std::{
+    io::Error,
+    sync::{Arc, Weak},
+};
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+/// A cloneable [`RotatingFile`] that serializes access internally.
+///
+/// Each [`write`](Self::write) lands whole, so writes from different tasks
+/// never interleave within the file, and rotation, flushing and shutdown
+/// only ever happen between writes.
+///
+/// With [`RotatingFileBuilder::max_file_age`], a timer task rotates the
+/// file once it's that old, even without writes. A failed rotation is left
+/// for the next write to run into.
+///
+/// [`RotatingFileBuilder::max_file_age`]: crate::RotatingFileBuilder::max_file_age
+#[derive(Clone)]
+pub struct SharedRotatingFile {
+    inner: Arc<Mutex<RotatingFile>>,
+}
+
+impl SharedRotatingFile {
+    pub fn new(file: RotatingFile) -> Self {
+        let aging = file.age_deadline().is_some();
+        let inner = Arc::new(Mutex::new(file));
+        if aging {
+            tokio::spawn(rotate_by_age(Arc::downgrade(&inner)));
+        }
+        Self { inner }
+    }
+
+    /// Writes all of `buf`; see [`RotatingFileBuilder::rotation_barrier`]
+    /// to keep it in one file.
+    ///
+    /// [`RotatingFileBuilder::rotation_barrier`]: crate::RotatingFileBuilder::rotation_barrier
+    pub async fn write(&self, buf: &[u8]) -> Result<(), Error> {
+        self.inner.lock().await.write_unsplit(buf).await
+    }
+
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.inner.lock().await.flush().await
+    }
+
+    /// Shuts the file down; see [`RotatingFile::close`].
+    pub async fn close(&self) -> Result<(), Error> {
+        self.inner.lock().await.close().await
+    }
+
+    /// Runs `f` with exclusive access to the underlying file.
+    pub async fn with<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut RotatingFile) -> T,
+    {
+        f(&mut *self.inner.lock().await)
+    }
+}
+
+/// Rotates the file at each age deadline until every handle is gone.
+async fn rotate_by_age(inner: Weak<Mutex<RotatingFile>>) {
+    let mut wait = None;
+    loop {
+        let deadline = match inner.upgrade() {
+            Some(inner) => inner.lock().await.age_deadline(),
+            None => return,
+        };
+        let Some(deadline) = deadline else { return };
+        // A file that wasn't due at its deadline, e.g. an empty one, is
+        // checked again an age later
+        let deadline = match wait.take() {
+            Some(retry) => std::cmp::max(deadline, retry),
+            None => deadline,
+        };
+        tokio::time::sleep_until(deadline.into()).await;
+        let Some(inner) = inner.upgrade() else { return };
+        let mut file = inner.lock().await;
+        if !file.rotate_if_due().await.unwrap_or(false) {
+            wait = file
+                .config
+                .max_file_age
+                .map(|age| std::time::Instant::now() + age);
+        }
+    }
+}
+
+impl From<RotatingFile> for SharedRotatingFile {
+    fn from(file: RotatingFile) -> Self {
+        Self::new(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::fresh_dir, RotationMode};
+
+    #[tokio::test]
+    async fn concurrent_writers() {
+        let dir = fresh_dir("shared_concurrent_writers").await;
+        let file = RotatingFile::builder(dir.join("app.log"), RotationMode::Lines(10))
+            .sequence(3)
+            .build()
+            .await
+            .unwrap();
+        let shared = SharedRotatingFile::new(file);
+        let tasks: Vec<_> = (0..4)
+            .map(|task| {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    for i in 0..10 {
+                        let line = format!("task {} line {:02} {}\n", task, i, "x".repeat(64));
+                        shared.write(line.as_bytes()).await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        shared.close().await.unwrap();
+
+        let archives = shared.inner.lock().await.archives().await.unwrap();
+        assert_eq!(archives.len(), 3);
+        let mut lines = Vec::new();
+        for archive in &archives {
+            let text = String::from_utf8(crate::tests::gunzip(&archive.path)).unwrap();
+            assert_eq!(text.lines().count(), 10);
+            lines.extend(text.lines().map(String::from));
+        }
+        let active = std::fs::read_to_string(dir.join("app.log")).unwrap();
+        lines.extend(active.lines().map(String::from));
+        assert_eq!(lines.len(), 40);
+        assert!(lines
+            .iter()
+            .all(|l| l.len() == "task 0 line 00 ".len() + 64));
+        lines.sort();
+        lines.dedup();
+        assert_eq!(lines.len(), 40);
+    }
+
+    #[tokio::test]
+    async fn rotation_barrier() {
+        const RECORD: usize = 3 * 1024 * 1024;
+
+        let dir = fresh_dir("shared_rotation_barrier").await;
+        let file = RotatingFile::builder(dir.join("app.bin"), RotationMode::Bytes(1))
+            .sequence(3)
+            .codec(crate::Codec::None)
+            .rotation_barrier(true)
+            .build()
+            .await
+            .unwrap();
+        let shared = SharedRotatingFile::new(file);
+        let tasks: Vec<_> = (0..4u8)
+            .map(|task| {
+                let shared = shared.clone();
+                tokio::spawn(async move { shared.write(&vec![task; RECORD]).await.unwrap() })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        shared.close().await.unwrap();
+
+        // Each file holds one whole record, though the file takes it in pieces
+        let archives = shared.inner.lock().await.archives().await.unwrap();
+        assert_eq!(archives.len(), 3);
+        let mut paths: Vec<_> = archives.into_iter().map(|a| a.path).collect();
+        paths.push(dir.join("app.bin"));
+        for path in paths {
+            let data = std::fs::read(&path).unwrap();
+            assert_eq!(data.len(), RECORD);
+            assert!(data.iter().all(|b| *b == data[0]));
+        }
+    }
+}